@@ -0,0 +1,281 @@
+// Compile time configuration, the equivalent of st's config.h.
+
+use std::{
+    os::raw::{c_int, c_uint},
+    time::Duration,
+};
+
+use crate::iterm_graphics::FileTransferPolicy;
+use crate::st::{ClipboardPolicy, CursorStyle};
+use crate::x::{
+    x, Arg, Button1, Button2, Button3, Button4, Button5, ControlMask, KeySym, Mod2Mask,
+    MouseShortcut, ShiftMask, Shortcut, XK_Down, XK_End, XK_Home, XK_Insert, XK_Page_Down,
+    XK_Page_Up, XK_Print, XK_Return, XK_Up, XK_ANY_MOD, XK_F, XK_O, XK_R, XK_SWITCH_MOD, XK_V,
+    XK_X, XK_Z,
+};
+
+/// Colour index used for the default foreground, past the end of the 256 colour palette.
+pub const DEFAULT_FOREGROUND: u32 = 258;
+
+/// Colour index used for the default background, past the end of the 256 colour palette.
+pub const DEFAULT_BACKGROUND: u32 = 259;
+
+/// Maximum number of lines kept in the scrollback history. Zero disables scrollback.
+pub const SCROLLBACK_LINES: usize = 10_000;
+
+/// Upper bound on the memory used by the scrollback history, in bytes. Whichever of this and
+/// `SCROLLBACK_LINES` is hit first causes the oldest lines to be dropped.
+pub const SCROLLBACK_MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Identification sent in reply to DA and DECID, claiming to be a VT220 with sixel graphics.
+pub const VT_IDENTIFICATION: &[u8] = b"\x1b[?62;4c";
+
+/// Spaces between the tab stops set on startup.
+pub const TAB_SPACES: usize = 8;
+
+/// Longest control sequence accepted, in bytes, before it is abandoned.
+pub const ESCAPE_CSI_LIMIT: usize = 256;
+
+/// Longest OSC, DCS or APC string kept, in bytes. Anything past this is dropped.
+pub const ESCAPE_STRING_LIMIT: usize = 16 * 1024 * 1024;
+
+/// How long drawing is held back for a synchronized update before giving up on it.
+pub const SYNC_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Largest width and height, in pixels, of a sixel image.
+pub const SIXEL_MAX_SIZE: usize = 4096;
+
+/// Largest width and height, in pixels, of an image sent with the kitty graphics protocol,
+/// either as sent or once scaled to fit the cells it is placed over.
+pub const IMAGE_MAX_SIZE: usize = 10_000;
+
+/// Upper bound on the memory used by images in the grid and history, in bytes. The oldest
+/// images are dropped past it.
+pub const IMAGE_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// What to do with files sent through OSC 1337 that aren't inline images. Saving them lets any
+/// program write files, so by default they are dropped.
+pub const FILE_TRANSFER_POLICY: FileTransferPolicy = FileTransferPolicy::Reject;
+
+/// Shape of the cursor until the application sets one with DECSCUSR.
+pub const CURSOR_STYLE: CursorStyle = CursorStyle::SteadyBlock;
+
+/// Thickness in pixels of the underline and bar cursors, and of the outline drawn in place of
+/// the cursor while the window is unfocused.
+pub const CURSOR_THICKNESS: c_int = 2;
+
+/// Title of the window until the application sets one.
+pub const DEFAULT_TITLE: &str = "tt";
+
+/// Template for the window title. `{title}` is replaced with the title set by the application
+/// and `{cwd}` with the working directory of the shell, eg: "{title} - {cwd}".
+pub const TITLE_TEMPLATE: &str = "{title}";
+
+/// What applications may do with the selections through OSC 52. Letting any program read the
+/// clipboard leaks whatever was last copied, so by default they may only set it.
+pub const OSC52_POLICY: ClipboardPolicy = ClipboardPolicy::WriteOnly;
+
+/// Largest selection, in bytes, that OSC 52 may set or read.
+pub const OSC52_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// Command run to open hyperlinks, with the URI added as the last argument.
+pub const HYPERLINK_HANDLER: &[&str] = &["xdg-open"];
+
+/// Command that printed text is piped into, run once for each print job. To print into a file
+/// go through the shell, eg: `&["sh", "-c", "cat >> ~/tt-print.txt"]`.
+pub const PRINTER: &[&str] = &["lpr"];
+
+/// Show the exit status of finished commands in the left border, for shells that report it
+/// with OSC 133.
+pub const EXIT_STATUS_GUTTER: bool = true;
+
+/// Colours of the exit status marks, as palette indices.
+pub const EXIT_SUCCESS_COLOR: u32 = 2;
+pub const EXIT_FAILURE_COLOR: u32 = 1;
+
+/// Colours of search matches, as palette indices.
+pub const SEARCH_MATCH_FOREGROUND: u32 = 0;
+pub const SEARCH_MATCH_BACKGROUND: u32 = 3;
+
+/// Colours of the search match the view is following.
+pub const SEARCH_CURRENT_FOREGROUND: u32 = 0;
+pub const SEARCH_CURRENT_BACKGROUND: u32 = 9;
+
+/// Send keys changed by modifyOtherKeys as `CSI code ; modifiers u` rather than xterm's default
+/// of `CSI 27 ; modifiers ; code ~`, like xterm's formatOtherKeys resource.
+pub const FORMAT_OTHER_KEYS: bool = false;
+
+/// Modifiers ignored when matching shortcuts, so that Num Lock and keyboard groups don't get in
+/// the way.
+pub const IGNORE_MODIFIERS: c_uint = Mod2Mask | XK_SWITCH_MOD;
+
+/// Modifier that bypasses mouse reporting, so the mouse can still be used for selection and
+/// scrolling in applications that grab it.
+pub const FORCE_MOUSE_MODIFIER: c_uint = ShiftMask;
+
+/// Whether the wheel sends Up and Down keys on the alternate screen, until an application
+/// changes it with mode 1007.
+pub const ALTERNATE_SCROLL: bool = true;
+
+/// Lines scrolled by each step of the wheel on the alternate screen.
+pub const ALTERNATE_SCROLL_LINES: usize = 3;
+
+/// Whether to ask before pasting several lines to an application that hasn't turned on
+/// bracketed paste, where each line would run as soon as it arrives, eg: in a shell.
+pub const PASTE_CONFIRM_MULTILINE: bool = false;
+
+/// Keyboard shortcuts, checked before any key is sent to the terminal.
+///
+/// Scroll functions take a number of lines as `Arg::Int`, or a fraction of the screen as
+/// `Arg::Float`.
+pub const SHORTCUTS: &[Shortcut] = &[
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_Insert as KeySym,
+        func: x::paste_selection,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_V as KeySym,
+        func: x::paste_selection,
+        arg: Arg::Int(1),
+    },
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_Page_Up as KeySym,
+        func: x::scroll_up,
+        arg: Arg::Float(1.0),
+    },
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_Page_Down as KeySym,
+        func: x::scroll_down,
+        arg: Arg::Float(1.0),
+    },
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_Home as KeySym,
+        func: x::scroll_to_top,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_End as KeySym,
+        func: x::scroll_to_bottom,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_Up as KeySym,
+        func: x::scroll_up,
+        arg: Arg::Int(1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_Down as KeySym,
+        func: x::scroll_down,
+        arg: Arg::Int(1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_F as KeySym,
+        func: x::search_start,
+        arg: Arg::Int(-1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_Return as KeySym,
+        func: x::new_terminal,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_Z as KeySym,
+        func: x::scroll_to_prompt,
+        arg: Arg::Int(-1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_X as KeySym,
+        func: x::scroll_to_prompt,
+        arg: Arg::Int(1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_O as KeySym,
+        func: x::copy_command_output,
+        arg: Arg::Int(1),
+    },
+    Shortcut {
+        modifiers: (ControlMask | ShiftMask) as usize,
+        key_symbol: XK_R as KeySym,
+        func: x::reset,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: ShiftMask as usize,
+        key_symbol: XK_Print as KeySym,
+        func: x::print_screen,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: ControlMask as usize,
+        key_symbol: XK_Print as KeySym,
+        func: x::toggle_auto_print,
+        arg: Arg::Int(0),
+    },
+    Shortcut {
+        modifiers: XK_ANY_MOD,
+        key_symbol: XK_Print as KeySym,
+        func: x::print_selection,
+        arg: Arg::Int(0),
+    },
+];
+
+/// Mouse shortcuts, checked in order when mouse reporting is off or `FORCE_MOUSE_MODIFIER` is
+/// held.
+pub const MOUSE_SHORTCUTS: &[MouseShortcut] = &[
+    MouseShortcut {
+        modifiers: ControlMask as usize,
+        button: Button1,
+        func: x::open_hyperlink,
+        arg: Arg::Int(0),
+    },
+    MouseShortcut {
+        modifiers: ControlMask as usize,
+        button: Button3,
+        func: x::copy_hyperlink,
+        arg: Arg::Int(0),
+    },
+    MouseShortcut {
+        modifiers: XK_ANY_MOD,
+        button: Button2,
+        func: x::paste_selection,
+        arg: Arg::Int(0),
+    },
+    MouseShortcut {
+        modifiers: ShiftMask as usize,
+        button: Button4,
+        func: x::scroll_up,
+        arg: Arg::Float(0.5),
+    },
+    MouseShortcut {
+        modifiers: ShiftMask as usize,
+        button: Button5,
+        func: x::scroll_down,
+        arg: Arg::Float(0.5),
+    },
+    MouseShortcut {
+        modifiers: XK_ANY_MOD,
+        button: Button4,
+        func: x::scroll_up,
+        arg: Arg::Int(3),
+    },
+    MouseShortcut {
+        modifiers: XK_ANY_MOD,
+        button: Button5,
+        func: x::scroll_down,
+        arg: Arg::Int(3),
+    },
+];
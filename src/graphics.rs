@@ -0,0 +1,228 @@
+// Images shown in the grid, from sixel, the kitty graphics protocol and iTerm2 inline images.
+//
+// An image is cut into cell sized tiles and each tile is kept on the line it is shown on, so
+// images scroll with the text, go into the history and are cleared along with the cells under
+// them.
+
+use std::{io::Cursor, mem::size_of};
+
+use crate::config;
+
+/// A decoded image, as rows of premultiplied 0xAARRGGBB pixels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+    /// Size of a cell in pixels when the image was placed, which its tiles are cut to.
+    pub cell_size: (usize, usize),
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u32>) -> Self {
+        debug_assert_eq!(pixels.len(), width * height);
+        Image {
+            width,
+            height,
+            pixels,
+            cell_size: (1, 1),
+        }
+    }
+
+    /// Converts rows of grey, grey and alpha, RGB or RGBA pixels, with `channels` bytes to each.
+    pub fn from_bytes(width: usize, height: usize, channels: usize, data: &[u8]) -> Option<Self> {
+        if width == 0 || height == 0 || width.max(height) > config::IMAGE_MAX_SIZE {
+            return None;
+        }
+        let data = data.get(..width * height * channels)?;
+        let pixels = data
+            .chunks_exact(channels)
+            .map(|pixel| match *pixel {
+                [gray] => rgba(gray, gray, gray, 0xff),
+                [gray, alpha] => rgba(gray, gray, gray, alpha),
+                [r, g, b] => rgba(r, g, b, 0xff),
+                [r, g, b, a, ..] => rgba(r, g, b, a),
+                [] => 0,
+            })
+            .collect();
+        Some(Image::new(width, height, pixels))
+    }
+
+    /// Decodes a PNG file, returning `None` if it is broken or too big.
+    pub fn from_png(data: &[u8]) -> Option<Self> {
+        let mut decoder = png::Decoder::new(Cursor::new(data));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().ok()?;
+        let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+        if width.max(height) > config::IMAGE_MAX_SIZE {
+            return None;
+        }
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf).ok()?;
+        let channels = frame.color_type.samples();
+        let mut rows = Vec::with_capacity(width * height * channels);
+        for row in buf.chunks(frame.line_size).take(height) {
+            rows.extend_from_slice(&row[..width * channels]);
+        }
+        Image::from_bytes(width, height, channels, &rows)
+    }
+
+    /// Decodes a baseline or progressive JPEG file.
+    pub fn from_jpeg(data: &[u8]) -> Option<Self> {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+        decoder.read_info().ok()?;
+        let info = decoder.info()?;
+        let (width, height) = (info.width as usize, info.height as usize);
+        if width.max(height) > config::IMAGE_MAX_SIZE {
+            return None;
+        }
+        let data = decoder.decode().ok()?;
+        match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => Image::from_bytes(width, height, 1, &data),
+            // Only the high byte of each big endian sample is kept.
+            jpeg_decoder::PixelFormat::L16 => {
+                let data: Vec<u8> = data.chunks_exact(2).map(|sample| sample[0]).collect();
+                Image::from_bytes(width, height, 1, &data)
+            }
+            jpeg_decoder::PixelFormat::RGB24 => Image::from_bytes(width, height, 3, &data),
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                let data: Vec<u8> = data
+                    .chunks_exact(4)
+                    .flat_map(|cmyk| {
+                        let k = 255 - cmyk[3] as u32;
+                        cmyk[..3]
+                            .iter()
+                            .map(move |&c| ((255 - c as u32) * k / 255) as u8)
+                    })
+                    .collect();
+                Image::from_bytes(width, height, 3, &data)
+            }
+        }
+    }
+
+    /// Decodes the first frame of a GIF file, drawn at its offset on the logical screen.
+    pub fn from_gif(data: &[u8]) -> Option<Self> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(Cursor::new(data)).ok()?;
+        let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+        if width.max(height) > config::IMAGE_MAX_SIZE {
+            return None;
+        }
+        let frame = decoder.read_next_frame().ok()??;
+        let (left, top) = (frame.left as usize, frame.top as usize);
+        let frame_width = frame.width as usize;
+        let mut rgba = vec![0; width * height * 4];
+        for (row, pixels) in frame.buffer.chunks_exact(frame_width * 4).enumerate() {
+            if top + row >= height || left >= width {
+                break;
+            }
+            let len = frame_width.min(width - left) * 4;
+            let start = ((top + row) * width + left) * 4;
+            rgba[start..start + len].copy_from_slice(&pixels[..len]);
+        }
+        Image::from_bytes(width, height, 4, &rgba)
+    }
+
+    /// Decodes a PNG, JPEG or GIF file, going by the signature at its start.
+    pub fn from_file_data(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG") {
+            Image::from_png(data)
+        } else if data.starts_with(b"\xff\xd8") {
+            Image::from_jpeg(data)
+        } else if data.starts_with(b"GIF8") {
+            Image::from_gif(data)
+        } else {
+            None
+        }
+    }
+
+    /// Cuts out the rectangle at `x`, `y` of `width` by `height` pixels, where a size of zero
+    /// reaches to the edge of the image.
+    pub fn cropped(&self, x: usize, y: usize, width: usize, height: usize) -> Option<Self> {
+        let (x, y) = (x.min(self.width), y.min(self.height));
+        let width = if width == 0 {
+            self.width - x
+        } else {
+            width.min(self.width - x)
+        };
+        let height = if height == 0 {
+            self.height - y
+        } else {
+            height.min(self.height - y)
+        };
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let pixels = (y..y + height)
+            .flat_map(|row| &self.pixels[row * self.width + x..row * self.width + x + width])
+            .copied()
+            .collect();
+        Some(Image::new(width, height, pixels))
+    }
+
+    /// Scales the image to `width` by `height` pixels, picking the nearest pixel for each, and
+    /// places it at `x`, `y` on a transparent image of `outer_width` by `outer_height`.
+    pub fn scaled(
+        &self,
+        width: usize,
+        height: usize,
+        (x, y): (usize, usize),
+        (outer_width, outer_height): (usize, usize),
+    ) -> Option<Self> {
+        let (width, height) = (
+            width.min(config::IMAGE_MAX_SIZE),
+            height.min(config::IMAGE_MAX_SIZE),
+        );
+        let (outer_width, outer_height) = (
+            outer_width.max(x + width).min(config::IMAGE_MAX_SIZE),
+            outer_height.max(y + height).min(config::IMAGE_MAX_SIZE),
+        );
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let mut pixels = vec![0; outer_width * outer_height];
+        for row in 0..height.min(outer_height.saturating_sub(y)) {
+            let source_row = row * self.height / height;
+            for col in 0..width.min(outer_width.saturating_sub(x)) {
+                let source_col = col * self.width / width;
+                pixels[(y + row) * outer_width + x + col] =
+                    self.pixels[source_row * self.width + source_col];
+            }
+        }
+        Some(Image::new(outer_width, outer_height, pixels))
+    }
+
+    /// Number of columns and rows the image covers.
+    pub fn size_in_cells(&self) -> (usize, usize) {
+        let (width, height) = self.cell_size;
+        (
+            self.width.div_ceil(width.max(1)),
+            self.height.div_ceil(height.max(1)),
+        )
+    }
+
+    pub fn memory_size(&self) -> usize {
+        size_of::<Image>() + self.pixels.capacity() * size_of::<u32>()
+    }
+}
+
+/// Packs a pixel, premultiplying the colour by its alpha.
+fn rgba(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    let premultiply = |c: u8| (c as u32 * a as u32 + 127) / 255;
+    (a as u32) << 24 | premultiply(r) << 16 | premultiply(g) << 8 | premultiply(b)
+}
+
+/// One cell sized tile of an image, kept on the line showing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageCell {
+    /// Column of the line the tile is shown in.
+    pub col: usize,
+    /// Id of the image in `Term::images`.
+    pub image: u32,
+    /// Column and row of the tile within the image, in cells.
+    pub x: usize,
+    pub y: usize,
+    /// Stacking order against the text, which is drawn over tiles with a negative z-index.
+    pub z: i32,
+}
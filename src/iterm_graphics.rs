@@ -0,0 +1,371 @@
+// iTerm2 inline images, sent as `OSC 1337 ; File = args : base64 data ST`.
+//
+// See https://iterm2.com/documentation-images.html for the details of the protocol. Only
+// inline images are shown; other files are dropped or saved as `config::FILE_TRANSFER_POLICY`
+// says.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{base64, config, graphics::Image, st::Term};
+
+/// What to do with files sent with OSC 1337 that aren't to be shown inline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileTransferPolicy {
+    Reject,
+    /// Save files into the directory, where a leading `~` is the home directory. Existing
+    /// files are never overwritten.
+    SaveTo(&'static str),
+}
+
+/// Width or height of an image, as given in the arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    /// The size of the image itself, shrunk to fit the screen.
+    Auto,
+    Cells(usize),
+    Pixels(usize),
+    /// Percentage of the width or height of the screen.
+    Percent(usize),
+}
+
+impl Dimension {
+    fn parse(value: &str) -> Self {
+        let number = |n: &str| n.parse().ok();
+        let dimension = if let Some(pixels) = value.strip_suffix("px") {
+            number(pixels).map(Dimension::Pixels)
+        } else if let Some(percent) = value.strip_suffix('%') {
+            number(percent).map(Dimension::Percent)
+        } else {
+            number(value).map(Dimension::Cells)
+        };
+        dimension.unwrap_or(Dimension::Auto)
+    }
+
+    /// Size in pixels, given the number of cells on the screen and the size of a cell. Sizes
+    /// past the largest image are cut down to it.
+    fn pixels(self, screen_cells: usize, cell_size: usize) -> Option<usize> {
+        let pixels = match self {
+            Dimension::Auto => return None,
+            Dimension::Cells(cells) => cells.saturating_mul(cell_size),
+            Dimension::Pixels(pixels) => pixels,
+            Dimension::Percent(percent) => screen_cells * cell_size * percent.min(100) / 100,
+        };
+        Some(pixels.min(config::IMAGE_MAX_SIZE))
+    }
+}
+
+/// The arguments and data of a `File=` command.
+#[derive(Debug)]
+struct File {
+    name: Option<String>,
+    size: Option<usize>,
+    width: Dimension,
+    height: Dimension,
+    preserve_aspect_ratio: bool,
+    inline: bool,
+    keep_cursor: bool,
+    data: Option<Vec<u8>>,
+}
+
+impl File {
+    fn parse(buf: &str) -> Self {
+        let (args, payload) = buf.split_once(':').unwrap_or((buf, ""));
+        let mut file = File {
+            name: None,
+            size: None,
+            width: Dimension::Auto,
+            height: Dimension::Auto,
+            preserve_aspect_ratio: true,
+            inline: false,
+            keep_cursor: false,
+            data: base64::decode(payload),
+        };
+        for arg in args.split(';') {
+            let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+            match key {
+                "name" => {
+                    file.name = base64::decode(value)
+                        .map(|name| String::from_utf8_lossy(&name).into_owned())
+                }
+                "size" => file.size = value.parse().ok(),
+                "width" => file.width = Dimension::parse(value),
+                "height" => file.height = Dimension::parse(value),
+                "preserveAspectRatio" => file.preserve_aspect_ratio = value != "0",
+                "inline" => file.inline = value == "1",
+                "doNotMoveCursor" => file.keep_cursor = value == "1",
+                _ => {}
+            }
+        }
+        file
+    }
+
+    /// Writes the file into `directory` under the last component of its name, adding a number
+    /// to the name if a file by it already exists.
+    fn save(&self, directory: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+        let directory = match directory.strip_prefix('~') {
+            Some(rest) => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+                .join(rest.trim_start_matches('/')),
+            None => PathBuf::from(directory),
+        };
+        let name = self
+            .name
+            .as_deref()
+            .and_then(|name| Path::new(name).file_name())
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.starts_with('.'))
+            .unwrap_or("download");
+
+        fs::create_dir_all(&directory)?;
+        let mut attempt = 0;
+        loop {
+            let path = match attempt {
+                0 => directory.join(name),
+                n => directory.join(format!("{}.{}", name, n)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(data)?;
+                    return Ok(path);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => {
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Term {
+    /// Handles the arguments of an OSC 1337 sequence, of which only `File=` is supported.
+    pub fn iterm_command(&mut self, args: &str) {
+        let Some(file) = args.strip_prefix("File=").map(File::parse) else {
+            return;
+        };
+        let Some(data) = file.data.as_deref() else {
+            return;
+        };
+        // A size that doesn't match means the data was cut short.
+        if file.size.is_some_and(|size| size != data.len()) {
+            return;
+        }
+
+        if !file.inline {
+            match config::FILE_TRANSFER_POLICY {
+                FileTransferPolicy::Reject => {}
+                FileTransferPolicy::SaveTo(directory) => {
+                    if let Err(err) = file.save(directory, data) {
+                        eprintln!("tt: could not save file sent by application: {}", err);
+                    }
+                }
+            }
+            return;
+        }
+
+        let Some(image) =
+            Image::from_file_data(data).and_then(|image| self.iterm_scale(image, &file))
+        else {
+            return;
+        };
+        if file.keep_cursor {
+            let id = self.insert_image(image);
+            self.place_image(id, self.cursor.x, self.cursor.y, false, 0, true);
+            self.collect_images();
+        } else {
+            self.add_image(image, true);
+        }
+    }
+
+    /// Scales an image to the width and height asked for, keeping its aspect ratio unless told
+    /// otherwise. An image with neither given is shrunk to the width of the screen if wider.
+    ///
+    /// Both the image and the box are at most `config::IMAGE_MAX_SIZE` across, so the products
+    /// below can't overflow.
+    fn iterm_scale(&self, image: Image, file: &File) -> Option<Image> {
+        let (cell_width, cell_height) = self.cell_size;
+        let (width, height) = (image.width, image.height);
+        let (scaled_width, scaled_height) = match (
+            file.width.pixels(self.cols, cell_width),
+            file.height.pixels(self.rows, cell_height),
+        ) {
+            (None, None) => {
+                let screen_width = self.cols * cell_width;
+                if width > screen_width {
+                    (screen_width, height * screen_width / width)
+                } else {
+                    (width, height)
+                }
+            }
+            (Some(box_width), None) if file.preserve_aspect_ratio => {
+                (box_width, height * box_width / width)
+            }
+            (None, Some(box_height)) if file.preserve_aspect_ratio => {
+                (width * box_height / height, box_height)
+            }
+            (Some(box_width), Some(box_height)) if file.preserve_aspect_ratio => {
+                if width * box_height > height * box_width {
+                    (box_width, height * box_width / width)
+                } else {
+                    (width * box_height / height, box_height)
+                }
+            }
+            (box_width, box_height) => (box_width.unwrap_or(width), box_height.unwrap_or(height)),
+        };
+        let (scaled_width, scaled_height) = (scaled_width.max(1), scaled_height.max(1));
+        if (scaled_width, scaled_height) == (width, height) {
+            return Some(image);
+        }
+        image.scaled(
+            scaled_width,
+            scaled_height,
+            (0, 0),
+            (scaled_width, scaled_height),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PNG file of a red image.
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[0xff, 0, 0].repeat((width * height) as usize))
+            .unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    fn scaled(term: &Term, (width, height): (usize, usize), args: &str) -> (usize, usize) {
+        let image = Image::new(width, height, vec![0; width * height]);
+        let image = term.iterm_scale(image, &File::parse(args)).unwrap();
+        (image.width, image.height)
+    }
+
+    #[test]
+    fn parses_dimensions() {
+        assert_eq!(Dimension::parse("5"), Dimension::Cells(5));
+        assert_eq!(Dimension::parse("30px"), Dimension::Pixels(30));
+        assert_eq!(Dimension::parse("50%"), Dimension::Percent(50));
+        assert_eq!(Dimension::parse("auto"), Dimension::Auto);
+        assert_eq!(Dimension::parse("-1px"), Dimension::Auto);
+
+        assert_eq!(Dimension::Cells(3).pixels(10, 20), Some(60));
+        assert_eq!(Dimension::Percent(150).pixels(10, 20), Some(200));
+        assert_eq!(Dimension::Auto.pixels(10, 20), None);
+        assert_eq!(
+            Dimension::Cells(usize::MAX).pixels(10, 20),
+            Some(config::IMAGE_MAX_SIZE)
+        );
+        assert_eq!(
+            Dimension::Pixels(usize::MAX).pixels(10, 20),
+            Some(config::IMAGE_MAX_SIZE)
+        );
+    }
+
+    #[test]
+    fn parses_arguments_and_data() {
+        let file = File::parse(
+            "name=Zm9vLnBuZw==;size=3;width=5;height=30px;preserveAspectRatio=0;inline=1;\
+             doNotMoveCursor=1:YWJj",
+        );
+        assert_eq!(file.name.as_deref(), Some("foo.png"));
+        assert_eq!(file.size, Some(3));
+        assert_eq!(file.width, Dimension::Cells(5));
+        assert_eq!(file.height, Dimension::Pixels(30));
+        assert!(!file.preserve_aspect_ratio);
+        assert!(file.inline);
+        assert!(file.keep_cursor);
+        assert_eq!(file.data.as_deref(), Some(&b"abc"[..]));
+
+        let file = File::parse("inline=0");
+        assert_eq!(file.name, None);
+        assert_eq!(
+            (file.width, file.height),
+            (Dimension::Auto, Dimension::Auto)
+        );
+        assert!(file.preserve_aspect_ratio);
+        assert!(!file.inline && !file.keep_cursor);
+        assert_eq!(file.data.as_deref(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn shrinks_wide_images_to_the_screen() {
+        // The screen is 100 by 100 pixels.
+        let term = Term::new(10, 5);
+        assert_eq!(scaled(&term, (50, 30), ""), (50, 30));
+        assert_eq!(scaled(&term, (200, 40), ""), (100, 20));
+    }
+
+    #[test]
+    fn scales_keeping_the_aspect_ratio() {
+        let term = Term::new(10, 5);
+        assert_eq!(scaled(&term, (20, 10), "width=5"), (50, 25));
+        assert_eq!(scaled(&term, (20, 10), "height=50%"), (100, 50));
+        assert_eq!(scaled(&term, (20, 10), "width=30px;height=30px"), (30, 15));
+        assert_eq!(scaled(&term, (10, 20), "width=30px;height=30px"), (15, 30));
+    }
+
+    #[test]
+    fn scales_ignoring_the_aspect_ratio() {
+        let term = Term::new(10, 5);
+        let args = "preserveAspectRatio=0";
+        assert_eq!(
+            scaled(&term, (20, 10), &format!("{};width=30px", args)),
+            (30, 10)
+        );
+        assert_eq!(
+            scaled(&term, (20, 10), &format!("{};width=30px;height=30px", args)),
+            (30, 30)
+        );
+        // Nothing is scaled to less than a pixel.
+        assert_eq!(
+            scaled(&term, (20, 10), &format!("{};width=0px", args)),
+            (1, 10)
+        );
+    }
+
+    #[test]
+    fn clamps_huge_sizes() {
+        let term = Term::new(10, 5);
+        let args = format!("preserveAspectRatio=0;width={};height=1px", usize::MAX);
+        assert_eq!(scaled(&term, (1, 1), &args), (config::IMAGE_MAX_SIZE, 1));
+        let args = format!("width=1px;height={}", usize::MAX);
+        assert_eq!(scaled(&term, (1, 1), &args), (1, 1));
+    }
+
+    #[test]
+    fn shows_inline_images() {
+        let mut term = Term::new(10, 5);
+        let data = base64::encode(&png(20, 40));
+        term.write(format!("\x1b]1337;File=inline=1:{}\x07", data).as_bytes());
+        assert_eq!(term.images.len(), 1);
+        assert_eq!(term.lines[0].images.len(), 2);
+        assert_eq!(term.lines[1].images.len(), 2);
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 2));
+
+        term.write(format!("\x1b]1337;File=inline=1;doNotMoveCursor=1:{}\x07", data).as_bytes());
+        assert_eq!(term.images.len(), 2);
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 2));
+    }
+
+    #[test]
+    fn drops_cut_short_and_other_files() {
+        let mut term = Term::new(10, 5);
+        let data = base64::encode(&png(20, 40));
+        term.write(format!("\x1b]1337;File=inline=1;size=1:{}\x07", data).as_bytes());
+        term.write(format!("\x1b]1337;File=name=Zm9v:{}\x07", data).as_bytes());
+        term.write(b"\x1b]1337;File=inline=1:YWJj\x07");
+        assert!(term.images.is_empty());
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+    }
+}
@@ -0,0 +1,635 @@
+// Encoding of key presses for the child, for the kitty keyboard protocol, xterm's
+// modifyOtherKeys and VT52 mode.
+//
+// See https://sw.kovidgoyal.net/kitty/keyboard-protocol/ for the details of the kitty protocol.
+
+#![allow(non_upper_case_globals)]
+
+use std::os::raw::c_uint;
+
+use bitflags::bitflags;
+
+use crate::x::{
+    ControlMask, KeySym, LockMask, Mod1Mask, Mod2Mask, Mod3Mask, Mod4Mask, Mod5Mask, ShiftMask,
+    XK_Alt_L, XK_Alt_R, XK_BackSpace, XK_Begin, XK_Caps_Lock, XK_Control_L, XK_Control_R,
+    XK_Delete, XK_Down, XK_End, XK_Escape, XK_Home, XK_Hyper_L, XK_Hyper_R, XK_ISO_Left_Tab,
+    XK_ISO_Level3_Shift, XK_ISO_Level5_Lock, XK_ISO_Level5_Shift, XK_ISO_Lock, XK_Insert,
+    XK_KP_Add, XK_KP_Begin, XK_KP_Decimal, XK_KP_Delete, XK_KP_Divide, XK_KP_Down, XK_KP_End,
+    XK_KP_Enter, XK_KP_Equal, XK_KP_Home, XK_KP_Insert, XK_KP_Left, XK_KP_Multiply,
+    XK_KP_Page_Down, XK_KP_Page_Up, XK_KP_Right, XK_KP_Separator, XK_KP_Subtract, XK_KP_Up,
+    XK_Left, XK_Menu, XK_Meta_L, XK_Meta_R, XK_Mode_switch, XK_Num_Lock, XK_Page_Down, XK_Page_Up,
+    XK_Pause, XK_Print, XK_Return, XK_Right, XK_Scroll_Lock, XK_Shift_L, XK_Shift_R, XK_Super_L,
+    XK_Super_R, XK_Tab, XK_Up, XK_F1, XK_F10, XK_F11, XK_F12, XK_F13, XK_F2, XK_F3, XK_F35, XK_F4,
+    XK_F5, XK_F6, XK_F7, XK_F8, XK_F9, XK_KP_0, XK_KP_9, XK_KP_F1, XK_KP_F2, XK_KP_F3, XK_KP_F4,
+};
+
+/// Most flag sets kept on each screen's stack. Pushing past this drops the oldest.
+pub const KEYBOARD_STACK_LIMIT: usize = 16;
+
+bitflags! {
+    /// Progressive enhancements an application may ask for.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct KeyboardFlags: u32 {
+        const DISAMBIGUATE = 1 << 0;
+        const REPORT_EVENT_TYPES = 1 << 1;
+        const REPORT_ALTERNATE_KEYS = 1 << 2;
+        const REPORT_ALL_KEYS = 1 << 3;
+        const REPORT_ASSOCIATED_TEXT = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// Modifiers as the protocol numbers them, which is one less than the number sent.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct KeyModifiers: u32 {
+        const SHIFT = 1 << 0;
+        const ALT = 1 << 1;
+        const CONTROL = 1 << 2;
+        const SUPER = 1 << 3;
+        const HYPER = 1 << 4;
+        const META = 1 << 5;
+        const CAPS_LOCK = 1 << 6;
+        const NUM_LOCK = 1 << 7;
+    }
+}
+
+impl KeyModifiers {
+    /// Converts the modifier state of an X event, using the usual layout of the modifier map:
+    /// Alt on Mod1, Num Lock on Mod2, Hyper on Mod3, Super on Mod4 and Meta on Mod5.
+    pub fn from_x_state(state: c_uint) -> Self {
+        let mut modifiers = KeyModifiers::empty();
+        for (mask, modifier) in [
+            (ShiftMask, KeyModifiers::SHIFT),
+            (Mod1Mask, KeyModifiers::ALT),
+            (ControlMask, KeyModifiers::CONTROL),
+            (Mod4Mask, KeyModifiers::SUPER),
+            (Mod3Mask, KeyModifiers::HYPER),
+            (Mod5Mask, KeyModifiers::META),
+            (LockMask, KeyModifiers::CAPS_LOCK),
+            (Mod2Mask, KeyModifiers::NUM_LOCK),
+        ] {
+            if state & mask != 0 {
+                modifiers |= modifier;
+            }
+        }
+        modifiers
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventKind {
+    Press,
+    Repeat,
+    Release,
+}
+
+pub struct KeyEvent<'a> {
+    /// Key symbol as looked up with the modifiers applied, eg: `XK_A` for Shift+a.
+    pub key_symbol: KeySym,
+    /// Key symbol of the key without any modifiers, eg: `XK_a` for Shift+a.
+    pub base_symbol: KeySym,
+    pub modifiers: KeyModifiers,
+    pub kind: KeyEventKind,
+    /// Text the key produces, which is empty for releases and keys such as arrows.
+    pub text: &'a str,
+}
+
+/// How a key is written in an escape code, as `CSI number ; modifiers final`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyCode {
+    number: u32,
+    final_char: char,
+}
+
+impl KeyCode {
+    const fn new(number: u32, final_char: char) -> Self {
+        KeyCode { number, final_char }
+    }
+}
+
+/// Escape code of keys that don't produce text, or that have their own code like Enter.
+fn functional_key(key_symbol: KeySym) -> Option<KeyCode> {
+    let key_symbol = u32::try_from(key_symbol).ok()?;
+    let code = match key_symbol {
+        XK_Escape => KeyCode::new(27, 'u'),
+        XK_Return => KeyCode::new(13, 'u'),
+        XK_Tab | XK_ISO_Left_Tab => KeyCode::new(9, 'u'),
+        XK_BackSpace => KeyCode::new(127, 'u'),
+        XK_Insert => KeyCode::new(2, '~'),
+        XK_Delete => KeyCode::new(3, '~'),
+        XK_Left => KeyCode::new(1, 'D'),
+        XK_Right => KeyCode::new(1, 'C'),
+        XK_Up => KeyCode::new(1, 'A'),
+        XK_Down => KeyCode::new(1, 'B'),
+        XK_Page_Up => KeyCode::new(5, '~'),
+        XK_Page_Down => KeyCode::new(6, '~'),
+        XK_Home => KeyCode::new(1, 'H'),
+        XK_End => KeyCode::new(1, 'F'),
+        XK_Begin => KeyCode::new(1, 'E'),
+        XK_Caps_Lock => KeyCode::new(57358, 'u'),
+        XK_Scroll_Lock => KeyCode::new(57359, 'u'),
+        XK_Num_Lock => KeyCode::new(57360, 'u'),
+        XK_Print => KeyCode::new(57361, 'u'),
+        XK_Pause => KeyCode::new(57362, 'u'),
+        XK_Menu => KeyCode::new(57363, 'u'),
+        XK_F1 => KeyCode::new(1, 'P'),
+        XK_F2 => KeyCode::new(1, 'Q'),
+        // F3 can't use `CSI R`, which is a cursor position report.
+        XK_F3 => KeyCode::new(13, '~'),
+        XK_F4 => KeyCode::new(1, 'S'),
+        XK_F5 => KeyCode::new(15, '~'),
+        XK_F6 => KeyCode::new(17, '~'),
+        XK_F7 => KeyCode::new(18, '~'),
+        XK_F8 => KeyCode::new(19, '~'),
+        XK_F9 => KeyCode::new(20, '~'),
+        XK_F10 => KeyCode::new(21, '~'),
+        XK_F11 => KeyCode::new(23, '~'),
+        XK_F12 => KeyCode::new(24, '~'),
+        XK_F13..=XK_F35 => KeyCode::new(57376 + key_symbol - XK_F13, 'u'),
+        XK_KP_0..=XK_KP_9 => KeyCode::new(57399 + key_symbol - XK_KP_0, 'u'),
+        XK_KP_Decimal => KeyCode::new(57409, 'u'),
+        XK_KP_Divide => KeyCode::new(57410, 'u'),
+        XK_KP_Multiply => KeyCode::new(57411, 'u'),
+        XK_KP_Subtract => KeyCode::new(57412, 'u'),
+        XK_KP_Add => KeyCode::new(57413, 'u'),
+        XK_KP_Enter => KeyCode::new(57414, 'u'),
+        XK_KP_Equal => KeyCode::new(57415, 'u'),
+        XK_KP_Separator => KeyCode::new(57416, 'u'),
+        XK_KP_Left => KeyCode::new(57417, 'u'),
+        XK_KP_Right => KeyCode::new(57418, 'u'),
+        XK_KP_Up => KeyCode::new(57419, 'u'),
+        XK_KP_Down => KeyCode::new(57420, 'u'),
+        XK_KP_Page_Up => KeyCode::new(57421, 'u'),
+        XK_KP_Page_Down => KeyCode::new(57422, 'u'),
+        XK_KP_Home => KeyCode::new(57423, 'u'),
+        XK_KP_End => KeyCode::new(57424, 'u'),
+        XK_KP_Insert => KeyCode::new(57425, 'u'),
+        XK_KP_Delete => KeyCode::new(57426, 'u'),
+        XK_KP_Begin => KeyCode::new(57427, 'u'),
+        XK_Shift_L => KeyCode::new(57441, 'u'),
+        XK_Control_L => KeyCode::new(57442, 'u'),
+        XK_Alt_L => KeyCode::new(57443, 'u'),
+        XK_Super_L => KeyCode::new(57444, 'u'),
+        XK_Hyper_L => KeyCode::new(57445, 'u'),
+        XK_Meta_L => KeyCode::new(57446, 'u'),
+        XK_Shift_R => KeyCode::new(57447, 'u'),
+        XK_Control_R => KeyCode::new(57448, 'u'),
+        XK_Alt_R => KeyCode::new(57449, 'u'),
+        XK_Super_R => KeyCode::new(57450, 'u'),
+        XK_Hyper_R => KeyCode::new(57451, 'u'),
+        XK_Meta_R => KeyCode::new(57452, 'u'),
+        XK_ISO_Level3_Shift => KeyCode::new(57453, 'u'),
+        XK_ISO_Level5_Shift => KeyCode::new(57454, 'u'),
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn is_modifier_key(code: KeyCode) -> bool {
+    code.final_char == 'u' && (57441..=57454).contains(&code.number)
+}
+
+/// Whether a key symbol is for a modifier key, as Xlib's `IsModifierKey`.
+pub fn is_modifier_key_symbol(key_symbol: KeySym) -> bool {
+    let key_symbol = key_symbol as u32;
+    (XK_Shift_L..=XK_Hyper_R).contains(&key_symbol)
+        || (XK_ISO_Lock..=XK_ISO_Level5_Lock).contains(&key_symbol)
+        || key_symbol == XK_Mode_switch
+        || key_symbol == XK_Num_Lock
+}
+
+/// Character a key symbol stands for, for Latin-1 and Unicode key symbols.
+fn key_symbol_char(key_symbol: KeySym) -> Option<char> {
+    match key_symbol {
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(key_symbol as u32),
+        0x0100_0100..=0x0110_ffff => char::from_u32((key_symbol - 0x0100_0000) as u32),
+        _ => None,
+    }
+}
+
+/// Encodes a key event with the given enhancements.
+///
+/// Returns `None` when the key should be sent the legacy way, and an empty sequence when
+/// nothing should be sent at all, such as for releases the application didn't ask for.
+pub fn encode(flags: KeyboardFlags, event: &KeyEvent) -> Option<Vec<u8>> {
+    if flags.is_empty() {
+        return None;
+    }
+    let report_all = flags.contains(KeyboardFlags::REPORT_ALL_KEYS);
+    let report_events = flags.contains(KeyboardFlags::REPORT_EVENT_TYPES);
+    let kind = match event.kind {
+        KeyEventKind::Repeat if !report_events => KeyEventKind::Press,
+        kind => kind,
+    };
+    // Lock keys are left out unless everything is reported, so that legacy key handling in the
+    // application keeps working with Caps Lock or Num Lock on.
+    let mut modifiers = event.modifiers;
+    if !report_all {
+        modifiers.remove(KeyModifiers::CAPS_LOCK | KeyModifiers::NUM_LOCK);
+    }
+    // Nothing is sent for a release unless the application asked, or legacy text would be.
+    let legacy = |kind: KeyEventKind| match kind {
+        KeyEventKind::Release => Some(Vec::new()),
+        _ => None,
+    };
+
+    let (code, shifted) = if let Some(code) = functional_key(event.key_symbol) {
+        if is_modifier_key(code) && !report_all {
+            return Some(Vec::new());
+        }
+        // Enter, Tab and Backspace keep working in shells that don't know about the protocol.
+        if !report_all
+            && modifiers.is_empty()
+            && code.final_char == 'u'
+            && matches!(code.number, 13 | 9 | 127)
+        {
+            return legacy(kind);
+        }
+        if !flags.contains(KeyboardFlags::DISAMBIGUATE) && !report_all {
+            return legacy(kind);
+        }
+        (code, None)
+    } else {
+        let Some(base) = key_symbol_char(event.base_symbol)
+            .or_else(|| event.text.chars().next())
+            .map(|c| c.to_lowercase().next().unwrap_or(c))
+        else {
+            return legacy(kind);
+        };
+        // Text typed with at most Shift held is sent as it is, unless everything is reported.
+        if !report_all
+            && ((modifiers - KeyModifiers::SHIFT).is_empty()
+                || !flags.contains(KeyboardFlags::DISAMBIGUATE))
+        {
+            return legacy(kind);
+        }
+        let shifted = key_symbol_char(event.key_symbol).filter(|&c| {
+            modifiers.contains(KeyModifiers::SHIFT)
+                && c != base
+                && flags.contains(KeyboardFlags::REPORT_ALTERNATE_KEYS)
+        });
+        (KeyCode::new(base as u32, 'u'), shifted)
+    };
+
+    if kind == KeyEventKind::Release && !report_events {
+        return Some(Vec::new());
+    }
+
+    let text: Vec<String> = if report_all
+        && flags.contains(KeyboardFlags::REPORT_ASSOCIATED_TEXT)
+        && kind != KeyEventKind::Release
+        && code.final_char == 'u'
+    {
+        event
+            .text
+            .chars()
+            .filter(|c| !c.is_control())
+            .map(|c| (c as u32).to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut sequence = String::from("\x1b[");
+    let has_modifiers = !modifiers.is_empty() || kind != KeyEventKind::Press || !text.is_empty();
+    // Keys ending in a letter leave out the number when there is nothing after it.
+    if !code.final_char.is_ascii_uppercase() || has_modifiers {
+        sequence += &code.number.to_string();
+    }
+    if let Some(shifted) = shifted {
+        sequence += &format!(":{}", shifted as u32);
+    }
+    if has_modifiers {
+        sequence += &format!(";{}", modifiers.bits() + 1);
+        match kind {
+            KeyEventKind::Press => {}
+            KeyEventKind::Repeat => sequence += ":2",
+            KeyEventKind::Release => sequence += ":3",
+        }
+    }
+    if !text.is_empty() {
+        sequence += ";";
+        sequence += &text.join(":");
+    }
+    sequence.push(code.final_char);
+    Some(sequence.into_bytes())
+}
+
+/// Encodes a key event the way xterm does with modifyOtherKeys at `level` 1 or 2, as
+/// `CSI 27 ; modifiers ; code ~`, or `CSI code ; modifiers u` when `format` is set.
+///
+/// Returns `None` when the key should be sent the legacy way. Level 1 leaves out keys that
+/// already have a well known encoding, such as Ctrl+letter and Alt+key.
+pub fn encode_modify_other_keys(level: u8, format: bool, event: &KeyEvent) -> Option<Vec<u8>> {
+    if level == 0 || event.kind == KeyEventKind::Release {
+        return None;
+    }
+    let mut modifiers =
+        event.modifiers & (KeyModifiers::SHIFT | KeyModifiers::ALT | KeyModifiers::CONTROL);
+    // xterm only knows one more modifier, which it calls Meta.
+    if event
+        .modifiers
+        .intersects(KeyModifiers::SUPER | KeyModifiers::META)
+    {
+        modifiers |= KeyModifiers::SUPER;
+    }
+
+    let (code, printable) = match u32::try_from(event.key_symbol).ok()? {
+        XK_Return | XK_KP_Enter => (13, false),
+        XK_Tab | XK_ISO_Left_Tab => (9, false),
+        XK_BackSpace => (127, false),
+        XK_Escape => (27, false),
+        _ => {
+            let c = key_symbol_char(event.key_symbol).or_else(|| event.text.chars().next())?;
+            (c as u32, !c.is_control())
+        }
+    };
+
+    // Shift just picks another character, which is sent as it is.
+    if modifiers.is_empty() || (modifiers == KeyModifiers::SHIFT && printable) {
+        return None;
+    }
+    if level == 1 {
+        // Only keys with Control held and no control character of their own are changed.
+        let c = char::from_u32(code)?;
+        let has_control_code = printable && (c.is_ascii_alphabetic() || "@[\\]^_ ?".contains(c));
+        if !modifiers.contains(KeyModifiers::CONTROL)
+            || (has_control_code && !modifiers.contains(KeyModifiers::SHIFT))
+        {
+            return None;
+        }
+    }
+
+    let sequence = if format {
+        format!("\x1b[{};{}u", code, modifiers.bits() + 1)
+    } else {
+        format!("\x1b[27;{};{}~", modifiers.bits() + 1, code)
+    };
+    Some(sequence.into_bytes())
+}
+
+/// Encodes a key the way a VT52 does, where the cursor keys and PF1 to PF4 send `ESC` and a
+/// letter, and the keypad sends `ESC ?` and a letter in alternate keypad mode.
+///
+/// Returns `None` when the key is sent the same way as in ANSI mode.
+pub fn encode_vt52(key_symbol: KeySym, app_keypad: bool) -> Option<Vec<u8>> {
+    let key_symbol = u32::try_from(key_symbol).ok()?;
+    let (keypad, c) = match key_symbol {
+        XK_Up | XK_KP_Up => (false, 'A'),
+        XK_Down | XK_KP_Down => (false, 'B'),
+        XK_Right | XK_KP_Right => (false, 'C'),
+        XK_Left | XK_KP_Left => (false, 'D'),
+        XK_F1 | XK_KP_F1 => (false, 'P'),
+        XK_F2 | XK_KP_F2 => (false, 'Q'),
+        XK_F3 | XK_KP_F3 => (false, 'R'),
+        XK_F4 | XK_KP_F4 => (false, 'S'),
+        _ if !app_keypad => return None,
+        XK_KP_0..=XK_KP_9 => (true, char::from_u32('p' as u32 + key_symbol - XK_KP_0)?),
+        XK_KP_Subtract => (true, 'm'),
+        XK_KP_Separator => (true, 'l'),
+        XK_KP_Decimal => (true, 'n'),
+        XK_KP_Enter => (true, 'M'),
+        _ => return None,
+    };
+    let sequence = if keypad {
+        format!("\x1b?{}", c)
+    } else {
+        format!("\x1b{}", c)
+    };
+    Some(sequence.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x::{XK_a, XK_1, XK_A};
+
+    fn event(
+        key_symbol: u32,
+        modifiers: KeyModifiers,
+        kind: KeyEventKind,
+        text: &str,
+    ) -> KeyEvent<'_> {
+        let base_symbol = match key_symbol {
+            XK_A => XK_a,
+            _ => key_symbol,
+        };
+        KeyEvent {
+            key_symbol: key_symbol as KeySym,
+            base_symbol: base_symbol as KeySym,
+            modifiers,
+            kind,
+            text,
+        }
+    }
+
+    fn press(key_symbol: u32, modifiers: KeyModifiers, text: &str) -> KeyEvent<'_> {
+        event(key_symbol, modifiers, KeyEventKind::Press, text)
+    }
+
+    fn encoded(flags: KeyboardFlags, event: &KeyEvent) -> Option<String> {
+        encode(flags, event).map(|sequence| String::from_utf8(sequence).unwrap())
+    }
+
+    #[test]
+    fn sends_keys_the_legacy_way_without_flags() {
+        let ctrl_a = press(XK_a, KeyModifiers::CONTROL, "\x01");
+        assert_eq!(encoded(KeyboardFlags::empty(), &ctrl_a), None);
+    }
+
+    #[test]
+    fn disambiguates_keys_with_modifiers() {
+        let flags = KeyboardFlags::DISAMBIGUATE;
+        assert_eq!(
+            encoded(flags, &press(XK_a, KeyModifiers::empty(), "a")),
+            None
+        );
+        assert_eq!(encoded(flags, &press(XK_A, KeyModifiers::SHIFT, "A")), None);
+        assert_eq!(
+            encoded(flags, &press(XK_a, KeyModifiers::CONTROL, "\x01")).as_deref(),
+            Some("\x1b[97;5u")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_Escape, KeyModifiers::empty(), "\x1b")).as_deref(),
+            Some("\x1b[27u")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_Up, KeyModifiers::empty(), "")).as_deref(),
+            Some("\x1b[A")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_Up, KeyModifiers::SHIFT, "")).as_deref(),
+            Some("\x1b[1;2A")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_F5, KeyModifiers::empty(), "")).as_deref(),
+            Some("\x1b[15~")
+        );
+    }
+
+    #[test]
+    fn keeps_enter_tab_and_backspace_legacy() {
+        let flags = KeyboardFlags::DISAMBIGUATE;
+        for key_symbol in [XK_Return, XK_Tab, XK_BackSpace] {
+            assert_eq!(
+                encoded(flags, &press(key_symbol, KeyModifiers::empty(), "")),
+                None
+            );
+            let release = event(key_symbol, KeyModifiers::empty(), KeyEventKind::Release, "");
+            assert_eq!(encoded(flags, &release).as_deref(), Some(""));
+        }
+        assert_eq!(
+            encoded(flags, &press(XK_Return, KeyModifiers::ALT, "")).as_deref(),
+            Some("\x1b[13;3u")
+        );
+    }
+
+    #[test]
+    fn leaves_out_lock_modifiers_unless_reporting_all_keys() {
+        let modifiers = KeyModifiers::CONTROL | KeyModifiers::CAPS_LOCK | KeyModifiers::NUM_LOCK;
+        let ctrl_a = press(XK_a, modifiers, "\x01");
+        assert_eq!(
+            encoded(KeyboardFlags::DISAMBIGUATE, &ctrl_a).as_deref(),
+            Some("\x1b[97;5u")
+        );
+        assert_eq!(
+            encoded(KeyboardFlags::REPORT_ALL_KEYS, &ctrl_a).as_deref(),
+            Some("\x1b[97;197u")
+        );
+    }
+
+    #[test]
+    fn reports_repeats_and_releases_when_asked() {
+        let kinds = |flags| {
+            [
+                KeyEventKind::Press,
+                KeyEventKind::Repeat,
+                KeyEventKind::Release,
+            ]
+            .map(|kind| encoded(flags, &event(XK_a, KeyModifiers::CONTROL, kind, "\x01")))
+        };
+        assert_eq!(
+            kinds(KeyboardFlags::DISAMBIGUATE),
+            [
+                Some("\x1b[97;5u".to_string()),
+                Some("\x1b[97;5u".to_string()),
+                Some(String::new()),
+            ]
+        );
+        assert_eq!(
+            kinds(KeyboardFlags::DISAMBIGUATE | KeyboardFlags::REPORT_EVENT_TYPES),
+            [
+                Some("\x1b[97;5u".to_string()),
+                Some("\x1b[97;5:2u".to_string()),
+                Some("\x1b[97;5:3u".to_string()),
+            ]
+        );
+
+        let flags = KeyboardFlags::DISAMBIGUATE | KeyboardFlags::REPORT_EVENT_TYPES;
+        let release = event(XK_Up, KeyModifiers::empty(), KeyEventKind::Release, "");
+        assert_eq!(encoded(flags, &release).as_deref(), Some("\x1b[1;1:3A"));
+    }
+
+    #[test]
+    fn reports_all_keys_as_escape_codes() {
+        let flags = KeyboardFlags::REPORT_ALL_KEYS;
+        assert_eq!(
+            encoded(flags, &press(XK_a, KeyModifiers::empty(), "a")).as_deref(),
+            Some("\x1b[97u")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_Return, KeyModifiers::empty(), "\r")).as_deref(),
+            Some("\x1b[13u")
+        );
+        assert_eq!(
+            encoded(flags, &press(XK_Shift_L, KeyModifiers::empty(), "")).as_deref(),
+            Some("\x1b[57441u")
+        );
+        // Modifier keys are only reported along with every other key.
+        assert_eq!(
+            encoded(
+                KeyboardFlags::DISAMBIGUATE,
+                &press(XK_Shift_L, KeyModifiers::empty(), "")
+            )
+            .as_deref(),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn reports_alternate_keys_and_text() {
+        let flags = KeyboardFlags::REPORT_ALL_KEYS
+            | KeyboardFlags::REPORT_ALTERNATE_KEYS
+            | KeyboardFlags::REPORT_ASSOCIATED_TEXT;
+        assert_eq!(
+            encoded(flags, &press(XK_A, KeyModifiers::SHIFT, "A")).as_deref(),
+            Some("\x1b[97:65;2;65u")
+        );
+        // Control characters aren't sent as text.
+        assert_eq!(
+            encoded(flags, &press(XK_a, KeyModifiers::CONTROL, "\x01")).as_deref(),
+            Some("\x1b[97;5u")
+        );
+    }
+
+    #[test]
+    fn encodes_modify_other_keys() {
+        let encoded = |level, format, event: &KeyEvent| {
+            encode_modify_other_keys(level, format, event)
+                .map(|sequence| String::from_utf8(sequence).unwrap())
+        };
+        let ctrl_a = press(XK_a, KeyModifiers::CONTROL, "\x01");
+        let ctrl_shift_a = press(XK_A, KeyModifiers::CONTROL | KeyModifiers::SHIFT, "\x01");
+        let ctrl_1 = press(XK_1, KeyModifiers::CONTROL, "1");
+        let shift_a = press(XK_A, KeyModifiers::SHIFT, "A");
+
+        assert_eq!(encoded(0, false, &ctrl_a), None);
+        assert_eq!(encoded(1, false, &ctrl_a), None);
+        assert_eq!(
+            encoded(1, false, &ctrl_shift_a).as_deref(),
+            Some("\x1b[27;6;65~")
+        );
+        assert_eq!(encoded(1, false, &ctrl_1).as_deref(), Some("\x1b[27;5;49~"));
+        assert_eq!(encoded(2, false, &ctrl_a).as_deref(), Some("\x1b[27;5;97~"));
+        assert_eq!(encoded(2, true, &ctrl_a).as_deref(), Some("\x1b[97;5u"));
+        assert_eq!(encoded(2, false, &shift_a), None);
+        let release = event(XK_a, KeyModifiers::CONTROL, KeyEventKind::Release, "");
+        assert_eq!(encoded(2, false, &release), None);
+    }
+
+    #[test]
+    fn encodes_vt52_keys() {
+        let encoded = |key_symbol: u32, app_keypad| {
+            encode_vt52(key_symbol as KeySym, app_keypad)
+                .map(|sequence| String::from_utf8(sequence).unwrap())
+        };
+        assert_eq!(encoded(XK_Up, false).as_deref(), Some("\x1bA"));
+        assert_eq!(encoded(XK_KP_Left, false).as_deref(), Some("\x1bD"));
+        assert_eq!(encoded(XK_F1, false).as_deref(), Some("\x1bP"));
+        assert_eq!(encoded(XK_KP_F4, true).as_deref(), Some("\x1bS"));
+
+        // The keypad only sends escape codes in alternate keypad mode.
+        assert_eq!(encoded(XK_KP_0 + 5, false), None);
+        assert_eq!(encoded(XK_KP_0 + 5, true).as_deref(), Some("\x1b?u"));
+        assert_eq!(encoded(XK_KP_Enter, true).as_deref(), Some("\x1b?M"));
+        assert_eq!(encoded(XK_KP_Subtract, true).as_deref(), Some("\x1b?m"));
+        assert_eq!(encoded(XK_Return, true), None);
+        assert_eq!(encoded(XK_a, true), None);
+    }
+
+    #[test]
+    fn recognises_modifier_key_symbols() {
+        for key_symbol in [
+            XK_Shift_L,
+            XK_Control_R,
+            XK_Hyper_R,
+            XK_ISO_Level3_Shift,
+            XK_Num_Lock,
+        ] {
+            assert!(is_modifier_key_symbol(key_symbol as KeySym));
+        }
+        for key_symbol in [XK_a, XK_Return, XK_Escape, XK_F1, XK_Hyper_R + 1] {
+            assert!(!is_modifier_key_symbol(key_symbol as KeySym));
+        }
+    }
+}
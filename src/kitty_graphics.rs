@@ -0,0 +1,1041 @@
+// The kitty graphics protocol, sent in APC strings as `APC G keys ; payload ST`.
+//
+// See https://sw.kovidgoyal.net/kitty/graphics-protocol/ for the details of the protocol.
+// Images are kept as they were sent, and each placement of one is scaled to fit its cells and
+// shown through tiles on the lines like any other image.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::ZlibDecoder;
+
+use crate::{
+    base64, config,
+    graphics::{Image, ImageCell},
+    st::{is_true_color, Term},
+};
+
+/// Character standing in for a cell of a virtual placement, with diacritics giving the row and
+/// column of the cell and the foreground colour giving the image id.
+pub const PLACEHOLDER: char = '\u{10EEEE}';
+
+/// Diacritics numbering the rows and columns of placeholders, in order.
+const DIACRITICS: &[(u32, u32)] = &[
+    (0x0305, 0x0305),
+    (0x030D, 0x030E),
+    (0x0310, 0x0310),
+    (0x0312, 0x0312),
+    (0x033D, 0x033F),
+    (0x0346, 0x0346),
+    (0x034A, 0x034C),
+    (0x0350, 0x0352),
+    (0x0357, 0x0357),
+    (0x035B, 0x035B),
+    (0x0363, 0x036F),
+    (0x0483, 0x0487),
+    (0x0592, 0x0595),
+    (0x0597, 0x0599),
+    (0x059C, 0x05A1),
+    (0x05A8, 0x05A9),
+    (0x05AB, 0x05AC),
+    (0x05AF, 0x05AF),
+    (0x05C4, 0x05C4),
+    (0x0610, 0x0617),
+    (0x0657, 0x065B),
+    (0x065D, 0x065E),
+    (0x06D6, 0x06DC),
+    (0x06DF, 0x06E2),
+    (0x06E4, 0x06E4),
+    (0x06E7, 0x06E8),
+    (0x06EB, 0x06EC),
+    (0x0730, 0x0730),
+    (0x0732, 0x0733),
+    (0x0735, 0x0736),
+    (0x073A, 0x073A),
+    (0x073D, 0x073D),
+    (0x073F, 0x0741),
+    (0x0743, 0x0743),
+    (0x0745, 0x0745),
+    (0x0747, 0x0747),
+    (0x0749, 0x074A),
+    (0x07EB, 0x07F1),
+    (0x07F3, 0x07F3),
+    (0x0816, 0x0819),
+    (0x081B, 0x0823),
+    (0x0825, 0x0827),
+    (0x0829, 0x082D),
+    (0x0951, 0x0951),
+    (0x0953, 0x0954),
+    (0x0F82, 0x0F83),
+    (0x0F86, 0x0F87),
+    (0x135D, 0x135F),
+    (0x17DD, 0x17DD),
+    (0x193A, 0x193A),
+    (0x1A17, 0x1A17),
+    (0x1A75, 0x1A7C),
+    (0x1B6B, 0x1B6B),
+    (0x1B6D, 0x1B73),
+    (0x1DC0, 0x1DC1),
+    (0x1DC3, 0x1DC9),
+    (0x1DCB, 0x1DCC),
+    (0x1DD1, 0x1DE6),
+    (0x1DFE, 0x1DFE),
+    (0x20D0, 0x20D1),
+    (0x20D4, 0x20D7),
+    (0x20DB, 0x20DC),
+    (0x20E1, 0x20E1),
+    (0x20E7, 0x20E7),
+    (0x20E9, 0x20E9),
+    (0x20F0, 0x20F0),
+    (0x2CEF, 0x2CF1),
+    (0x2DE0, 0x2DFF),
+    (0xA66F, 0xA66F),
+    (0xA67C, 0xA67D),
+    (0xA6F0, 0xA6F1),
+    (0xA8E0, 0xA8F1),
+    (0xAAB0, 0xAAB0),
+    (0xAAB2, 0xAAB3),
+    (0xAAB7, 0xAAB8),
+    (0xAABE, 0xAABF),
+    (0xAAC1, 0xAAC1),
+    (0xFE20, 0xFE26),
+    (0x10A0F, 0x10A0F),
+    (0x10A38, 0x10A38),
+    (0x1D185, 0x1D189),
+    (0x1D1AA, 0x1D1AD),
+    (0x1D242, 0x1D244),
+];
+
+/// Number a placeholder diacritic stands for.
+fn diacritic_value(c: char) -> Option<usize> {
+    let c = c as u32;
+    let mut value = 0;
+    for &(first, last) in DIACRITICS {
+        if (first..=last).contains(&c) {
+            return Some(value + (c - first) as usize);
+        }
+        value += (last - first + 1) as usize;
+    }
+    None
+}
+
+/// A graphics command, with the keys not given left at their defaults.
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// `a`: transmit (`t`), transmit and display (`T`), query (`q`), put (`p`) or delete (`d`).
+    pub action: char,
+    /// `q`: 1 leaves out replies for success, 2 leaves out errors as well.
+    pub quiet: u32,
+    /// `f`: 24 for RGB, 32 for RGBA or 100 for PNG.
+    pub format: u32,
+    /// `t`: the payload is the data (`d`), or names a file (`f`), temporary file (`t`) or
+    /// shared memory object (`s`) to read it from.
+    pub medium: char,
+    /// `o=z`: the data is compressed with zlib.
+    pub compressed: bool,
+    /// `s` and `v`: size of RGB and RGBA data in pixels.
+    pub width: usize,
+    pub height: usize,
+    /// `S` and `O`: amount of a file to read and where from.
+    pub size: usize,
+    pub offset: usize,
+    /// `i`, `I` and `p`: image id, image number and placement id.
+    pub id: u32,
+    pub number: u32,
+    pub placement: u32,
+    /// `m`: more chunks of the payload follow.
+    pub more: bool,
+    /// `x`, `y`, `w` and `h`: the part of the image to show, in pixels. Deleting by position
+    /// uses `x` and `y` as a cell instead.
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+    /// `X` and `Y`: offset of the image within its first cell, in pixels.
+    pub cell_x: usize,
+    pub cell_y: usize,
+    /// `c` and `r`: columns and rows to scale the image to.
+    pub cols: usize,
+    pub rows: usize,
+    /// `C=1` leaves the cursor where it is.
+    pub keep_cursor: bool,
+    /// `U=1` creates a virtual placement, shown through placeholder characters.
+    pub is_virtual: bool,
+    /// `z`: stacking order against text and other images.
+    pub z: i32,
+    /// `d`: what to delete.
+    pub delete: char,
+    pub payload: String,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command {
+            action: 't',
+            quiet: 0,
+            format: 32,
+            medium: 'd',
+            compressed: false,
+            width: 0,
+            height: 0,
+            size: 0,
+            offset: 0,
+            id: 0,
+            number: 0,
+            placement: 0,
+            more: false,
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+            cell_x: 0,
+            cell_y: 0,
+            cols: 0,
+            rows: 0,
+            keep_cursor: false,
+            is_virtual: false,
+            z: 0,
+            delete: 'a',
+            payload: String::new(),
+        }
+    }
+}
+
+impl Command {
+    /// Parses the part of the APC string after the `G`, as `key=value,...;payload`.
+    pub fn parse(buf: &str) -> Self {
+        let (keys, payload) = buf.split_once(';').unwrap_or((buf, ""));
+        let mut command = Command {
+            payload: payload.to_string(),
+            ..Command::default()
+        };
+        for (key, value) in keys.split(',').filter_map(|pair| pair.split_once('=')) {
+            let number = || value.parse::<u32>().unwrap_or(0);
+            let size = || value.parse::<usize>().unwrap_or(0);
+            let letter = || value.chars().next().unwrap_or_default();
+            match key {
+                "a" => command.action = letter(),
+                "q" => command.quiet = number(),
+                "f" => command.format = number(),
+                "t" => command.medium = letter(),
+                "o" => command.compressed = value == "z",
+                "s" => command.width = size(),
+                "v" => command.height = size(),
+                "S" => command.size = size(),
+                "O" => command.offset = size(),
+                "i" => command.id = number(),
+                "I" => command.number = number(),
+                "p" => command.placement = number(),
+                "m" => command.more = value == "1",
+                "x" => command.x = size(),
+                "y" => command.y = size(),
+                "w" => command.w = size(),
+                "h" => command.h = size(),
+                "X" => command.cell_x = size(),
+                "Y" => command.cell_y = size(),
+                "c" => command.cols = size(),
+                "r" => command.rows = size(),
+                "C" => command.keep_cursor = value == "1",
+                "U" => command.is_virtual = value == "1",
+                "z" => command.z = value.parse().unwrap_or(0),
+                "d" => command.delete = letter(),
+                _ => {}
+            }
+        }
+        command
+    }
+
+    /// Decodes the image the command carries, or points to. Errors are given as the error
+    /// code and message sent back to the application.
+    fn load(&self) -> Result<Image, String> {
+        let payload = base64::decode(&self.payload).ok_or("EINVAL:bad base64 data")?;
+        let mut data = match self.medium {
+            'd' => payload,
+            'f' | 't' | 's' => {
+                let name = String::from_utf8(payload).map_err(|_| "EINVAL:bad file name")?;
+                self.read_file(&name)?
+            }
+            _ => return Err("EINVAL:unknown transmission medium".to_string()),
+        };
+
+        if self.compressed {
+            let mut inflated = Vec::new();
+            ZlibDecoder::new(data.as_slice())
+                .take(config::IMAGE_MEMORY_LIMIT as u64)
+                .read_to_end(&mut inflated)
+                .map_err(|_| "EINVAL:bad zlib data")?;
+            data = inflated;
+        }
+
+        let image = match self.format {
+            24 => Image::from_bytes(self.width, self.height, 3, &data),
+            32 => Image::from_bytes(self.width, self.height, 4, &data),
+            100 => Image::from_png(&data),
+            _ => return Err("EINVAL:unknown format".to_string()),
+        };
+        image.ok_or_else(|| "ENODATA:image data is too short, too big or broken".to_string())
+    }
+
+    /// Reads image data from a file, a temporary file that is then deleted, or a POSIX shared
+    /// memory object that is then unlinked.
+    fn read_file(&self, name: &str) -> Result<Vec<u8>, String> {
+        let path = match self.medium {
+            's' => Path::new("/dev/shm").join(name.trim_start_matches('/')),
+            _ => PathBuf::from(name),
+        };
+        // Only ever read regular files, so that devices, pipes and the like can't be used to
+        // hang the terminal or read what they shouldn't.
+        let metadata = fs::metadata(&path).map_err(|_| "EBADF:no such file")?;
+        if !metadata.is_file()
+            || ["/proc", "/sys", "/dev/"].iter().any(|dir| {
+                path.starts_with(dir) && !(self.medium == 's' && path.starts_with("/dev/shm"))
+            })
+        {
+            return Err("EBADF:not a regular file".to_string());
+        }
+
+        let data = fs::read(&path).map_err(|_| "EBADF:could not read file")?;
+        match self.medium {
+            't' if path.starts_with(std::env::temp_dir())
+                && name.contains("tty-graphics-protocol") =>
+            {
+                _ = fs::remove_file(&path);
+            }
+            's' => _ = fs::remove_file(&path),
+            _ => {}
+        }
+
+        let start = self.offset.min(data.len());
+        let end = match self.size {
+            0 => data.len(),
+            size => (start + size).min(data.len()),
+        };
+        Ok(data[start..end].to_vec())
+    }
+}
+
+/// An image as it was transmitted.
+#[derive(Debug)]
+pub struct KittyImage {
+    pub number: u32,
+    pub image: Image,
+}
+
+/// An image put on the screen, shown through a scaled copy in `Term::images`.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub image_id: u32,
+    pub placement_id: u32,
+    /// Id of the scaled copy in `Term::images`.
+    pub image: u32,
+    /// Shown only where the application writes placeholders.
+    pub is_virtual: bool,
+    pub z: i32,
+}
+
+/// A placeholder cell written last, which the diacritics that follow it describe.
+#[derive(Debug, Clone, Copy)]
+struct PlaceholderCell {
+    x: usize,
+    y: usize,
+    /// Low 24 bits of the image id, from the foreground colour.
+    image_id: u32,
+    /// Row and column of the placeholder just left of this one, to carry on from.
+    left: Option<(usize, usize)>,
+    /// Row, column and top byte of the image id, as far as diacritics gave them.
+    diacritics: [Option<usize>; 3],
+}
+
+impl PlaceholderCell {
+    fn row(&self) -> usize {
+        self.diacritics[0]
+            .or(self.left.map(|(row, _)| row))
+            .unwrap_or(0)
+    }
+
+    fn col(&self) -> usize {
+        let row = self.row();
+        self.diacritics[1]
+            .or(self
+                .left
+                .filter(|&(left_row, _)| left_row == row)
+                .map(|(_, col)| col + 1))
+            .unwrap_or(0)
+    }
+
+    fn image_id(&self) -> u32 {
+        let high = self.diacritics[2].unwrap_or(0) as u32 & 0xff;
+        self.image_id | high << 24
+    }
+}
+
+#[derive(Debug)]
+pub struct KittyGraphics {
+    pub images: BTreeMap<u32, KittyImage>,
+    pub placements: Vec<Placement>,
+    /// A command whose payload is still arriving in chunks.
+    pending: Option<Command>,
+    /// Id given to the next image sent without one, counting down from the top so as not to
+    /// meet the ids applications pick.
+    next_id: u32,
+    placeholder: Option<PlaceholderCell>,
+}
+
+impl Default for KittyGraphics {
+    fn default() -> Self {
+        KittyGraphics {
+            images: BTreeMap::new(),
+            placements: Vec::new(),
+            pending: None,
+            next_id: u32::MAX,
+            placeholder: None,
+        }
+    }
+}
+
+impl KittyGraphics {
+    /// Id of the image a command refers to, by id or else the newest one with its number.
+    fn find(&self, command: &Command) -> Option<u32> {
+        if command.id != 0 {
+            return self.images.contains_key(&command.id).then_some(command.id);
+        }
+        if command.number != 0 {
+            return self
+                .images
+                .iter()
+                .rev()
+                .find(|(_, image)| image.number == command.number)
+                .map(|(&id, _)| id);
+        }
+        None
+    }
+
+    fn unused_id(&mut self) -> u32 {
+        while self.images.contains_key(&self.next_id) {
+            self.next_id -= 1;
+        }
+        let id = self.next_id;
+        self.next_id -= 1;
+        id
+    }
+
+    /// Drops the oldest images with nothing placed while they take more memory than allowed.
+    fn evict(&mut self) {
+        let mut size: usize = self.images.values().map(|i| i.image.memory_size()).sum();
+        let unplaced: Vec<u32> = self
+            .images
+            .keys()
+            .copied()
+            .filter(|&id| self.placements.iter().all(|p| p.image_id != id))
+            .collect();
+        for id in unplaced {
+            if size <= config::IMAGE_MEMORY_LIMIT {
+                break;
+            }
+            if let Some(image) = self.images.remove(&id) {
+                size -= image.image.memory_size();
+            }
+        }
+    }
+}
+
+impl Term {
+    /// Handles a graphics command, putting chunked payloads back together first.
+    pub fn kitty_graphics_command(&mut self, buf: &str) {
+        let command = Command::parse(buf);
+        let command = match self.kitty_graphics.pending.take() {
+            Some(mut pending) => {
+                pending.payload += &command.payload;
+                pending.more = command.more;
+                if buf.contains("q=") {
+                    pending.quiet = command.quiet;
+                }
+                pending
+            }
+            None => command,
+        };
+        if command.more {
+            if command.payload.len() < config::ESCAPE_STRING_LIMIT {
+                self.kitty_graphics.pending = Some(command);
+            }
+            return;
+        }
+
+        let result = match command.action {
+            't' | 'T' | 'q' => self.kitty_transmit(&command),
+            'p' => self.kitty_put(&command, command.id).map(|_| command.id),
+            'd' => {
+                self.kitty_delete(&command);
+                return;
+            }
+            _ => Err("EINVAL:unsupported action".to_string()),
+        };
+        self.kitty_reply(&command, result);
+    }
+
+    /// Replies to a command that gave an image id or number, with the id an image sent with
+    /// only a number was given.
+    fn kitty_reply(&mut self, command: &Command, result: Result<u32, String>) {
+        if command.id == 0 && command.number == 0 {
+            return;
+        }
+        let (id, message) = match result {
+            Ok(id) if command.quiet == 0 => (id, "OK".to_string()),
+            Err(error) if command.quiet < 2 => (command.id, error),
+            _ => return,
+        };
+        let mut keys = format!("i={}", id);
+        if command.number != 0 {
+            keys += &format!(",I={}", command.number);
+        }
+        if command.placement != 0 {
+            keys += &format!(",p={}", command.placement);
+        }
+        let reply = format!("\x1b_G{};{}\x1b\\", keys, message);
+        self.tty_write(reply.as_bytes(), false);
+    }
+
+    /// Stores the image a command sends, returning its id.
+    fn kitty_transmit(&mut self, command: &Command) -> Result<u32, String> {
+        let image = command.load()?;
+        if command.action == 'q' {
+            return Ok(command.id);
+        }
+
+        let graphics = &mut self.kitty_graphics;
+        let id = match (command.id, command.number) {
+            (0, _) => graphics.unused_id(),
+            (id, _) => id,
+        };
+        // Sending an image again under the same id replaces it, along with where it was shown.
+        if graphics.images.contains_key(&id) {
+            self.kitty_remove_placements(|placement| placement.image_id == id);
+        }
+        self.kitty_graphics.images.insert(
+            id,
+            KittyImage {
+                number: command.number,
+                image,
+            },
+        );
+        self.kitty_graphics.evict();
+
+        if command.action == 'T' {
+            self.kitty_put(command, id)?;
+        }
+        Ok(id)
+    }
+
+    /// Places image `id` at the cursor, or as a virtual placement for placeholders to show.
+    fn kitty_put(&mut self, command: &Command, id: u32) -> Result<(), String> {
+        let id = match id {
+            0 => self.kitty_graphics.find(command),
+            id => self.kitty_graphics.images.contains_key(&id).then_some(id),
+        }
+        .ok_or("ENOENT:no such image")?;
+
+        let source = &self.kitty_graphics.images[&id].image;
+        let image = source
+            .cropped(command.x, command.y, command.w, command.h)
+            .and_then(|cropped| self.kitty_scale(&cropped, command))
+            .ok_or("EINVAL:nothing of the image to show")?;
+
+        // Putting a placement again moves it.
+        if command.placement != 0 {
+            self.kitty_remove_placements(|placement| {
+                placement.image_id == id && placement.placement_id == command.placement
+            });
+        }
+        let image = self.insert_image(image);
+        self.kitty_graphics.placements.push(Placement {
+            image_id: id,
+            placement_id: command.placement,
+            image,
+            is_virtual: command.is_virtual,
+            z: command.z,
+        });
+
+        if !command.is_virtual {
+            let (x, y) = (self.cursor.x, self.cursor.y);
+            let last_row = self.place_image(image, x, y, true, command.z, false);
+            if !command.keep_cursor {
+                let (cols, _) = self.images[&image].size_in_cells();
+                self.move_to(x + cols, last_row);
+            }
+        }
+        self.collect_images();
+        Ok(())
+    }
+
+    /// Scales an image to the cells asked for, keeping its aspect ratio when only one of the
+    /// columns or rows is given. Virtual placements are fitted inside their cells instead.
+    fn kitty_scale(&self, image: &Image, command: &Command) -> Option<Image> {
+        let (cell_width, cell_height) = self.cell_size;
+        let (width, height) = (image.width, image.height);
+        // Boxes past the largest image are cut down to it, which also keeps the products below
+        // from overflowing as the image sizes are bounded the same way.
+        let (box_width, box_height) = (
+            command
+                .cols
+                .saturating_mul(cell_width)
+                .min(config::IMAGE_MAX_SIZE),
+            command
+                .rows
+                .saturating_mul(cell_height)
+                .min(config::IMAGE_MAX_SIZE),
+        );
+        let (scaled_width, scaled_height) = match (box_width, box_height) {
+            (0, 0) => (width, height),
+            (0, _) => (width * box_height / height, box_height),
+            (_, 0) => (box_width, height * box_width / width),
+            _ if command.is_virtual => {
+                if width * box_height > height * box_width {
+                    (box_width, height * box_width / width)
+                } else {
+                    (width * box_height / height, box_height)
+                }
+            }
+            _ => (box_width, box_height),
+        };
+
+        let offset = if command.is_virtual {
+            (
+                box_width.saturating_sub(scaled_width) / 2,
+                box_height.saturating_sub(scaled_height) / 2,
+            )
+        } else {
+            (
+                command.cell_x.min(cell_width.saturating_sub(1)),
+                command.cell_y.min(cell_height.saturating_sub(1)),
+            )
+        };
+        image.scaled(scaled_width, scaled_height, offset, (box_width, box_height))
+    }
+
+    /// Takes the placements matching `pred` off the screen and the history.
+    fn kitty_remove_placements(&mut self, pred: impl Fn(&Placement) -> bool) {
+        let mut removed = Vec::new();
+        self.kitty_graphics.placements.retain(|placement| {
+            let remove = pred(placement);
+            if remove {
+                removed.push(placement.image);
+            }
+            !remove
+        });
+        if removed.is_empty() {
+            return;
+        }
+
+        for (y, line) in self.lines.iter_mut().enumerate() {
+            let before = line.images.len();
+            line.images.retain(|tile| !removed.contains(&tile.image));
+            if line.images.len() != before {
+                self.dirty[y] = true;
+            }
+        }
+        for line in &mut self.alt_lines {
+            line.images.retain(|tile| !removed.contains(&tile.image));
+        }
+        for index in 0..self.history.len() {
+            if let Some(line) = self.history.get_mut(index) {
+                line.images.retain(|tile| !removed.contains(&tile.image));
+            }
+        }
+        self.set_dirty_all();
+    }
+
+    /// Deletes placements, and with an upper case `d` the images left with none.
+    fn kitty_delete(&mut self, command: &Command) {
+        // Placements with a tile on the screen for which `on_cell` holds.
+        let on_screen = |term: &Term, on_cell: &dyn Fn(usize, usize, &ImageCell) -> bool| {
+            let mut images = Vec::new();
+            for (y, line) in term.lines.iter().enumerate() {
+                for tile in &line.images {
+                    if on_cell(tile.col, y, tile) {
+                        images.push(tile.image);
+                    }
+                }
+            }
+            images
+        };
+        let (x, y) = (command.x.saturating_sub(1), command.y.saturating_sub(1));
+        let (cursor_x, cursor_y) = (self.cursor.x, self.cursor.y);
+
+        let shown = match command.delete.to_ascii_lowercase() {
+            'a' => Some(on_screen(self, &|_, _, _| true)),
+            'c' => Some(on_screen(self, &|col, row, _| {
+                col == cursor_x && row == cursor_y
+            })),
+            'p' => Some(on_screen(self, &|col, row, _| col == x && row == y)),
+            'q' => Some(on_screen(self, &|col, row, tile| {
+                col == x && row == y && tile.z == command.z
+            })),
+            'x' => Some(on_screen(self, &|col, _, _| col == x)),
+            'y' => Some(on_screen(self, &|_, row, _| row == y)),
+            _ => None,
+        };
+
+        let target = self.kitty_graphics.find(command);
+        let (first, last) = (command.x as u32, command.y as u32);
+        let matches = |placement: &Placement| match command.delete.to_ascii_lowercase() {
+            'i' | 'n' => {
+                Some(placement.image_id) == target
+                    && (command.placement == 0 || placement.placement_id == command.placement)
+            }
+            'z' => placement.z == command.z,
+            'r' => (first..=last).contains(&placement.image_id),
+            _ => shown
+                .as_ref()
+                .is_some_and(|shown| shown.contains(&placement.image)),
+        };
+        let image_ids: Vec<u32> = self
+            .kitty_graphics
+            .placements
+            .iter()
+            .filter(|placement| matches(placement))
+            .map(|placement| placement.image_id)
+            .collect();
+        self.kitty_remove_placements(matches);
+
+        if command.delete.is_ascii_uppercase() {
+            let graphics = &mut self.kitty_graphics;
+            let mut freed = image_ids;
+            match command.delete {
+                'I' | 'N' => freed.extend(target),
+                'R' => freed.extend(graphics.images.range(first..=last).map(|(&id, _)| id)),
+                _ => {}
+            }
+            for id in freed {
+                if graphics.placements.iter().all(|p| p.image_id != id) {
+                    graphics.images.remove(&id);
+                }
+            }
+        }
+        self.collect_images();
+    }
+
+    /// Shows the tile of a virtual placement for a placeholder just written at `x`, `y`. Its
+    /// row and column follow on from a placeholder to the left until diacritics give them.
+    pub fn image_placeholder(&mut self, x: usize, y: usize) {
+        let foreground = self.cursor.attr.foreground;
+        let image_id = if is_true_color(foreground) {
+            foreground & 0xff_ffff
+        } else if foreground < 256 {
+            foreground
+        } else {
+            0
+        };
+        let left = self
+            .kitty_graphics
+            .placeholder
+            .filter(|last| last.y == y && last.x + 1 == x && last.image_id == image_id)
+            .map(|last| (last.row(), last.col()));
+        self.kitty_graphics.placeholder = Some(PlaceholderCell {
+            x,
+            y,
+            image_id,
+            left,
+            diacritics: [None; 3],
+        });
+        self.show_placeholder();
+    }
+
+    /// Takes a diacritic following a placeholder as its row, column and the top byte of its
+    /// image id, in that order. Returns whether the character was used up this way.
+    pub fn placeholder_diacritic(&mut self, c: char) -> bool {
+        let Some(mut cell) = self.kitty_graphics.placeholder else {
+            return false;
+        };
+        let follows = cell.y == self.cursor.y
+            && (cell.x + 1 == self.cursor.x || (cell.x == self.cursor.x && self.cursor.wrap_next));
+        let Some(value) = diacritic_value(c).filter(|_| follows) else {
+            return false;
+        };
+        if self.lines[cell.y].glyphs[cell.x].character != PLACEHOLDER {
+            return false;
+        }
+        if let Some(slot) = cell.diacritics.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(value);
+        }
+        self.kitty_graphics.placeholder = Some(cell);
+        self.show_placeholder();
+        true
+    }
+
+    fn show_placeholder(&mut self) {
+        let Some(cell) = self.kitty_graphics.placeholder else {
+            return;
+        };
+        let placement = self
+            .kitty_graphics
+            .placements
+            .iter()
+            .find(|placement| placement.is_virtual && placement.image_id == cell.image_id())
+            .copied();
+        let line = &mut self.lines[cell.y];
+        line.images.retain(|tile| tile.col != cell.x);
+        if let Some(placement) = placement {
+            line.images.push(ImageCell {
+                col: cell.x,
+                image: placement.image,
+                x: cell.col(),
+                y: cell.row(),
+                z: placement.z,
+            });
+        }
+        self.set_dirty(cell.y, cell.y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xffff_0000;
+
+    /// Sends a graphics command, with the payload as it is.
+    fn send(term: &mut Term, keys: &str, payload: &str) {
+        term.write(format!("\x1b_G{};{}\x1b\\", keys, payload).as_bytes());
+    }
+
+    /// Payload of a red RGB image of `width` by `height` pixels.
+    fn red(width: usize, height: usize) -> String {
+        base64::encode(&[0xff, 0, 0].repeat(width * height))
+    }
+
+    fn reply(term: &mut Term) -> String {
+        String::from_utf8(std::mem::take(&mut term.tty_buffer)).unwrap()
+    }
+
+    /// Images shown on each row of the screen, as the column of their first tile.
+    fn shown(term: &Term) -> Vec<Vec<(usize, u32)>> {
+        term.lines
+            .iter()
+            .map(|line| {
+                let mut images: Vec<(usize, u32)> = Vec::new();
+                for tile in &line.images {
+                    let image_id = term
+                        .kitty_graphics
+                        .placements
+                        .iter()
+                        .find(|placement| placement.image == tile.image)
+                        .map_or(0, |placement| placement.image_id);
+                    if !images.iter().any(|&(_, id)| id == image_id) {
+                        images.push((tile.col, image_id));
+                    }
+                }
+                images
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_keys() {
+        let command = Command::parse("a=T,f=100,i=7,p=2,c=3,r=4,z=-5,C=1,U=1,m=1;abcd");
+        assert_eq!(command.action, 'T');
+        assert_eq!(command.format, 100);
+        assert_eq!((command.id, command.placement), (7, 2));
+        assert_eq!((command.cols, command.rows), (3, 4));
+        assert_eq!(command.z, -5);
+        assert!(command.keep_cursor && command.is_virtual && command.more);
+        assert_eq!(command.payload, "abcd");
+
+        let command = Command::parse("i=x,bad,s=");
+        assert_eq!((command.action, command.id, command.width), ('t', 0, 0));
+    }
+
+    #[test]
+    fn puts_chunks_back_together() {
+        let mut term = Term::new(10, 5);
+        let payload = red(2, 2);
+        let (first, rest) = payload.split_at(5);
+        let (second, last) = rest.split_at(6);
+        send(&mut term, "a=t,f=24,s=2,v=2,i=1,m=1", first);
+        send(&mut term, "m=1", second);
+        assert!(term.kitty_graphics.images.is_empty());
+        send(&mut term, "m=0", last);
+
+        let image = &term.kitty_graphics.images[&1].image;
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.pixels, [RED; 4]);
+        assert_eq!(reply(&mut term), "\x1b_Gi=1;OK\x1b\\");
+        assert!(term.kitty_graphics.placements.is_empty());
+    }
+
+    #[test]
+    fn replies_with_errors() {
+        let mut term = Term::new(10, 5);
+        send(&mut term, "a=t,f=24,s=2,v=2,i=1", &red(1, 1));
+        assert!(reply(&mut term).starts_with("\x1b_Gi=1;ENODATA:"));
+        send(&mut term, "a=p,i=2", "");
+        assert_eq!(reply(&mut term), "\x1b_Gi=2;ENOENT:no such image\x1b\\");
+        send(&mut term, "a=p,i=2,q=2", "");
+        assert_eq!(reply(&mut term), "");
+        // Commands without an id or number get no reply.
+        send(&mut term, "a=t,f=24,s=1,v=1", &red(1, 1));
+        assert_eq!(reply(&mut term), "");
+        assert_eq!(term.kitty_graphics.images.len(), 1);
+    }
+
+    #[test]
+    fn transmits_and_displays_at_the_cursor() {
+        let mut term = Term::new(10, 5);
+        term.write(b"\x1b[2;3H");
+        send(&mut term, "a=T,f=24,s=20,v=40,i=1", &red(20, 40));
+
+        assert_eq!(term.kitty_graphics.placements.len(), 1);
+        let tiles: Vec<(usize, usize, usize)> = term.lines[2]
+            .images
+            .iter()
+            .map(|tile| (tile.col, tile.x, tile.y))
+            .collect();
+        assert_eq!(tiles, [(2, 0, 1), (3, 1, 1)]);
+        assert_eq!(shown(&term)[1], [(2, 1)]);
+        assert_eq!((term.cursor.x, term.cursor.y), (4, 2));
+
+        // The image is scaled to the cells asked for, leaving the cursor with `C=1`.
+        term.write(b"\x1b[H");
+        send(&mut term, "a=p,i=1,c=3,r=1,C=1", "");
+        let placement = term.kitty_graphics.placements[1];
+        let image = &term.images[&placement.image];
+        assert_eq!((image.width, image.height), (30, 20));
+        assert_eq!(term.lines[0].images.len(), 3);
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+    }
+
+    #[test]
+    fn putting_a_placement_again_moves_it() {
+        let mut term = Term::new(10, 5);
+        send(&mut term, "a=t,f=24,s=10,v=20,i=1", &red(10, 20));
+        term.write(b"\x1b[3;4H");
+        send(&mut term, "a=p,i=1,p=7", "");
+        assert_eq!(shown(&term)[2], [(3, 1)]);
+
+        term.write(b"\x1b[H");
+        send(&mut term, "a=p,i=1,p=7", "");
+        assert_eq!(term.kitty_graphics.placements.len(), 1);
+        assert_eq!(shown(&term)[0], [(0, 1)]);
+        assert!(shown(&term)[2].is_empty());
+        // The scaled copy of the old placement is dropped.
+        assert_eq!(term.images.len(), 1);
+
+        // Another placement id adds a placement.
+        send(&mut term, "a=p,i=1,p=8", "");
+        assert_eq!(term.kitty_graphics.placements.len(), 2);
+    }
+
+    #[test]
+    fn clamps_huge_cell_counts() {
+        let mut term = Term::new(10, 5);
+        send(&mut term, "a=t,f=24,s=1,v=1,i=1", &red(1, 1));
+        send(&mut term, &format!("a=p,i=1,c={},r=1", usize::MAX), "");
+        let placement = term.kitty_graphics.placements[0];
+        let image = &term.images[&placement.image];
+        assert_eq!((image.width, image.height), (config::IMAGE_MAX_SIZE, 20));
+
+        send(
+            &mut term,
+            &format!("a=p,i=1,p=1,c=1,r={},U=1", usize::MAX),
+            "",
+        );
+        let placement = term.kitty_graphics.placements[1];
+        let image = &term.images[&placement.image];
+        assert_eq!((image.width, image.height), (10, config::IMAGE_MAX_SIZE));
+    }
+
+    /// A terminal showing image 1 at the top left, image 2 at column 5 of row 2 with a z-index
+    /// of -1, and image 3 with number 9 nowhere.
+    fn three_images() -> Term {
+        let mut term = Term::new(10, 5);
+        send(&mut term, "a=T,f=24,s=10,v=20,i=1", &red(10, 20));
+        term.write(b"\x1b[3;6H");
+        send(&mut term, "a=T,f=24,s=10,v=20,i=2,z=-1", &red(10, 20));
+        send(&mut term, "a=t,f=24,s=1,v=1,i=3,I=9", &red(1, 1));
+        term.write(b"\x1b[H");
+        term.tty_buffer.clear();
+        term
+    }
+
+    fn placed_ids(term: &Term) -> Vec<u32> {
+        let mut ids: Vec<u32> = term
+            .kitty_graphics
+            .placements
+            .iter()
+            .map(|placement| placement.image_id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn image_ids(term: &Term) -> Vec<u32> {
+        term.kitty_graphics.images.keys().copied().collect()
+    }
+
+    #[test]
+    fn deletes_by_each_selector() {
+        for (keys, placed, images) in [
+            ("d=a", vec![], vec![1, 2, 3]),
+            ("d=A", vec![], vec![3]),
+            ("d=i,i=1", vec![2], vec![1, 2, 3]),
+            ("d=I,i=1", vec![2], vec![2, 3]),
+            ("d=I,i=3", vec![1, 2], vec![1, 2]),
+            ("d=n,I=9", vec![1, 2], vec![1, 2, 3]),
+            ("d=N,I=9", vec![1, 2], vec![1, 2]),
+            ("d=c", vec![2], vec![1, 2, 3]),
+            ("d=C", vec![2], vec![2, 3]),
+            ("d=p,x=6,y=3", vec![1], vec![1, 2, 3]),
+            ("d=P,x=6,y=3", vec![1], vec![1, 3]),
+            ("d=q,x=6,y=3,z=-1", vec![1], vec![1, 2, 3]),
+            ("d=q,x=6,y=3,z=0", vec![1, 2], vec![1, 2, 3]),
+            ("d=Q,x=6,y=3,z=-1", vec![1], vec![1, 3]),
+            ("d=x,x=1", vec![2], vec![1, 2, 3]),
+            ("d=X,x=1", vec![2], vec![2, 3]),
+            ("d=y,y=3", vec![1], vec![1, 2, 3]),
+            ("d=Y,y=3", vec![1], vec![1, 3]),
+            ("d=z,z=-1", vec![1], vec![1, 2, 3]),
+            ("d=Z,z=-1", vec![1], vec![1, 3]),
+            ("d=r,x=2,y=3", vec![1], vec![1, 2, 3]),
+            ("d=R,x=2,y=3", vec![1], vec![1]),
+        ] {
+            let mut term = three_images();
+            send(&mut term, &format!("a=d,{}", keys), "");
+            assert_eq!(placed_ids(&term), placed, "{}", keys);
+            assert_eq!(image_ids(&term), images, "{}", keys);
+            assert_eq!(shown(&term)[2].is_empty(), !placed.contains(&2), "{}", keys);
+            assert_eq!(reply(&mut term), "", "{}", keys);
+        }
+    }
+
+    #[test]
+    fn shows_virtual_placements_through_placeholders() {
+        let mut term = Term::new(10, 5);
+        send(&mut term, "a=t,f=24,s=20,v=40,i=5", &red(20, 40));
+        send(&mut term, "a=p,U=1,i=5,c=2,r=2", "");
+        assert!(term.lines.iter().all(|line| line.images.is_empty()));
+
+        // The first cell gives its row and column, and the next one on the row carries on.
+        term.write("\x1b[38;5;5m\u{10EEEE}\u{0305}\u{0305}\u{10EEEE}\r\n".as_bytes());
+        // Only the row is given here, and the column starts from zero.
+        term.write("\u{10EEEE}\u{030D}\u{10EEEE}\u{030D}\u{030D}".as_bytes());
+
+        let tiles = |y: usize| -> Vec<(usize, usize, usize)> {
+            term.lines[y]
+                .images
+                .iter()
+                .map(|tile| (tile.col, tile.x, tile.y))
+                .collect()
+        };
+        assert_eq!(tiles(0), [(0, 0, 0), (1, 1, 0)]);
+        assert_eq!(tiles(1), [(0, 0, 1), (1, 1, 1)]);
+        assert_eq!(term.lines[1].glyphs[1].character, PLACEHOLDER);
+        assert_eq!(term.cursor.x, 2);
+
+        // Placeholders for an image with no virtual placement show nothing.
+        term.write("\x1b[38;5;6m\u{10EEEE}".as_bytes());
+        assert!(term.lines[1].images.iter().all(|tile| tile.col != 2));
+    }
+}
@@ -1,3 +1,14 @@
 #![warn(clippy::undocumented_unsafe_blocks)]
 
+pub mod base64;
+pub mod config;
+pub mod graphics;
+pub mod iterm_graphics;
+pub mod keyboard;
+pub mod kitty_graphics;
+pub mod mouse;
+pub mod printer;
+pub mod search;
+pub mod sixel;
+pub mod st;
 pub mod x;
@@ -0,0 +1,297 @@
+// Encoding of mouse events for the child, in the tracking modes and encodings xterm offers.
+//
+// See https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Mouse-Tracking for the details
+// of each.
+
+use crate::{keyboard::KeyModifiers, st::WindowMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEventKind {
+    Press,
+    Release,
+    Motion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub kind: MouseEventKind,
+    /// Button pressed or released, from 1 to 11. For motion it is the lowest numbered button
+    /// held down, or zero for none.
+    pub button: u32,
+    /// Only Shift, Alt and Control are reported.
+    pub modifiers: KeyModifiers,
+    /// Zero based column and row, or pixel within the terminal area for SGR pixel reports
+    /// (mode 1016).
+    pub position: (u32, u32),
+}
+
+/// Encodes a mouse event in the tracking mode and encoding of `mode`, or `None` when the event
+/// isn't reported or doesn't fit the encoding.
+pub fn encode(mode: WindowMode, event: &MouseEvent) -> Option<Vec<u8>> {
+    let motion = event.kind == MouseEventKind::Motion;
+    let release = event.kind == MouseEventKind::Release;
+    let button = event.button;
+
+    if !mode.intersects(WindowMode::MOUSE) {
+        return None;
+    }
+    if motion {
+        // Button-event tracking only reports motion with a button held, any-event tracking
+        // reports all of it.
+        if !mode.intersects(WindowMode::MOUSEMOTION | WindowMode::MOUSEMANY)
+            || (mode.contains(WindowMode::MOUSEMOTION) && button == 0)
+        {
+            return None;
+        }
+    } else if !(1..=11).contains(&button)
+        // X10 mode reports presses only, and wheels never report releases.
+        || (release && (mode.contains(WindowMode::MOUSEX10) || (4..=7).contains(&button)))
+    {
+        return None;
+    }
+
+    let sgr = mode.intersects(WindowMode::MOUSESGR | WindowMode::MOUSESGRPIXELS);
+    let mut code = if motion { 32 } else { 0 };
+    code += match button {
+        // Motion with no button held encodes as a release.
+        0 => 3,
+        _ if release && !sgr => 3,
+        8.. => 128 + button - 8,
+        4.. => 64 + button - 4,
+        _ => button - 1,
+    };
+    if !mode.contains(WindowMode::MOUSEX10) {
+        for (modifier, bit) in [
+            (KeyModifiers::SHIFT, 4),
+            (KeyModifiers::ALT, 8),
+            (KeyModifiers::CONTROL, 16),
+        ] {
+            if event.modifiers.contains(modifier) {
+                code += bit;
+            }
+        }
+    }
+
+    let (x, y) = (event.position.0 + 1, event.position.1 + 1);
+    let report = if sgr {
+        let last = if release { 'm' } else { 'M' };
+        format!("\x1b[<{};{};{}{}", code, x, y, last).into_bytes()
+    } else if mode.contains(WindowMode::MOUSEURXVT) {
+        format!("\x1b[{};{};{}M", 32 + code, x, y).into_bytes()
+    } else if mode.contains(WindowMode::MOUSEUTF8) {
+        // Each value is sent as a UTF-8 character, which fits 2015 columns and rows in the two
+        // bytes xterm allows.
+        let mut report = b"\x1b[M".to_vec();
+        for value in [32 + code, 32 + x, 32 + y] {
+            let c = char::from_u32(value).filter(|c| c.len_utf8() <= 2)?;
+            report.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        report
+    } else {
+        // A byte each, which only fits 223 columns and rows.
+        if x > 223 || y > 223 {
+            return None;
+        }
+        let mut report = b"\x1b[M".to_vec();
+        report.extend([code, x, y].map(|value| (32 + value) as u8));
+        report
+    };
+    Some(report)
+}
+
+/// Keys the wheel sends on the alternate screen with alternate scroll (mode 1007), `lines` Up
+/// or Down keys for each step of button 4 or 5. `None` when the wheel isn't turned into keys.
+pub fn alternate_scroll_keys(
+    mode: WindowMode,
+    altscreen: bool,
+    button: u32,
+    lines: usize,
+) -> Option<Vec<u8>> {
+    if !mode.contains(WindowMode::ALTSCROLL) || !altscreen {
+        return None;
+    }
+    let arrow = match button {
+        4 => b'A',
+        5 => b'B',
+        _ => return None,
+    };
+    let prefix: &[u8] = if mode.contains(WindowMode::VT52) {
+        b"\x1b"
+    } else if mode.contains(WindowMode::APPCURSOR) {
+        b"\x1bO"
+    } else {
+        b"\x1b["
+    };
+    Some([prefix, &[arrow]].concat().repeat(lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: MouseEventKind, button: u32, position: (u32, u32)) -> MouseEvent {
+        MouseEvent {
+            kind,
+            button,
+            modifiers: KeyModifiers::empty(),
+            position,
+        }
+    }
+
+    fn press(button: u32) -> MouseEvent {
+        event(MouseEventKind::Press, button, (0, 0))
+    }
+
+    fn release(button: u32) -> MouseEvent {
+        event(MouseEventKind::Release, button, (0, 0))
+    }
+
+    fn motion(button: u32) -> MouseEvent {
+        event(MouseEventKind::Motion, button, (1, 2))
+    }
+
+    #[test]
+    fn reports_nothing_without_a_tracking_mode() {
+        assert_eq!(encode(WindowMode::empty(), &press(1)), None);
+        assert_eq!(encode(WindowMode::MOUSESGR, &press(1)), None);
+    }
+
+    #[test]
+    fn reports_presses_only_in_x10_mode() {
+        let mode = WindowMode::MOUSEX10;
+        let mut shifted = press(3);
+        shifted.modifiers = KeyModifiers::SHIFT | KeyModifiers::CONTROL;
+        assert_eq!(encode(mode, &press(1)).unwrap(), b"\x1b[M !!");
+        assert_eq!(encode(mode, &shifted).unwrap(), b"\x1b[M\"!!");
+        assert_eq!(encode(mode, &release(1)), None);
+        assert_eq!(encode(mode, &motion(1)), None);
+    }
+
+    #[test]
+    fn reports_presses_and_releases_in_normal_tracking() {
+        let mode = WindowMode::MOUSEBTN;
+        let mut modified = event(MouseEventKind::Press, 2, (4, 9));
+        modified.modifiers = KeyModifiers::SHIFT | KeyModifiers::ALT | KeyModifiers::CONTROL;
+        assert_eq!(encode(mode, &press(1)).unwrap(), b"\x1b[M !!");
+        assert_eq!(encode(mode, &modified).unwrap(), b"\x1b[M=%*");
+        // Releases don't say which button.
+        assert_eq!(encode(mode, &release(2)).unwrap(), b"\x1b[M#!!");
+        assert_eq!(encode(mode, &motion(1)), None);
+    }
+
+    #[test]
+    fn reports_motion_with_a_button_in_button_event_tracking() {
+        let mode = WindowMode::MOUSEMOTION;
+        assert_eq!(encode(mode, &motion(1)).unwrap(), b"\x1b[M@\"#");
+        assert_eq!(encode(mode, &motion(3)).unwrap(), b"\x1b[MB\"#");
+        assert_eq!(encode(mode, &motion(0)), None);
+    }
+
+    #[test]
+    fn reports_all_motion_in_any_event_tracking() {
+        let mode = WindowMode::MOUSEMANY;
+        assert_eq!(encode(mode, &motion(1)).unwrap(), b"\x1b[M@\"#");
+        assert_eq!(encode(mode, &motion(0)).unwrap(), b"\x1b[MC\"#");
+    }
+
+    #[test]
+    fn numbers_wheel_and_extra_buttons() {
+        let mode = WindowMode::MOUSEBTN | WindowMode::MOUSESGR;
+        let codes: Vec<String> = (4..=11)
+            .map(|button| String::from_utf8(encode(mode, &press(button)).unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "\x1b[<64;1;1M",
+                "\x1b[<65;1;1M",
+                "\x1b[<66;1;1M",
+                "\x1b[<67;1;1M",
+                "\x1b[<128;1;1M",
+                "\x1b[<129;1;1M",
+                "\x1b[<130;1;1M",
+                "\x1b[<131;1;1M",
+            ]
+        );
+        // Wheels don't release, the extra buttons do.
+        for button in 4..=7 {
+            assert_eq!(encode(mode, &release(button)), None);
+        }
+        assert_eq!(encode(mode, &release(8)).unwrap(), b"\x1b[<128;1;1m");
+        assert_eq!(encode(mode, &press(12)), None);
+        assert_eq!(encode(mode, &press(0)), None);
+    }
+
+    #[test]
+    fn encodes_sgr_reports() {
+        let mode = WindowMode::MOUSEMANY | WindowMode::MOUSESGR;
+        let far = event(MouseEventKind::Press, 1, (299, 499));
+        assert_eq!(encode(mode, &far).unwrap(), b"\x1b[<0;300;500M");
+        assert_eq!(encode(mode, &release(3)).unwrap(), b"\x1b[<2;1;1m");
+        assert_eq!(encode(mode, &motion(0)).unwrap(), b"\x1b[<35;2;3M");
+
+        // In pixels, which only changes how the position is read.
+        let mode = WindowMode::MOUSEBTN | WindowMode::MOUSESGRPIXELS;
+        let pixel = event(MouseEventKind::Release, 1, (25, 47));
+        assert_eq!(encode(mode, &pixel).unwrap(), b"\x1b[<0;26;48m");
+    }
+
+    #[test]
+    fn encodes_urxvt_reports() {
+        let mode = WindowMode::MOUSEBTN | WindowMode::MOUSEURXVT;
+        let far = event(MouseEventKind::Press, 1, (299, 499));
+        assert_eq!(encode(mode, &far).unwrap(), b"\x1b[32;300;500M");
+        assert_eq!(encode(mode, &release(1)).unwrap(), b"\x1b[35;1;1M");
+    }
+
+    #[test]
+    fn encodes_utf8_reports() {
+        let mode = WindowMode::MOUSEBTN | WindowMode::MOUSEUTF8;
+        assert_eq!(encode(mode, &press(1)).unwrap(), b"\x1b[M !!");
+        let far = event(MouseEventKind::Press, 1, (299, 0));
+        assert_eq!(encode(mode, &far).unwrap(), "\x1b[M \u{14c}!".as_bytes());
+        let too_far = event(MouseEventKind::Press, 1, (2015, 0));
+        assert_eq!(encode(mode, &too_far), None);
+    }
+
+    #[test]
+    fn limits_default_reports_to_223_cells() {
+        let mode = WindowMode::MOUSEBTN;
+        let last = event(MouseEventKind::Press, 1, (222, 222));
+        assert_eq!(encode(mode, &last).unwrap(), b"\x1b[M \xff\xff");
+        let past = event(MouseEventKind::Press, 1, (223, 0));
+        assert_eq!(encode(mode, &past), None);
+        let past = event(MouseEventKind::Press, 1, (0, 223));
+        assert_eq!(encode(mode, &past), None);
+    }
+
+    #[test]
+    fn scrolls_the_alternate_screen_with_cursor_keys() {
+        let mode = WindowMode::ALTSCROLL;
+        assert_eq!(alternate_scroll_keys(mode, true, 4, 1).unwrap(), b"\x1b[A");
+        assert_eq!(
+            alternate_scroll_keys(mode, true, 5, 3).unwrap(),
+            b"\x1b[B\x1b[B\x1b[B"
+        );
+
+        let mode = WindowMode::ALTSCROLL | WindowMode::APPCURSOR;
+        assert_eq!(
+            alternate_scroll_keys(mode, true, 4, 2).unwrap(),
+            b"\x1bOA\x1bOA"
+        );
+        let mode = WindowMode::ALTSCROLL | WindowMode::APPCURSOR | WindowMode::VT52;
+        assert_eq!(
+            alternate_scroll_keys(mode, true, 5, 2).unwrap(),
+            b"\x1bB\x1bB"
+        );
+    }
+
+    #[test]
+    fn leaves_the_wheel_alone_without_alternate_scroll() {
+        let mode = WindowMode::ALTSCROLL;
+        assert_eq!(alternate_scroll_keys(mode, false, 4, 3), None);
+        assert_eq!(alternate_scroll_keys(mode, true, 6, 3), None);
+        assert_eq!(alternate_scroll_keys(mode, true, 1, 3), None);
+        assert_eq!(alternate_scroll_keys(WindowMode::empty(), true, 4, 3), None);
+    }
+}
@@ -0,0 +1,110 @@
+// Media copy (MC), sending text to the printer, which is whatever command the printer was made
+// with, `config::PRINTER` for a terminal.
+//
+// A print job starts the command with the text on its standard input and ends by closing it,
+// so that `lpr` prints each job on its own. Printing the screen or a line is a job of its own,
+// unless printer controller mode or auto print is on, which keep a job open until turned off.
+
+use std::{
+    io::Write,
+    process::{Child, Command, Stdio},
+};
+
+/// Ends printer controller mode, everything before it goes to the printer.
+const CONTROLLER_END: &[u8] = b"\x1b[4i";
+
+#[derive(Debug)]
+pub struct Printer {
+    /// Program and arguments run for each print job.
+    command: Vec<String>,
+    job: Option<Child>,
+    /// How much of `CONTROLLER_END` the last characters in controller mode matched.
+    controller_matched: usize,
+}
+
+impl Printer {
+    pub fn new(command: &[&str]) -> Self {
+        Printer {
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            job: None,
+            controller_matched: 0,
+        }
+    }
+
+    /// Sends bytes to the printer, starting a job if none is running.
+    pub fn write(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if self.job.is_none() {
+            self.job = self.start_job();
+        }
+        let Some(stdin) = self.job.as_mut().and_then(|job| job.stdin.as_mut()) else {
+            return;
+        };
+        if let Err(error) = stdin.write_all(bytes) {
+            eprintln!("tt: could not print: {}", error);
+            self.finish();
+        }
+    }
+
+    /// Ends the print job, if one is running.
+    pub fn finish(&mut self) {
+        if let Some(mut job) = self.job.take() {
+            // Closing its input lets the command finish, reap it without waiting here.
+            drop(job.stdin.take());
+            std::thread::spawn(move || job.wait());
+        }
+    }
+
+    /// Passes a character received in printer controller mode on to the printer, unless it ends
+    /// the mode. Returns whether the mode ended, after which the job is finished.
+    pub fn controller_char(&mut self, c: char, utf8: bool) -> bool {
+        let mut buf = [0; 4];
+        let bytes = if utf8 {
+            c.encode_utf8(&mut buf).as_bytes()
+        } else {
+            buf[0] = c as u8;
+            &buf[..1]
+        };
+
+        if bytes.len() == 1 && bytes[0] == CONTROLLER_END[self.controller_matched] {
+            self.controller_matched += 1;
+            if self.controller_matched == CONTROLLER_END.len() {
+                self.controller_matched = 0;
+                return true;
+            }
+            return false;
+        }
+        // The start of what looked like the end of the mode was just text after all.
+        let matched = std::mem::take(&mut self.controller_matched);
+        self.write(&CONTROLLER_END[..matched]);
+        if bytes[0] == CONTROLLER_END[0] {
+            self.controller_matched = 1;
+        } else {
+            self.write(bytes);
+        }
+        false
+    }
+
+    fn start_job(&self) -> Option<Child> {
+        let (program, args) = self.command.split_first()?;
+        match Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => Some(child),
+            Err(error) => {
+                eprintln!("tt: could not run {:?}: {}", program, error);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
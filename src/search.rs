@@ -0,0 +1,376 @@
+// Incremental search through the scrollback and the screen.
+
+use regex::{Regex, RegexBuilder};
+
+use crate::st::{GlyphAttribute, Term};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchMode {
+    #[default]
+    Plain,
+    CaseInsensitive,
+    Regex,
+}
+
+impl SearchMode {
+    /// The mode after this one, used to cycle through them while searching.
+    pub fn next(self) -> Self {
+        match self {
+            SearchMode::Plain => SearchMode::CaseInsensitive,
+            SearchMode::CaseInsensitive => SearchMode::Regex,
+            SearchMode::Regex => SearchMode::Plain,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SearchMode::Plain => "Search",
+            SearchMode::CaseInsensitive => "Search (ignore case)",
+            SearchMode::Regex => "Search (regex)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchDirection {
+    Forward,
+    #[default]
+    Backward,
+}
+
+/// A cell, addressed by line number as given by `Term::screen_line_number` and column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Point {
+    pub line: u64,
+    pub col: usize,
+}
+
+/// The cells covered by a match, both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub start: Point,
+    pub end: Point,
+}
+
+pub struct Search {
+    pub query: String,
+    pub mode: SearchMode,
+    pub direction: SearchDirection,
+    /// Every match in the history and screen, in order.
+    pub matches: Vec<Match>,
+    /// Index into `matches` of the match the view follows.
+    pub current: Option<usize>,
+    /// Why the query could not be used, eg: a regex that doesn't compile.
+    pub error: Option<String>,
+    /// Where the search started from, so that editing the query searches from the same place.
+    origin: Point,
+}
+
+/// A soft-wrapped run of rows joined into one string, with the cell each character came from.
+struct LogicalLine {
+    text: String,
+    /// Byte offset of each character of `text`.
+    offsets: Vec<usize>,
+    points: Vec<Point>,
+}
+
+impl LogicalLine {
+    /// Cell of the character starting at a byte offset, or of the last character before it.
+    fn point_at(&self, byte: usize) -> Point {
+        let index = match self.offsets.binary_search(&byte) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        };
+        self.points[index]
+    }
+}
+
+impl Search {
+    pub fn new(term: &Term, direction: SearchDirection) -> Self {
+        let origin = match direction {
+            SearchDirection::Forward => Point {
+                line: term.view_line_number(0),
+                col: 0,
+            },
+            SearchDirection::Backward => Point {
+                line: term.view_line_number(term.rows - 1),
+                col: term.cols - 1,
+            },
+        };
+        Search {
+            query: String::new(),
+            mode: SearchMode::default(),
+            direction,
+            matches: Vec::new(),
+            current: None,
+            error: None,
+            origin,
+        }
+    }
+
+    fn regex(&self) -> Result<Regex, regex::Error> {
+        let pattern = match self.mode {
+            SearchMode::Plain | SearchMode::CaseInsensitive => regex::escape(&self.query),
+            SearchMode::Regex => self.query.clone(),
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.mode == SearchMode::CaseInsensitive)
+            .build()
+    }
+
+    /// Finds every match of the query again, picking the one nearest the origin in the search
+    /// direction as the current match.
+    pub fn update(&mut self, term: &Term) {
+        self.matches.clear();
+        self.current = None;
+        self.error = None;
+        if self.query.is_empty() {
+            return;
+        }
+
+        let regex = match self.regex() {
+            Ok(regex) => regex,
+            Err(error) => {
+                self.error = Some(error.to_string());
+                return;
+            }
+        };
+
+        for line in logical_lines(term) {
+            for found in regex.find_iter(&line.text) {
+                if found.is_empty() {
+                    continue;
+                }
+                self.matches.push(Match {
+                    start: line.point_at(found.start()),
+                    end: line.point_at(found.end() - 1),
+                });
+            }
+        }
+
+        self.current = match self.direction {
+            SearchDirection::Forward => self
+                .matches
+                .iter()
+                .position(|m| m.start >= self.origin)
+                .or((!self.matches.is_empty()).then_some(0)),
+            SearchDirection::Backward => self
+                .matches
+                .iter()
+                .rposition(|m| m.start <= self.origin)
+                .or(self.matches.len().checked_sub(1)),
+        };
+    }
+
+    /// Moves to the next match in the search direction, or against it if `reverse` is set,
+    /// wrapping around at either end.
+    pub fn step(&mut self, reverse: bool) {
+        let Some(current) = self.current else {
+            return;
+        };
+        let len = self.matches.len();
+        let forward = (self.direction == SearchDirection::Forward) != reverse;
+        let next = if forward {
+            (current + 1) % len
+        } else {
+            (current + len - 1) % len
+        };
+        self.current = Some(next);
+        self.origin = self.matches[next].start;
+    }
+
+    pub fn current_match(&self) -> Option<&Match> {
+        self.current.map(|index| &self.matches[index])
+    }
+
+    /// Gets whether a cell is part of a match, and if so whether it is the current match.
+    pub fn match_at(&self, point: Point) -> Option<bool> {
+        let index = self
+            .matches
+            .partition_point(|m| m.start <= point)
+            .checked_sub(1)?;
+        (self.matches[index].end >= point).then_some(self.current == Some(index))
+    }
+}
+
+/// Joins the rows reachable by scrolling into logical lines, so that matches can span rows the
+/// application wrapped onto the next line.
+fn logical_lines(term: &Term) -> Vec<LogicalLine> {
+    let mut lines = Vec::new();
+    let mut current: Option<LogicalLine> = None;
+    let last = term.screen_line_number() + term.rows as u64;
+
+    for number in term.first_line_number()..last {
+        let Some(line) = term.line_by_number(number) else {
+            continue;
+        };
+        let logical = current.get_or_insert_with(|| LogicalLine {
+            text: String::new(),
+            offsets: Vec::new(),
+            points: Vec::new(),
+        });
+
+        // Trailing blanks are padding unless the row carries on into the next one.
+        let wrapped = line.is_wrapped();
+        let len = if wrapped {
+            line.cols()
+        } else {
+            line.glyphs
+                .iter()
+                .rposition(|g| g.character != ' ')
+                .map_or(0, |last| last + 1)
+        };
+
+        for (col, glyph) in line.glyphs[..len].iter().enumerate() {
+            if glyph.mode.contains(GlyphAttribute::WDUMMY) {
+                continue;
+            }
+            logical.offsets.push(logical.text.len());
+            logical.points.push(Point { line: number, col });
+            logical.text.push(glyph.character);
+        }
+
+        if !wrapped {
+            lines.extend(current.take());
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(term: &Term, query: &str, mode: SearchMode) -> Search {
+        let mut search = Search::new(term, SearchDirection::Backward);
+        search.query = query.to_string();
+        search.mode = mode;
+        search.update(term);
+        search
+    }
+
+    fn point(line: u64, col: usize) -> Point {
+        Point { line, col }
+    }
+
+    #[test]
+    fn joins_soft_wrapped_rows() {
+        let mut term = Term::new(5, 3);
+        term.write(b"hello world");
+        let lines = logical_lines(&term);
+        assert_eq!(lines[0].text, "hello world");
+
+        let search = search(&term, "lo wo", SearchMode::Plain);
+        assert_eq!(
+            search.matches,
+            [Match {
+                start: point(0, 3),
+                end: point(1, 2),
+            }]
+        );
+        assert_eq!(search.match_at(point(1, 0)), Some(true));
+        assert_eq!(search.match_at(point(1, 3)), None);
+    }
+
+    #[test]
+    fn joins_rows_wrapped_on_double_width_lines() {
+        let mut term = Term::new(10, 3);
+        term.write(b"\x1b#6hello world");
+        let lines = logical_lines(&term);
+        assert_eq!(lines[0].text, "hello world");
+        assert_eq!(lines[0].points[5], point(1, 0));
+
+        let search = search(&term, "lo wo", SearchMode::Plain);
+        assert_eq!(
+            search.matches,
+            [Match {
+                start: point(0, 3),
+                end: point(1, 2),
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_hard_line_breaks() {
+        let mut term = Term::new(5, 3);
+        term.write(b"ab\r\ncd");
+        let lines = logical_lines(&term);
+        assert_eq!(lines[0].text, "ab");
+        assert_eq!(lines[1].text, "cd");
+        assert!(search(&term, "bc", SearchMode::Plain).matches.is_empty());
+    }
+
+    #[test]
+    fn finds_matches_in_the_history() {
+        let mut term = Term::new(8, 2);
+        term.write(b"one\r\nfoo\r\ntwo\r\nfoo\r\nend");
+        assert_eq!(term.history.len(), 3);
+
+        let mut search = search(&term, "foo", SearchMode::Plain);
+        let starts: Vec<_> = search.matches.iter().map(|m| m.start).collect();
+        assert_eq!(starts, [point(1, 0), point(3, 0)]);
+        // Searching backward starts from the match nearest the bottom of the view.
+        assert_eq!(search.current, Some(1));
+
+        search.step(false);
+        assert_eq!(search.current_match().unwrap().start, point(1, 0));
+        search.step(false);
+        assert_eq!(search.current_match().unwrap().start, point(3, 0));
+        search.step(true);
+        assert_eq!(search.current_match().unwrap().start, point(1, 0));
+    }
+
+    #[test]
+    fn forward_search_starts_from_the_top_of_the_view() {
+        let mut term = Term::new(8, 3);
+        term.write(b"foo\r\nfoo\r\nfoo");
+        let mut search = Search::new(&term, SearchDirection::Forward);
+        search.query = "foo".to_string();
+        search.update(&term);
+        assert_eq!(search.current, Some(0));
+        search.step(false);
+        assert_eq!(search.current, Some(1));
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        let mut term = Term::new(10, 2);
+        term.write(b"Hello");
+        assert!(search(&term, "HELLO", SearchMode::Plain).matches.is_empty());
+        assert_eq!(
+            search(&term, "HELLO", SearchMode::CaseInsensitive)
+                .matches
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn plain_search_escapes_regex_syntax() {
+        let mut term = Term::new(10, 2);
+        term.write(b"a.c abc");
+        let search = search(&term, "a.c", SearchMode::Plain);
+        assert_eq!(search.matches.len(), 1);
+        assert_eq!(search.matches[0].start, point(0, 0));
+    }
+
+    #[test]
+    fn matches_regexes() {
+        let mut term = Term::new(10, 2);
+        term.write(b"a1 b22 c3");
+        let search = search(&term, r"[a-z]\d+", SearchMode::Regex);
+        let ends: Vec<_> = search.matches.iter().map(|m| m.end).collect();
+        assert_eq!(ends, [point(0, 1), point(0, 5), point(0, 8)]);
+    }
+
+    #[test]
+    fn reports_invalid_regexes() {
+        let mut term = Term::new(10, 2);
+        term.write(b"(");
+        let search = search(&term, "(", SearchMode::Regex);
+        assert!(search.error.is_some());
+        assert!(search.matches.is_empty());
+        assert!(search.current.is_none());
+    }
+}
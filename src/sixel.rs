@@ -0,0 +1,362 @@
+// Decoding of sixel images, sent in a DCS string as `DCS P1 ; P2 ; P3 q data ST`.
+//
+// The data is drawn in bands six pixels high, with each character from `?` to `~` giving one
+// column of the band. See the VT330/VT340 programmer reference for the details.
+
+use crate::{config, graphics::Image};
+
+/// Colour registers available to an image.
+pub const COLOR_REGISTERS: usize = 1024;
+
+/// The VT340 palette, as percentages of red, green and blue.
+const DEFAULT_PALETTE: [(u32, u32, u32); 16] = [
+    (0, 0, 0),
+    (20, 20, 80),
+    (80, 13, 13),
+    (20, 80, 20),
+    (80, 20, 80),
+    (20, 80, 80),
+    (80, 80, 20),
+    (53, 53, 53),
+    (26, 26, 26),
+    (33, 33, 60),
+    (60, 26, 26),
+    (33, 60, 33),
+    (60, 33, 60),
+    (33, 60, 60),
+    (60, 60, 33),
+    (80, 80, 80),
+];
+
+fn rgb(r: u32, g: u32, b: u32) -> u32 {
+    let scale = |c: u32| c.min(100) * 255 / 100;
+    0xff00_0000 | scale(r) << 16 | scale(g) << 8 | scale(b)
+}
+
+/// Converts a colour given as hue, lightness and saturation. Hue starts at blue rather than red,
+/// as the VT340 has it.
+fn hls(hue: u32, lightness: u32, saturation: u32) -> u32 {
+    let hue = ((hue + 240) % 360) as f64;
+    let lightness = lightness.min(100) as f64 / 100.0;
+    let saturation = saturation.min(100) as f64 / 100.0;
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = lightness - chroma / 2.0;
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let percent = |c: f64| ((c + m) * 100.0).round() as u32;
+    rgb(percent(r), percent(g), percent(b))
+}
+
+struct Decoder {
+    palette: Vec<u32>,
+    color: usize,
+    /// Position of the next column to draw, with `y` the top of the current band.
+    x: usize,
+    y: usize,
+    /// Pixel rows drawn for each bit of a sixel, from the raster attributes.
+    aspect: usize,
+    /// Size of the image, from the raster attributes or as far as anything was drawn.
+    width: usize,
+    height: usize,
+    /// Size `pixels` is laid out for, which grows as the image is drawn.
+    stride: usize,
+    rows: usize,
+    pixels: Vec<u32>,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        let mut palette: Vec<u32> = DEFAULT_PALETTE
+            .iter()
+            .map(|&(r, g, b)| rgb(r, g, b))
+            .collect();
+        palette.resize(COLOR_REGISTERS, 0xff00_0000);
+        Decoder {
+            palette,
+            color: 0,
+            x: 0,
+            y: 0,
+            aspect: 1,
+            width: 0,
+            height: 0,
+            stride: 0,
+            rows: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    /// Makes room for a pixel, returning false if it would be past the size limit.
+    fn reserve(&mut self, x: usize, y: usize) -> bool {
+        if x >= config::SIXEL_MAX_SIZE || y >= config::SIXEL_MAX_SIZE {
+            return false;
+        }
+        if x < self.stride && y < self.rows {
+            return true;
+        }
+        let stride = if x < self.stride {
+            self.stride
+        } else {
+            (x + 1).max(self.stride * 2).min(config::SIXEL_MAX_SIZE)
+        };
+        let rows = if y < self.rows {
+            self.rows
+        } else {
+            (y + 1).max(self.rows * 2).min(config::SIXEL_MAX_SIZE)
+        };
+        let mut pixels = vec![0; stride * rows];
+        for row in 0..self.rows {
+            pixels[row * stride..row * stride + self.stride]
+                .copy_from_slice(&self.pixels[row * self.stride..(row + 1) * self.stride]);
+        }
+        self.pixels = pixels;
+        self.stride = stride;
+        self.rows = rows;
+        true
+    }
+
+    /// Draws a sixel `count` times at the current position.
+    fn draw(&mut self, sixel: u8, count: usize) {
+        let bits = sixel - b'?';
+        let count = count.min(config::SIXEL_MAX_SIZE.saturating_sub(self.x));
+        if bits != 0 && count > 0 {
+            let color = self.palette[self.color];
+            let bottom = self.y + 6 * self.aspect - 1;
+            if self.reserve(self.x + count - 1, bottom.min(config::SIXEL_MAX_SIZE - 1)) {
+                for bit in (0..6).filter(|bit| bits & 1 << bit != 0) {
+                    for y in self.y + bit * self.aspect..self.y + (bit + 1) * self.aspect {
+                        if y >= self.rows {
+                            break;
+                        }
+                        let start = y * self.stride + self.x;
+                        self.pixels[start..start + count].fill(color);
+                        self.height = self.height.max(y + 1);
+                    }
+                }
+                self.width = self.width.max(self.x + count);
+            }
+        }
+        self.x += count;
+    }
+
+    fn color(&mut self, params: &[u32]) {
+        let register = params.first().copied().unwrap_or(0) as usize % COLOR_REGISTERS;
+        self.color = register;
+        if let [_, space, a, b, c, ..] = *params {
+            match space {
+                1 => self.palette[register] = hls(a, b, c),
+                2 => self.palette[register] = rgb(a, b, c),
+                _ => {}
+            }
+        }
+    }
+
+    fn raster(&mut self, params: &[u32]) {
+        let param = |n: usize| params.get(n).copied().unwrap_or(0) as usize;
+        let (numerator, denominator) = (param(0), param(1));
+        if numerator > 0 && denominator > 0 {
+            self.aspect = numerator.div_ceil(denominator).clamp(1, 6);
+        }
+        let (width, height) = (
+            param(2).min(config::SIXEL_MAX_SIZE),
+            param(3).min(config::SIXEL_MAX_SIZE),
+        );
+        if width > 0 && height > 0 && self.reserve(width - 1, height - 1) {
+            self.width = self.width.max(width);
+            self.height = self.height.max(height);
+        }
+    }
+
+    /// Copies out the drawn part of the image, filling in what wasn't drawn with `background`.
+    fn finish(self, background: u32) -> Option<Image> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        for row in 0..self.height {
+            let start = row * self.stride;
+            pixels.extend(self.pixels[start..start + self.width].iter().map(|&pixel| {
+                if pixel == 0 {
+                    background
+                } else {
+                    pixel
+                }
+            }));
+        }
+        Some(Image::new(self.width, self.height, pixels))
+    }
+}
+
+/// Reads numeric parameters separated by `;`, leaving the first byte after them.
+fn read_params(data: &[u8], i: &mut usize) -> Vec<u32> {
+    let mut params = vec![0u32];
+    while let Some(&c) = data.get(*i) {
+        match c {
+            b'0'..=b'9' => {
+                let last = params.last_mut().unwrap();
+                *last = last.saturating_mul(10).saturating_add((c - b'0') as u32);
+            }
+            b';' => params.push(0),
+            _ => break,
+        }
+        *i += 1;
+    }
+    params
+}
+
+/// Decodes the data of a sixel DCS string, which `params` introduced. Returns `None` for an
+/// image with nothing drawn.
+pub fn decode(params: &[i64], data: &str) -> Option<Image> {
+    // The second parameter picks whether pixels left undrawn are transparent.
+    let transparent = params.get(1) == Some(&1);
+    let mut decoder = Decoder::new();
+    let data = data.as_bytes();
+    let mut i = 0;
+
+    while let Some(&c) = data.get(i) {
+        i += 1;
+        match c {
+            b'"' => {
+                let params = read_params(data, &mut i);
+                decoder.raster(&params);
+            }
+            b'#' => {
+                let params = read_params(data, &mut i);
+                decoder.color(&params);
+            }
+            b'!' => {
+                let count = read_params(data, &mut i)[0].max(1) as usize;
+                if let Some(&sixel @ b'?'..=b'~') = data.get(i) {
+                    i += 1;
+                    decoder.draw(sixel, count);
+                }
+            }
+            b'$' => decoder.x = 0,
+            b'-' => {
+                decoder.x = 0;
+                decoder.y += 6 * decoder.aspect;
+            }
+            b'?'..=b'~' => decoder.draw(c, 1),
+            _ => {}
+        }
+    }
+
+    let background = if transparent { 0 } else { decoder.palette[0] };
+    decoder.finish(background)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xffff_0000;
+    const GREEN: u32 = 0xff00_ff00;
+    const BLUE: u32 = 0xff00_00ff;
+    const BLACK: u32 = 0xff00_0000;
+
+    fn size(image: &Image) -> (usize, usize) {
+        (image.width, image.height)
+    }
+
+    #[test]
+    fn draws_a_column_per_sixel() {
+        let image = decode(&[0, 1], "#1;2;100;0;0~").unwrap();
+        assert_eq!(size(&image), (1, 6));
+        assert_eq!(image.pixels, [RED; 6]);
+
+        // Only the set bits are drawn, from the top down.
+        let image = decode(&[0, 1], "#1;2;100;0;0D").unwrap();
+        assert_eq!(size(&image), (1, 3));
+        assert_eq!(image.pixels, [RED, 0, RED]);
+    }
+
+    #[test]
+    fn defines_colours_as_rgb_or_hls() {
+        let image = decode(&[0, 1], "#1;2;0;100;0@#2;1;120;50;100@#3;1;0;50;100@#1@").unwrap();
+        assert_eq!(image.pixels, [GREEN, RED, BLUE, GREEN]);
+
+        // Registers that were never defined keep the VT340 colours.
+        let image = decode(&[0, 1], "#15@").unwrap();
+        assert_eq!(image.pixels, [rgb(80, 80, 80)]);
+    }
+
+    #[test]
+    fn repeats_sixels() {
+        let image = decode(&[0, 1], "#1;2;100;0;0!3@@").unwrap();
+        assert_eq!(size(&image), (4, 1));
+        assert_eq!(image.pixels, [RED; 4]);
+
+        // A count of zero draws once.
+        let image = decode(&[0, 1], "#1;2;100;0;0!0@").unwrap();
+        assert_eq!(size(&image), (1, 1));
+    }
+
+    #[test]
+    fn returns_to_the_start_of_the_band_or_the_next_one() {
+        let image = decode(&[0, 1], "#1;2;100;0;0@@$#2;2;0;100;0A-#1@").unwrap();
+        assert_eq!(size(&image), (2, 7));
+        let column = |x: usize| -> Vec<u32> { (0..7).map(|y| image.pixels[y * 2 + x]).collect() };
+        assert_eq!(column(0), [RED, GREEN, 0, 0, 0, 0, RED]);
+        assert_eq!(column(1), [RED, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sizes_the_image_from_the_raster_attributes() {
+        let image = decode(&[0, 1], "\"1;1;4;2").unwrap();
+        assert_eq!(size(&image), (4, 2));
+        assert_eq!(image.pixels, [0; 8]);
+
+        // Drawing past the given size grows the image.
+        let image = decode(&[0, 1], "\"1;1;1;1#1;2;100;0;0!2A").unwrap();
+        assert_eq!(size(&image), (2, 2));
+        assert_eq!(image.pixels, [0, 0, RED, RED]);
+
+        // The aspect ratio stretches each sixel over more pixel rows.
+        let image = decode(&[0, 1], "\"2;1;1;1#1;2;100;0;0A").unwrap();
+        assert_eq!(size(&image), (1, 4));
+        assert_eq!(image.pixels, [0, 0, RED, RED]);
+    }
+
+    #[test]
+    fn fills_the_background_unless_transparent() {
+        let data = "\"1;1;2;1#1;2;100;0;0@";
+        assert_eq!(decode(&[0, 1], data).unwrap().pixels, [RED, 0]);
+        assert_eq!(decode(&[0, 0], data).unwrap().pixels, [RED, BLACK]);
+        assert_eq!(decode(&[], data).unwrap().pixels, [RED, BLACK]);
+
+        // The background is whatever colour register 0 holds.
+        let data = "#0;2;0;0;100\"1;1;2;1#1;2;100;0;0@";
+        assert_eq!(decode(&[0, 0], data).unwrap().pixels, [RED, BLUE]);
+    }
+
+    #[test]
+    fn returns_nothing_for_an_empty_image() {
+        assert!(decode(&[], "").is_none());
+        assert!(decode(&[], "#1;2;100;0;0???").is_none());
+    }
+
+    #[test]
+    fn clamps_the_size() {
+        let image = decode(&[0, 1], "\"1;1;99999;99999").unwrap();
+        assert_eq!(
+            size(&image),
+            (config::SIXEL_MAX_SIZE, config::SIXEL_MAX_SIZE)
+        );
+
+        let image = decode(&[0, 1], "#1;2;100;0;0!99999@").unwrap();
+        assert_eq!(size(&image), (config::SIXEL_MAX_SIZE, 1));
+        assert!(image.pixels.iter().all(|&pixel| pixel == RED));
+
+        // Bands past the bottom are dropped.
+        let data = format!("@{}@", "-".repeat(config::SIXEL_MAX_SIZE / 6 + 1));
+        let image = decode(&[0, 1], &data).unwrap();
+        assert_eq!(size(&image), (1, 1));
+    }
+}
@@ -0,0 +1,3744 @@
+// Terminal state, the equivalent of st's st.c.
+//
+// Nothing in here talks to X, so the grid, cursor and history can be driven without a display.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    mem::size_of,
+    ops::RangeInclusive,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    base64, config,
+    graphics::{Image, ImageCell},
+    keyboard::{KeyboardFlags, KEYBOARD_STACK_LIMIT},
+    kitty_graphics::{self, KittyGraphics},
+    printer::Printer,
+    sixel,
+};
+
+const ESC: char = '\x1b';
+
+/// Set on a colour to mark it as 24-bit RGB rather than an index into the palette.
+pub const TRUE_COLOR: u32 = 1 << 24;
+
+pub fn true_color(red: u8, green: u8, blue: u8) -> u32 {
+    TRUE_COLOR | (red as u32) << 16 | (green as u32) << 8 | blue as u32
+}
+
+pub fn is_true_color(color: u32) -> bool {
+    color & TRUE_COLOR != 0
+}
+
+bitflags! {
+    /// Attributes of a single glyph in the grid.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct GlyphAttribute: u16 {
+        const BOLD = 1 << 0;
+        const FAINT = 1 << 1;
+        const ITALIC = 1 << 2;
+        const BLINK = 1 << 3;
+        const REVERSE = 1 << 4;
+        const INVISIBLE = 1 << 5;
+        const STRUCK = 1 << 6;
+        /// The line continues on the next row, set on the last glyph of a soft-wrapped row.
+        const WRAP = 1 << 7;
+        const WIDE = 1 << 8;
+        const WDUMMY = 1 << 9;
+        /// Spared by selective erases, set with DECSCA or SPA.
+        const PROTECTED = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// Modes of the terminal itself, as opposed to the window modes in `WindowMode`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct TermMode: u32 {
+        const WRAP = 1 << 0;
+        const INSERT = 1 << 1;
+        const ALTSCREEN = 1 << 2;
+        const CRLF = 1 << 3;
+        const ECHO = 1 << 4;
+        /// Printer controller mode (MC 5), everything received goes to the printer until MC 4.
+        const PRINT = 1 << 5;
+        const UTF8 = 1 << 6;
+        /// Synchronized output (mode 2026), drawing is held back until it is reset.
+        const SYNC = 1 << 7;
+        /// Sixel display mode (DECSDM), sixel images are drawn from the top left corner of the
+        /// screen without scrolling.
+        const SIXEL_DISPLAY = 1 << 8;
+        /// DECCARA and DECRARA change the whole rectangle rather than the characters in reading
+        /// order from its top left to its bottom right corner (DECSACE).
+        const RECTANGLE_EXTENT = 1 << 9;
+        /// Auto print mode (MC ? 5), lines are printed as the cursor leaves them with a line
+        /// feed or wrap.
+        const AUTO_PRINT = 1 << 10;
+        /// Print a form feed after printing the screen (DECPFF).
+        const PRINT_FORM_FEED = 1 << 11;
+        /// Printing the screen prints all of it rather than the scroll region (DECPEX).
+        const PRINT_EXTENT = 1 << 12;
+    }
+}
+
+bitflags! {
+    /// Modes that change how the window behaves, eg: application cursor keys or mouse reporting,
+    /// along with the ones the window tracks itself such as focus.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct WindowMode: u32 {
+        const VISIBLE = 1 << 0;
+        const FOCUSED = 1 << 1;
+        const APPKEYPAD = 1 << 2;
+        const MOUSEBTN = 1 << 3;
+        const MOUSEMOTION = 1 << 4;
+        const REVERSE = 1 << 5;
+        const KBDLOCK = 1 << 6;
+        const HIDE = 1 << 7;
+        const APPCURSOR = 1 << 8;
+        const MOUSESGR = 1 << 9;
+        const EIGHTBIT = 1 << 10;
+        const BLINK = 1 << 11;
+        const FBLINK = 1 << 12;
+        const FOCUS = 1 << 13;
+        const MOUSEX10 = 1 << 14;
+        const MOUSEMANY = 1 << 15;
+        const BRCKTPASTE = 1 << 16;
+        const NUMLOCK = 1 << 17;
+        /// UTF-8 mouse coordinates (mode 1005).
+        const MOUSEUTF8 = 1 << 18;
+        /// urxvt style decimal mouse reports (mode 1015).
+        const MOUSEURXVT = 1 << 19;
+        /// SGR mouse reports in pixels rather than cells (mode 1016).
+        const MOUSESGRPIXELS = 1 << 20;
+        /// The wheel sends cursor keys on the alternate screen (mode 1007).
+        const ALTSCROLL = 1 << 21;
+        /// VT52 mode (DECANM reset), with escape sequences and keys of its own.
+        const VT52 = 1 << 22;
+        /// Any of the mouse reporting modes.
+        const MOUSE = Self::MOUSEBTN.bits()
+            | Self::MOUSEMOTION.bits()
+            | Self::MOUSEX10.bits()
+            | Self::MOUSEMANY.bits();
+        /// Any of the encodings of mouse reports other than the default.
+        const MOUSE_ENCODING = Self::MOUSESGR.bits()
+            | Self::MOUSEUTF8.bits()
+            | Self::MOUSEURXVT.bits()
+            | Self::MOUSESGRPIXELS.bits();
+    }
+}
+
+/// Shape of the cursor, as set with DECSCUSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorStyle {
+    BlinkingBlock,
+    SteadyBlock,
+    BlinkingUnderline,
+    SteadyUnderline,
+    BlinkingBar,
+    SteadyBar,
+}
+
+impl CursorStyle {
+    /// Style for a DECSCUSR parameter, where zero is `config::CURSOR_STYLE`.
+    pub fn from_decscusr(param: i64) -> Option<Self> {
+        match param {
+            0 => Some(config::CURSOR_STYLE),
+            1 => Some(CursorStyle::BlinkingBlock),
+            2 => Some(CursorStyle::SteadyBlock),
+            3 => Some(CursorStyle::BlinkingUnderline),
+            4 => Some(CursorStyle::SteadyUnderline),
+            5 => Some(CursorStyle::BlinkingBar),
+            6 => Some(CursorStyle::SteadyBar),
+            _ => None,
+        }
+    }
+
+    /// The DECSCUSR parameter selecting the style.
+    pub fn decscusr(self) -> i64 {
+        self as i64 + 1
+    }
+
+    pub fn blinks(self) -> bool {
+        matches!(
+            self,
+            CursorStyle::BlinkingBlock | CursorStyle::BlinkingUnderline | CursorStyle::BlinkingBar
+        )
+    }
+}
+
+/// How a glyph is underlined, from `SGR 4:x`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnderlineStyle {
+    #[default]
+    None,
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
+/// One of the X selections an application can reach through OSC 52.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Primary,
+    Clipboard,
+}
+
+/// What applications may do with the selections through OSC 52.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardPolicy {
+    Disabled,
+    /// Applications can set the selections but not read them back.
+    WriteOnly,
+    ReadWrite,
+}
+
+/// Something the terminal needs the window to do, queued in `Term::events` for the frontend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TermEvent {
+    /// Take ownership of a selection with the given text.
+    SetSelection(Selection, String),
+    /// Fetch the text of a selection and hand it to `Term::report_selection`.
+    RequestSelection(Selection),
+    /// The title or working directory changed, so the window title needs updating.
+    TitleChanged,
+}
+
+/// Where the parser is within an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum EscapeState {
+    #[default]
+    Ground,
+    /// After ESC.
+    Escape,
+    /// After ESC and an intermediate byte, eg: `ESC #` or `ESC (`.
+    EscapeIntermediate(char),
+    Csi,
+    /// Collecting the body of an OSC, DCS, APC, PM or SOS string.
+    Str,
+    /// After ESC inside a string, which ends the string when followed by `\`.
+    StrEscape,
+    /// After `ESC Y` in VT52 mode, collecting the row and then the column to move to.
+    Vt52Position(Option<usize>),
+}
+
+/// A control sequence, eg: `CSI ? 25 h`.
+#[derive(Debug, Default)]
+pub struct CsiEscape {
+    buf: String,
+    /// Private marker leading the parameters, one of `?`, `>`, `<` or `=`.
+    pub private: Option<char>,
+    pub args: Vec<i64>,
+    /// Sub-parameters following each argument after a `:`, eg: `[3]` for `4:3`.
+    pub subargs: Vec<Vec<i64>>,
+    /// Intermediate bytes between the parameters and the final byte, eg: `$` in DECRQM.
+    pub intermediates: String,
+    pub final_char: char,
+}
+
+impl CsiEscape {
+    fn parse(&mut self) {
+        let mut chars = self.buf.chars().peekable();
+        self.private = chars.next_if(|c| "?<>=".contains(*c));
+
+        let rest: String = chars.collect();
+        let params_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != ';' && c != ':')
+            .unwrap_or(rest.len());
+        let (params, tail) = rest.split_at(params_end);
+
+        self.args.clear();
+        self.subargs.clear();
+        if !params.is_empty() {
+            for param in params.split(';') {
+                let mut values = param.split(':').map(|value| value.parse().unwrap_or(0));
+                self.args.push(values.next().unwrap_or(0));
+                self.subargs.push(values.collect());
+            }
+        }
+
+        let mut tail: Vec<char> = tail.chars().collect();
+        self.final_char = tail.pop().unwrap_or_default();
+        self.intermediates = tail.into_iter().collect();
+    }
+
+    /// Gets argument `n`, or `default` when it is missing or zero.
+    pub fn arg(&self, n: usize, default: i64) -> i64 {
+        match self.args.get(n) {
+            Some(&arg) if arg != 0 => arg,
+            _ => default,
+        }
+    }
+}
+
+/// An OSC, DCS, APC, PM or SOS string.
+#[derive(Debug, Default)]
+pub struct StrEscape {
+    /// The character introducing the string, eg: `]` for OSC.
+    pub kind: char,
+    pub buf: String,
+}
+
+/// A single cell of the grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glyph {
+    pub character: char,
+    pub mode: GlyphAttribute,
+    pub foreground: u32,
+    pub background: u32,
+    pub underline: UnderlineStyle,
+    /// Colour of the underline, or `None` to use the foreground.
+    pub underline_color: Option<u32>,
+    /// Id of the hyperlink in `Term::hyperlinks` the glyph belongs to, or zero for none.
+    pub hyperlink: u32,
+}
+
+impl Default for Glyph {
+    fn default() -> Self {
+        Glyph {
+            character: ' ',
+            mode: GlyphAttribute::empty(),
+            foreground: config::DEFAULT_FOREGROUND,
+            background: config::DEFAULT_BACKGROUND,
+            underline: UnderlineStyle::None,
+            underline_color: None,
+            hyperlink: 0,
+        }
+    }
+}
+
+/// A hyperlink set with OSC 8.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Hyperlink {
+    /// The `id` parameter, which ties together links split up by the application, eg: across
+    /// rows of a pane.
+    pub id: Option<String>,
+    pub uri: String,
+}
+
+/// Interns hyperlinks, so glyphs only carry a small id rather than a copy of the URI.
+#[derive(Debug)]
+pub struct Hyperlinks {
+    links: Vec<Hyperlink>,
+    ids: HashMap<Hyperlink, u32>,
+    /// Program and arguments run to open a link, with the URI added as the last argument.
+    handler: Vec<String>,
+}
+
+impl Hyperlinks {
+    pub fn new(handler: &[&str]) -> Self {
+        Hyperlinks {
+            links: Vec::new(),
+            ids: HashMap::new(),
+            handler: handler.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    /// Gets the id of a hyperlink, adding it if it hasn't been seen before. Ids start at one.
+    pub fn intern(&mut self, link: Hyperlink) -> u32 {
+        if let Some(&id) = self.ids.get(&link) {
+            return id;
+        }
+        self.links.push(link.clone());
+        let id = self.links.len() as u32;
+        self.ids.insert(link, id);
+        id
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Hyperlink> {
+        id.checked_sub(1)
+            .and_then(|index| self.links.get(index as usize))
+    }
+
+    /// Builds the command that opens a link, or `None` when there is no handler.
+    pub fn open_command(&self, link: &Hyperlink) -> Option<Command> {
+        let (program, args) = self.handler.split_first()?;
+        let mut command = Command::new(program);
+        command.args(args).arg(&link.uri);
+        Some(command)
+    }
+}
+
+/// Part of a shell session a line belongs to, marked by the shell with OSC 133.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SemanticZone {
+    /// Not marked, eg: without shell integration.
+    #[default]
+    Unknown,
+    Prompt,
+    /// The command line being typed after the prompt.
+    Input,
+    /// Output of a command.
+    Output,
+}
+
+/// Size of the characters on a line, set with DECSWL, DECDWL and DECDHL. Lines other than
+/// single width hold half as many columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineSize {
+    #[default]
+    Single,
+    DoubleWidth,
+    /// The top half of double height characters, the bottom half goes on the line below.
+    DoubleHeightTop,
+    DoubleHeightBottom,
+}
+
+impl LineSize {
+    /// Number of columns a line of this size holds on a screen `cols` wide.
+    pub fn cols(self, cols: usize) -> usize {
+        match self {
+            LineSize::Single => cols,
+            _ => (cols / 2).max(1),
+        }
+    }
+}
+
+/// A character set that can be designated as G0 or G1 with `ESC (` and `ESC )`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    /// The United Kingdom set, with `£` in place of `#`.
+    Uk,
+    /// The DEC Special Graphics set, with line drawing characters in place of lower case.
+    LineDrawing,
+}
+
+/// The DEC Special Graphics characters standing in for `` ` `` to `~`.
+const LINE_DRAWING: [char; 31] = [
+    '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼', '⎺', '⎻', '─', '⎼',
+    '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+impl Charset {
+    /// The set a designation's final character names, eg: `0` in `ESC ( 0`.
+    fn from_designator(c: char) -> Option<Self> {
+        match c {
+            'B' => Some(Charset::Ascii),
+            'A' => Some(Charset::Uk),
+            '0' => Some(Charset::LineDrawing),
+            _ => None,
+        }
+    }
+
+    /// The character `c` stands for in this set.
+    pub fn map(self, c: char) -> char {
+        match (self, c) {
+            (Charset::Uk, '#') => '£',
+            (Charset::LineDrawing, '_') => ' ',
+            (Charset::LineDrawing, '`'..='~') => LINE_DRAWING[c as usize - '`' as usize],
+            _ => c,
+        }
+    }
+}
+
+/// How protected glyphs were last marked, which decides the erases that spare them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protection {
+    #[default]
+    Off,
+    /// Set with DECSCA, only selective erases spare protected glyphs.
+    Dec,
+    /// Set with SPA and EPA, every erase spares protected glyphs.
+    Iso,
+}
+
+/// A row of the grid.
+#[derive(Debug, Clone, Default)]
+pub struct Line {
+    pub glyphs: Vec<Glyph>,
+    /// Zone the line started in.
+    pub zone: SemanticZone,
+    /// A prompt starts on this line.
+    pub prompt_start: bool,
+    /// Exit status of the command run from the prompt starting on this line, once finished.
+    pub exit_status: Option<i32>,
+    /// Tiles of images shown on the line.
+    pub images: Vec<ImageCell>,
+    pub size: LineSize,
+}
+
+impl Line {
+    pub fn new(cols: usize, template: Glyph) -> Self {
+        Line {
+            glyphs: vec![template; cols],
+            ..Line::default()
+        }
+    }
+
+    /// Forgets the shell integration marks, when the line is cleared.
+    fn clear_marks(&mut self) {
+        self.zone = SemanticZone::Unknown;
+        self.prompt_start = false;
+        self.exit_status = None;
+    }
+
+    /// Approximate heap and inline memory held by this line, used for the scrollback cap.
+    pub fn memory_size(&self) -> usize {
+        size_of::<Line>()
+            + self.glyphs.capacity() * size_of::<Glyph>()
+            + self.images.capacity() * size_of::<ImageCell>()
+    }
+
+    /// Drops the image tiles in columns `x1..=x2`, when the cells are written over.
+    fn remove_images(&mut self, x1: usize, x2: usize) {
+        if !self.images.is_empty() {
+            self.images.retain(|cell| !(x1..=x2).contains(&cell.col));
+        }
+    }
+
+    /// Gets the characters of the line, without trailing blanks.
+    pub fn text(&self) -> String {
+        let text: String = self
+            .glyphs
+            .iter()
+            .filter(|g| !g.mode.contains(GlyphAttribute::WDUMMY))
+            .map(|g| g.character)
+            .collect();
+        text.trim_end().to_string()
+    }
+
+    /// Number of columns the line holds, which is half the glyphs on double width and double
+    /// height lines.
+    pub fn cols(&self) -> usize {
+        self.size.cols(self.glyphs.len()).min(self.glyphs.len())
+    }
+
+    /// Whether the row soft-wraps into the next one, as marked on its last column.
+    pub fn is_wrapped(&self) -> bool {
+        self.glyphs[..self.cols()]
+            .last()
+            .is_some_and(|g| g.mode.contains(GlyphAttribute::WRAP))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cursor {
+    /// Attributes given to glyphs written at the cursor.
+    pub attr: Glyph,
+    pub x: usize,
+    pub y: usize,
+    /// The last column was written and the next character wraps first.
+    pub wrap_next: bool,
+    /// Cursor addressing is relative to the scroll region.
+    pub origin: bool,
+    /// The sets designated as G0 and G1, and which of them is shifted in with SI and SO.
+    pub charsets: [Charset; 2],
+    pub charset: usize,
+}
+
+impl Cursor {
+    /// A blank glyph carrying the cursor's colours, used when clearing.
+    pub fn attr_blank(&self) -> Glyph {
+        Glyph {
+            character: ' ',
+            mode: GlyphAttribute::empty(),
+            underline: UnderlineStyle::None,
+            underline_color: None,
+            hyperlink: 0,
+            ..self.attr
+        }
+    }
+}
+
+/// Lines that scrolled off the top of the primary screen, oldest first.
+///
+/// This is a ring buffer bounded both by a number of lines and by an estimate of the memory they
+/// use, dropping the oldest lines once either limit is exceeded.
+#[derive(Debug)]
+pub struct History {
+    lines: VecDeque<Line>,
+    max_lines: usize,
+    max_bytes: usize,
+    bytes: usize,
+    /// Lines dropped off the front so far, which gives every line a stable number.
+    dropped: u64,
+}
+
+impl History {
+    pub fn new(max_lines: usize, max_bytes: usize) -> Self {
+        History {
+            lines: VecDeque::new(),
+            max_lines,
+            max_bytes,
+            bytes: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: Line) {
+        if self.max_lines == 0 {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes += line.memory_size();
+        self.lines.push_back(line);
+        while self.lines.len() > self.max_lines || self.bytes > self.max_bytes {
+            match self.lines.pop_front() {
+                Some(old) => {
+                    self.bytes -= old.memory_size();
+                    self.dropped += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Gets a line by index, where zero is the oldest line kept.
+    pub fn get(&self, index: usize) -> Option<&Line> {
+        self.lines.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Line> {
+        self.lines.get_mut(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Number of the oldest line kept, counting every line ever pushed.
+    pub fn first_line_number(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Memory currently accounted to the history, in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.dropped += self.lines.len() as u64;
+        self.lines.clear();
+        self.bytes = 0;
+    }
+}
+
+pub struct Term {
+    pub rows: usize,
+    pub cols: usize,
+    /// The screen currently shown, primary or alternate depending on `TermMode::ALTSCREEN`.
+    pub lines: Vec<Line>,
+    /// The screen not currently shown.
+    pub alt_lines: Vec<Line>,
+    /// Scrollback for the primary screen. The alternate screen never records history.
+    pub history: History,
+    /// How many lines of history the view is scrolled back by. Zero shows the live screen.
+    ///
+    /// This only moves the view, the cursor stays where the application left it.
+    pub scroll_offset: usize,
+    pub cursor: Cursor,
+    /// Cursors saved with DECSC, one for each screen.
+    pub saved_cursors: [Cursor; 2],
+    /// Top row of the scroll region.
+    pub top: usize,
+    /// Bottom row of the scroll region, inclusive.
+    pub bottom: usize,
+    pub mode: TermMode,
+    /// Modes set by the terminal that change how the window behaves, eg: application cursor keys
+    /// or mouse reporting, along with the ones the window tracks itself such as focus.
+    pub window_mode: WindowMode,
+    pub cursor_style: CursorStyle,
+    pub protection: Protection,
+    /// Images shown in the grid or the history, by id.
+    pub images: HashMap<u32, Image>,
+    next_image_id: u32,
+    /// Size of a cell in pixels, set by the window so that images can be laid out in cells.
+    pub cell_size: (usize, usize),
+    /// Images and placements of the kitty graphics protocol.
+    pub kitty_graphics: KittyGraphics,
+    /// Kitty keyboard protocol flags pushed by applications, for the primary and alternate
+    /// screens. The last entry is in effect.
+    keyboard_stacks: [Vec<KeyboardFlags>; 2],
+    /// xterm's modifyOtherKeys level set with `CSI > 4 ; Pm m`, zero when off.
+    pub modify_other_keys: u8,
+    /// Rows of the view that need redrawing.
+    pub dirty: Vec<bool>,
+    pub hyperlinks: Hyperlinks,
+    /// Columns with a tab stop.
+    pub tabs: Vec<bool>,
+    /// Bytes waiting to be written to the child, such as replies to queries.
+    pub tty_buffer: Vec<u8>,
+    /// Requests for the window, waiting to be handled by the frontend.
+    pub events: Vec<TermEvent>,
+    /// What applications may do with the selections through OSC 52, `config::OSC52_POLICY`
+    /// unless changed.
+    pub clipboard_policy: ClipboardPolicy,
+    /// Title set with OSC 0 or 2.
+    pub title: Option<String>,
+    /// Working directory reported by the shell with OSC 7.
+    pub reported_directory: Option<PathBuf>,
+    /// Process id of the shell running in the terminal, once it has been spawned.
+    pub child_pid: Option<u32>,
+    /// Zone the shell last marked with OSC 133, given to new lines as the cursor reaches them.
+    pub semantic_zone: SemanticZone,
+    /// Number of the line the last prompt started on, to attach the exit status to.
+    last_prompt: Option<u64>,
+    /// Where media copy sends text, running `config::PRINTER` unless given another command.
+    pub printer: Printer,
+    /// When the current synchronized update began.
+    sync_start: Option<Instant>,
+    escape: EscapeState,
+    csi: CsiEscape,
+    str_escape: StrEscape,
+    /// Bytes of an incomplete UTF-8 sequence left over from the last write.
+    utf8_buffer: Vec<u8>,
+}
+
+impl Term {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Term {
+            rows,
+            cols,
+            lines: (0..rows)
+                .map(|_| Line::new(cols, Glyph::default()))
+                .collect(),
+            alt_lines: (0..rows)
+                .map(|_| Line::new(cols, Glyph::default()))
+                .collect(),
+            history: History::new(config::SCROLLBACK_LINES, config::SCROLLBACK_MEMORY_LIMIT),
+            scroll_offset: 0,
+            cursor: Cursor::default(),
+            saved_cursors: [Cursor::default(); 2],
+            top: 0,
+            bottom: rows - 1,
+            mode: TermMode::WRAP | TermMode::UTF8,
+            window_mode: if config::ALTERNATE_SCROLL {
+                WindowMode::ALTSCROLL
+            } else {
+                WindowMode::empty()
+            },
+            cursor_style: config::CURSOR_STYLE,
+            protection: Protection::Off,
+            images: HashMap::new(),
+            next_image_id: 1,
+            cell_size: (10, 20),
+            kitty_graphics: KittyGraphics::default(),
+            keyboard_stacks: Default::default(),
+            modify_other_keys: 0,
+            dirty: vec![true; rows],
+            hyperlinks: Hyperlinks::new(config::HYPERLINK_HANDLER),
+            tabs: default_tabs(cols),
+            tty_buffer: Vec::new(),
+            events: Vec::new(),
+            clipboard_policy: config::OSC52_POLICY,
+            title: None,
+            reported_directory: None,
+            child_pid: None,
+            semantic_zone: SemanticZone::Unknown,
+            last_prompt: None,
+            printer: Printer::new(config::PRINTER),
+            sync_start: None,
+            escape: EscapeState::Ground,
+            csi: CsiEscape::default(),
+            str_escape: StrEscape::default(),
+            utf8_buffer: Vec::new(),
+        }
+    }
+
+    pub fn is_altscreen(&self) -> bool {
+        self.mode.contains(TermMode::ALTSCREEN)
+    }
+
+    pub fn set_dirty(&mut self, top: usize, bottom: usize) {
+        for dirty in self.dirty.iter_mut().take(bottom + 1).skip(top) {
+            *dirty = true;
+        }
+    }
+
+    pub fn set_dirty_all(&mut self) {
+        self.dirty.fill(true);
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+
+        // Slide the screen up so the cursor stays on it, keeping what slides off in the history.
+        if self.cursor.y >= rows {
+            let shift = self.cursor.y - rows + 1;
+            let (primary, alternate) = if self.is_altscreen() {
+                (&mut self.alt_lines, &mut self.lines)
+            } else {
+                (&mut self.lines, &mut self.alt_lines)
+            };
+            for line in primary.drain(..shift) {
+                self.history.push(line);
+            }
+            alternate.drain(..shift);
+            self.cursor.y -= shift;
+        }
+
+        for screen in [&mut self.lines, &mut self.alt_lines] {
+            screen.resize_with(rows, || Line::new(cols, Glyph::default()));
+            for line in screen.iter_mut() {
+                line.glyphs.resize(cols, Glyph::default());
+            }
+        }
+
+        let old_cols = self.tabs.len();
+        self.tabs.resize(cols, false);
+        for col in (old_cols..cols).filter(|col| col % config::TAB_SPACES == 0) {
+            self.tabs[col] = true;
+        }
+
+        self.rows = rows;
+        self.cols = cols;
+        self.top = 0;
+        self.bottom = rows - 1;
+        self.dirty = vec![true; rows];
+        self.scroll_offset = self.scroll_offset.min(self.history.len());
+        self.move_to(self.cursor.x, self.cursor.y);
+    }
+
+    /// Switches between the primary and alternate screens.
+    pub fn swap_screen(&mut self) {
+        std::mem::swap(&mut self.lines, &mut self.alt_lines);
+        self.mode.toggle(TermMode::ALTSCREEN);
+        self.scroll_offset = 0;
+        self.set_dirty_all();
+    }
+
+    /// Kitty keyboard protocol flags in effect on the current screen.
+    pub fn keyboard_flags(&self) -> KeyboardFlags {
+        let stack = &self.keyboard_stacks[self.is_altscreen() as usize];
+        stack.last().copied().unwrap_or_default()
+    }
+
+    fn keyboard_stack(&mut self) -> &mut Vec<KeyboardFlags> {
+        &mut self.keyboard_stacks[self.is_altscreen() as usize]
+    }
+
+    /// Moves the cursor, clamping it to the screen.
+    pub fn move_to(&mut self, x: usize, y: usize) {
+        let (min_y, max_y) = if self.cursor.origin {
+            (self.top, self.bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.cursor.wrap_next = false;
+        self.cursor.y = y.clamp(min_y, max_y);
+        self.cursor.x = x.min(self.line_cols(self.cursor.y) - 1);
+    }
+
+    /// Number of columns on a row of the screen, which is half the width of the screen on double
+    /// width and double height lines.
+    pub fn line_cols(&self, y: usize) -> usize {
+        self.lines[y].size.cols(self.cols)
+    }
+
+    /// Moves the cursor, with `y` relative to the scroll region in origin mode.
+    pub fn move_to_origin(&mut self, x: usize, y: usize) {
+        let y = if self.cursor.origin { y + self.top } else { y };
+        self.move_to(x, y);
+    }
+
+    /// Saves the cursor for the current screen, as DECSC.
+    pub fn save_cursor(&mut self) {
+        let screen = self.is_altscreen() as usize;
+        self.saved_cursors[screen] = self.cursor;
+    }
+
+    /// Restores the cursor saved for the current screen, as DECRC.
+    pub fn load_cursor(&mut self) {
+        let screen = self.is_altscreen() as usize;
+        self.cursor = self.saved_cursors[screen];
+        self.move_to(self.cursor.x, self.cursor.y);
+    }
+
+    /// Writes a printable character at the cursor and advances it.
+    pub fn put_char(&mut self, c: char) {
+        if self.placeholder_diacritic(c) {
+            return;
+        }
+        if self.mode.contains(TermMode::WRAP) && self.cursor.wrap_next {
+            let (x, y) = (self.cursor.x, self.cursor.y);
+            self.lines[y].glyphs[x].mode |= GlyphAttribute::WRAP;
+            self.newline(true);
+        }
+
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let cols = self.line_cols(y);
+        if self.mode.contains(TermMode::INSERT) && x + 1 < cols {
+            let glyphs = &mut self.lines[y].glyphs;
+            glyphs[x..cols].rotate_right(1);
+        }
+        self.lines[y].glyphs[x] = Glyph {
+            character: c,
+            ..self.cursor.attr
+        };
+        self.lines[y].remove_images(x, x);
+        if c == kitty_graphics::PLACEHOLDER {
+            self.image_placeholder(x, y);
+        }
+        self.set_dirty(y, y);
+
+        if x + 1 < cols {
+            self.cursor.x += 1;
+        } else {
+            self.cursor.wrap_next = true;
+        }
+    }
+
+    /// Moves the cursor down a row, scrolling at the bottom of the scroll region.
+    pub fn newline(&mut self, first_col: bool) {
+        let y = self.cursor.y;
+        if self.mode.contains(TermMode::AUTO_PRINT) {
+            let number = self.screen_line_number() + y as u64;
+            self.print_lines(number..=number, false);
+        }
+        if y == self.bottom {
+            self.scroll_up(self.top, 1);
+        } else if y + 1 < self.rows {
+            self.cursor.y += 1;
+        }
+        if first_col {
+            self.cursor.x = 0;
+        }
+        self.cursor.x = self.cursor.x.min(self.line_cols(self.cursor.y) - 1);
+        self.cursor.wrap_next = false;
+        self.lines[self.cursor.y].zone = self.semantic_zone;
+    }
+
+    /// Scrolls rows `orig..=bottom` up by `n`, blanking the rows exposed at the bottom.
+    ///
+    /// Rows leaving the top of the primary screen are kept in the history.
+    pub fn scroll_up(&mut self, orig: usize, n: usize) {
+        let n = n.min(self.bottom + 1 - orig);
+        let blank = Line::new(self.cols, self.cursor.attr_blank());
+        let keep_history = orig == 0 && !self.is_altscreen();
+
+        for line in self.lines[orig..=self.bottom].iter_mut().take(n) {
+            let old = std::mem::replace(line, blank.clone());
+            if keep_history {
+                self.history.push(old);
+            }
+        }
+        self.lines[orig..=self.bottom].rotate_left(n);
+
+        // Keep the view on the same history lines while output arrives, but never further back
+        // than the oldest line the history still holds.
+        if keep_history && self.scroll_offset > 0 {
+            self.scroll_offset = (self.scroll_offset + n).min(self.history.len());
+        } else {
+            self.scroll_offset = self.scroll_offset.min(self.history.len());
+        }
+        self.set_dirty(orig, self.bottom);
+    }
+
+    /// Scrolls rows `orig..=bottom` down by `n`, blanking the rows exposed at the top.
+    pub fn scroll_down(&mut self, orig: usize, n: usize) {
+        let n = n.min(self.bottom + 1 - orig);
+        let blank = self.cursor.attr_blank();
+        self.lines[orig..=self.bottom].rotate_right(n);
+        for line in self.lines[orig..orig + n].iter_mut() {
+            line.glyphs.fill(blank);
+        }
+        self.set_dirty(orig, self.bottom);
+    }
+
+    /// Blanks the inclusive region between two corners.
+    pub fn clear_region(&mut self, x1: usize, y1: usize, x2: usize, y2: usize) {
+        let (x1, x2) = (x1.min(x2), x1.max(x2).min(self.cols - 1));
+        let (y1, y2) = (y1.min(y2), y1.max(y2).min(self.rows - 1));
+        let blank = self.cursor.attr_blank();
+        let whole_lines = x1 == 0 && x2 == self.cols - 1;
+        for line in &mut self.lines[y1..=y2] {
+            line.glyphs[x1..=x2].fill(blank);
+            line.remove_images(x1, x2);
+            if whole_lines {
+                line.clear_marks();
+            }
+        }
+        self.set_dirty(y1, y2);
+    }
+
+    /// Erases the inclusive region between two corners, sparing protected glyphs when SPA marked
+    /// them or, for a `selective` erase, when DECSCA did. A selective erase only blanks the
+    /// characters and keeps their attributes.
+    pub fn erase_region(&mut self, x1: usize, y1: usize, x2: usize, y2: usize, selective: bool) {
+        let spare = match self.protection {
+            Protection::Off => false,
+            Protection::Dec => selective,
+            Protection::Iso => true,
+        };
+        if !spare && !selective {
+            self.clear_region(x1, y1, x2, y2);
+            return;
+        }
+        let (x1, x2) = (x1.min(x2), x1.max(x2).min(self.cols - 1));
+        let (y1, y2) = (y1.min(y2), y1.max(y2).min(self.rows - 1));
+        let blank = self.cursor.attr_blank();
+        for line in &mut self.lines[y1..=y2] {
+            for glyph in &mut line.glyphs[x1..=x2] {
+                if spare && glyph.mode.contains(GlyphAttribute::PROTECTED) {
+                    continue;
+                }
+                if selective {
+                    glyph.character = ' ';
+                    glyph
+                        .mode
+                        .remove(GlyphAttribute::WIDE | GlyphAttribute::WDUMMY);
+                } else {
+                    *glyph = blank;
+                }
+            }
+            line.remove_images(x1, x2);
+        }
+        self.set_dirty(y1, y2);
+    }
+
+    /// Gets the row shown at `y` of the view, which is a history line when scrolled back.
+    pub fn view_line(&self, y: usize) -> &Line {
+        let offset = self.scroll_offset.min(self.history.len());
+        match y.checked_sub(offset) {
+            Some(row) => &self.lines[row.min(self.lines.len() - 1)],
+            None => &self.history.lines[self.history.len() - offset + y],
+        }
+    }
+
+    /// Scrolls the view back into the history by `n` lines.
+    pub fn scroll_view_up(&mut self, n: usize) {
+        if self.is_altscreen() {
+            return;
+        }
+        let offset = (self.scroll_offset + n).min(self.history.len());
+        if offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.set_dirty_all();
+        }
+    }
+
+    /// Scrolls the view forward towards the live screen by `n` lines.
+    pub fn scroll_view_down(&mut self, n: usize) {
+        let offset = self.scroll_offset.saturating_sub(n);
+        if offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.set_dirty_all();
+        }
+    }
+
+    /// Snaps the view back to the live screen.
+    pub fn scroll_view_reset(&mut self) {
+        self.scroll_view_down(self.scroll_offset);
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.scroll_offset > 0
+    }
+
+    /// Number of the top row of the screen, following on from the numbers of history lines.
+    ///
+    /// Line numbers stay attached to the same text as it scrolls into the history, so they can be
+    /// used to remember positions across output.
+    pub fn screen_line_number(&self) -> u64 {
+        self.history.first_line_number() + self.history.len() as u64
+    }
+
+    /// Number of the first line reachable by scrolling, which is the top of the screen on the
+    /// alternate screen.
+    pub fn first_line_number(&self) -> u64 {
+        if self.is_altscreen() {
+            self.screen_line_number()
+        } else {
+            self.history.first_line_number()
+        }
+    }
+
+    /// Gets a line of the history or screen by its number.
+    pub fn line_by_number(&self, number: u64) -> Option<&Line> {
+        let screen = self.screen_line_number();
+        if number >= screen {
+            self.lines.get((number - screen) as usize)
+        } else if number >= self.first_line_number() {
+            self.history
+                .get((number - self.history.first_line_number()) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn line_by_number_mut(&mut self, number: u64) -> Option<&mut Line> {
+        let screen = self.screen_line_number();
+        if number >= screen {
+            self.lines.get_mut((number - screen) as usize)
+        } else if number >= self.first_line_number() {
+            let first = self.history.first_line_number();
+            self.history.get_mut((number - first) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Number of the line shown at row `y` of the view.
+    pub fn view_line_number(&self, y: usize) -> u64 {
+        self.screen_line_number() - self.scroll_offset.min(self.history.len()) as u64 + y as u64
+    }
+
+    /// Scrolls the view so the numbered line is shown, leaving it alone if it already is.
+    pub fn scroll_view_to_line(&mut self, number: u64) {
+        let top = self.view_line_number(0);
+        if (top..top + self.rows as u64).contains(&number) {
+            return;
+        }
+        let screen = self.screen_line_number();
+        let offset = if number >= screen {
+            0
+        } else {
+            ((screen - number) as usize + self.rows / 2).min(self.history.len())
+        };
+        if !self.is_altscreen() && offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.set_dirty_all();
+        }
+    }
+
+    /// Queues bytes to be written to the child. Input typed by the user is echoed back to the
+    /// screen in local echo mode.
+    pub fn tty_write(&mut self, bytes: &[u8], may_echo: bool) {
+        if may_echo && self.mode.contains(TermMode::ECHO) {
+            self.write(bytes);
+        }
+        if self.mode.contains(TermMode::CRLF) {
+            for &byte in bytes {
+                self.tty_buffer.push(byte);
+                if byte == b'\r' {
+                    self.tty_buffer.push(b'\n');
+                }
+            }
+        } else {
+            self.tty_buffer.extend_from_slice(bytes);
+        }
+    }
+
+    /// Whether drawing should be held back for a synchronized update.
+    ///
+    /// Updates that go on for longer than `timeout` are abandoned, so an application that dies
+    /// part way through one can't freeze the window.
+    pub fn in_synchronized_update(&mut self, timeout: Duration) -> bool {
+        match self.sync_start {
+            Some(start) if start.elapsed() < timeout => true,
+            Some(_) => {
+                self.set_synchronized_update(false);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn set_synchronized_update(&mut self, set: bool) {
+        self.mode.set(TermMode::SYNC, set);
+        self.sync_start = set.then(Instant::now);
+        if !set {
+            self.set_dirty_all();
+        }
+    }
+
+    /// Feeds output from the child through the parser.
+    pub fn write(&mut self, bytes: &[u8]) {
+        if !self.mode.contains(TermMode::UTF8) {
+            for &byte in bytes {
+                self.handle_char(byte as char);
+            }
+            return;
+        }
+
+        let mut buffer = std::mem::take(&mut self.utf8_buffer);
+        buffer.extend_from_slice(bytes);
+        let mut rest = &buffer[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    text.chars().for_each(|c| self.handle_char(c));
+                    break;
+                }
+                Err(error) => {
+                    let (valid, invalid) = rest.split_at(error.valid_up_to());
+                    std::str::from_utf8(valid)
+                        .unwrap_or_default()
+                        .chars()
+                        .for_each(|c| self.handle_char(c));
+                    match error.error_len() {
+                        Some(len) => {
+                            self.handle_char(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // Keep the start of a sequence split across writes for next time.
+                        None => {
+                            self.utf8_buffer = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles a single character of output, either as part of an escape sequence or by
+    /// writing it to the screen.
+    pub fn handle_char(&mut self, c: char) {
+        if self.mode.contains(TermMode::PRINT) {
+            let utf8 = self.mode.contains(TermMode::UTF8);
+            if self.printer.controller_char(c, utf8) {
+                self.set_printer_mode(TermMode::PRINT, false);
+            }
+            return;
+        }
+
+        match self.escape {
+            EscapeState::Str => {
+                match c {
+                    '\x07' | '\u{9c}' => self.str_handle(),
+                    ESC => self.escape = EscapeState::StrEscape,
+                    '\x18' | '\x1a' => self.escape = EscapeState::Ground,
+                    _ if self.str_escape.buf.len() < config::ESCAPE_STRING_LIMIT => {
+                        self.str_escape.buf.push(c)
+                    }
+                    _ => {}
+                }
+                return;
+            }
+            EscapeState::StrEscape => {
+                if c == '\\' {
+                    self.str_handle();
+                    return;
+                }
+                // Anything but ST drops the string and starts a new escape sequence.
+                self.csi = CsiEscape::default();
+                self.escape = EscapeState::Escape;
+            }
+            _ => {}
+        }
+
+        if is_control(c) {
+            self.control_code(c);
+            return;
+        }
+
+        match self.escape {
+            EscapeState::Csi => {
+                self.csi.buf.push(c);
+                if ('\x40'..='\x7e').contains(&c) {
+                    self.escape = EscapeState::Ground;
+                    self.csi.parse();
+                    self.csi_handle();
+                } else if self.csi.buf.len() > config::ESCAPE_CSI_LIMIT {
+                    self.escape = EscapeState::Ground;
+                }
+            }
+            EscapeState::Escape if self.window_mode.contains(WindowMode::VT52) => {
+                self.vt52_handle(c)
+            }
+            EscapeState::Escape => self.esc_handle(c),
+            EscapeState::Vt52Position(None) => {
+                let row = (c as usize).saturating_sub(32);
+                self.escape = EscapeState::Vt52Position(Some(row));
+            }
+            EscapeState::Vt52Position(Some(row)) => {
+                self.escape = EscapeState::Ground;
+                self.move_to((c as usize).saturating_sub(32), row);
+            }
+            EscapeState::EscapeIntermediate(intermediate) => {
+                self.escape = EscapeState::Ground;
+                self.esc_intermediate_handle(intermediate, c);
+            }
+            _ => self.put_char(self.cursor.charsets[self.cursor.charset].map(c)),
+        }
+    }
+
+    fn control_code(&mut self, c: char) {
+        match c {
+            '\t' => self.put_tab(1),
+            '\x08' => self.move_to(self.cursor.x.saturating_sub(1), self.cursor.y),
+            '\r' => self.move_to(0, self.cursor.y),
+            '\x0c' | '\x0b' | '\n' => self.newline(self.mode.contains(TermMode::CRLF)),
+            // TODO: Bell
+            '\x07' => {}
+            // SO and SI
+            '\x0e' => self.cursor.charset = 1,
+            '\x0f' => self.cursor.charset = 0,
+            ESC => {
+                self.csi = CsiEscape::default();
+                self.escape = EscapeState::Escape;
+            }
+            '\x18' | '\x1a' => {
+                self.csi = CsiEscape::default();
+                self.escape = EscapeState::Ground;
+            }
+            // NEL
+            '\u{85}' => self.newline(true),
+            // HTS
+            '\u{88}' => self.tabs[self.cursor.x] = true,
+            // CSI
+            '\u{9b}' => {
+                self.csi = CsiEscape::default();
+                self.escape = EscapeState::Csi;
+            }
+            // DCS, SOS, OSC, PM and APC
+            '\u{90}' => self.str_start('P'),
+            '\u{98}' => self.str_start('X'),
+            '\u{9d}' => self.str_start(']'),
+            '\u{9e}' => self.str_start('^'),
+            '\u{9f}' => self.str_start('_'),
+            _ => {}
+        }
+    }
+
+    fn esc_handle(&mut self, c: char) {
+        self.escape = EscapeState::Ground;
+        match c {
+            '[' => self.escape = EscapeState::Csi,
+            '#' | '(' | ')' | '*' | '+' | ' ' | '%' => {
+                self.escape = EscapeState::EscapeIntermediate(c)
+            }
+            'P' | '_' | '^' | ']' | 'X' => self.str_start(c),
+            // IND
+            'D' => {
+                if self.cursor.y == self.bottom {
+                    self.scroll_up(self.top, 1);
+                } else {
+                    self.move_to(self.cursor.x, self.cursor.y + 1);
+                }
+            }
+            // NEL
+            'E' => self.newline(true),
+            // HTS
+            'H' => self.tabs[self.cursor.x] = true,
+            // RI
+            'M' => self.reverse_index(),
+            // SPA and EPA
+            'V' => {
+                self.cursor.attr.mode.insert(GlyphAttribute::PROTECTED);
+                self.protection = Protection::Iso;
+            }
+            'W' => self.cursor.attr.mode.remove(GlyphAttribute::PROTECTED),
+            // DECID
+            'Z' => self.tty_write(config::VT_IDENTIFICATION, false),
+            // RIS
+            'c' => self.reset(),
+            // DECPAM and DECPNM
+            '=' => self.window_mode.insert(WindowMode::APPKEYPAD),
+            '>' => self.window_mode.remove(WindowMode::APPKEYPAD),
+            // DECSC and DECRC
+            '7' => self.save_cursor(),
+            '8' => self.load_cursor(),
+            _ => {}
+        }
+    }
+
+    /// Handles the escape sequences of VT52 mode, which take the place of the ANSI ones.
+    fn vt52_handle(&mut self, c: char) {
+        self.escape = EscapeState::Ground;
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        match c {
+            'A' => self.move_to(x, y.saturating_sub(1)),
+            'B' => self.move_to(x, y + 1),
+            'C' => self.move_to(x + 1, y),
+            'D' => self.move_to(x.saturating_sub(1), y),
+            // Graphics mode, which uses the DEC Special Graphics set as a VT100 does.
+            'F' => self.cursor.charsets[self.cursor.charset] = Charset::LineDrawing,
+            'G' => self.cursor.charsets[self.cursor.charset] = Charset::Ascii,
+            'H' => self.move_to(0, 0),
+            'I' => self.reverse_index(),
+            'J' => self.erase_display(0, false),
+            'K' => self.erase_line(0, false),
+            'Y' => self.escape = EscapeState::Vt52Position(None),
+            'Z' => self.tty_write(b"\x1b/Z", false),
+            '=' => self.window_mode.insert(WindowMode::APPKEYPAD),
+            '>' => self.window_mode.remove(WindowMode::APPKEYPAD),
+            '<' => self.window_mode.remove(WindowMode::VT52),
+            _ => {}
+        }
+    }
+
+    /// Moves the cursor up a row, scrolling down at the top of the scroll region.
+    fn reverse_index(&mut self) {
+        if self.cursor.y == self.top {
+            self.scroll_down(self.top, 1);
+        } else {
+            self.move_to(self.cursor.x, self.cursor.y.saturating_sub(1));
+        }
+    }
+
+    fn esc_intermediate_handle(&mut self, intermediate: char, c: char) {
+        match (intermediate, c) {
+            // DECDHL
+            ('#', '3') => self.set_line_size(LineSize::DoubleHeightTop),
+            ('#', '4') => self.set_line_size(LineSize::DoubleHeightBottom),
+            // DECSWL
+            ('#', '5') => self.set_line_size(LineSize::Single),
+            // DECDWL
+            ('#', '6') => self.set_line_size(LineSize::DoubleWidth),
+            // DECALN
+            ('#', '8') => self.screen_alignment_test(),
+            // SCS, designating G0 or G1
+            ('(' | ')', _) => {
+                if let Some(charset) = Charset::from_designator(c) {
+                    self.cursor.charsets[(intermediate == ')') as usize] = charset;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Changes the size of the characters on the cursor's line. Those that no longer fit on a
+    /// line of half the width are lost.
+    fn set_line_size(&mut self, size: LineSize) {
+        let y = self.cursor.y;
+        self.lines[y].size = size;
+        let cols = self.line_cols(y);
+        if cols < self.cols {
+            self.clear_region(cols, y, self.cols - 1, y);
+        }
+        if self.cursor.x >= cols {
+            self.cursor.x = cols - 1;
+            self.cursor.wrap_next = false;
+        }
+        self.set_dirty(y, y);
+    }
+
+    /// Resets the terminal to how it started (RIS). The history is kept, along with what the
+    /// window tracks itself such as focus, and anything waiting to be sent to the child or the
+    /// window.
+    pub fn reset(&mut self) {
+        let mut old = std::mem::replace(self, Term::new(self.cols, self.rows));
+        let window_modes =
+            WindowMode::VISIBLE | WindowMode::FOCUSED | WindowMode::BLINK | WindowMode::NUMLOCK;
+        self.window_mode |= old.window_mode & window_modes;
+        self.history = old.history;
+        self.hyperlinks = old.hyperlinks;
+        self.images = old.images;
+        self.next_image_id = old.next_image_id;
+        self.cell_size = old.cell_size;
+        self.clipboard_policy = old.clipboard_policy;
+        self.tty_buffer = old.tty_buffer;
+        self.events = old.events;
+        self.reported_directory = old.reported_directory;
+        self.child_pid = old.child_pid;
+        self.last_prompt = old.last_prompt;
+        // The printer keeps its command, but a job left open by a printer mode ends.
+        std::mem::swap(&mut self.printer, &mut old.printer);
+        self.printer.finish();
+
+        // Images only shown on the screen, or placed with the kitty protocol, are gone now.
+        self.collect_images();
+        self.events.push(TermEvent::TitleChanged);
+    }
+
+    /// Resets the modes an application is most likely to have left behind (DECSTR), leaving the
+    /// screen and the cursor position alone.
+    fn soft_reset(&mut self) {
+        self.window_mode.remove(
+            WindowMode::HIDE | WindowMode::APPKEYPAD | WindowMode::APPCURSOR | WindowMode::KBDLOCK,
+        );
+        self.mode.remove(TermMode::INSERT);
+        self.mode.insert(TermMode::WRAP);
+        self.cursor.attr = Glyph::default();
+        self.cursor.origin = false;
+        self.cursor.wrap_next = false;
+        self.cursor.charsets = [Charset::Ascii; 2];
+        self.cursor.charset = 0;
+        self.saved_cursors = [Cursor::default(); 2];
+        self.protection = Protection::Off;
+        self.top = 0;
+        self.bottom = self.rows - 1;
+    }
+
+    /// Fills the screen with `E` for adjusting the display (DECALN), resetting the scroll region
+    /// and moving the cursor home.
+    fn screen_alignment_test(&mut self) {
+        let glyph = Glyph {
+            character: 'E',
+            ..Glyph::default()
+        };
+        for line in &mut self.lines {
+            *line = Line::new(self.cols, glyph);
+        }
+        self.top = 0;
+        self.bottom = self.rows - 1;
+        self.cursor.origin = false;
+        self.move_to(0, 0);
+        self.set_dirty_all();
+        self.collect_images();
+    }
+
+    fn str_start(&mut self, kind: char) {
+        self.str_escape = StrEscape {
+            kind,
+            buf: String::new(),
+        };
+        self.escape = EscapeState::Str;
+    }
+
+    fn str_handle(&mut self) {
+        self.escape = EscapeState::Ground;
+        let escape = std::mem::take(&mut self.str_escape);
+        match escape.kind {
+            ']' => self.osc_handle(&escape.buf),
+            'P' => self.dcs_handle(&escape.buf),
+            '_' => {
+                if let Some(command) = escape.buf.strip_prefix('G') {
+                    self.kitty_graphics_command(command);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn dcs_handle(&mut self, buf: &str) {
+        let params_end = buf
+            .find(|c: char| !c.is_ascii_digit() && c != ';')
+            .unwrap_or(buf.len());
+        let (params, rest) = buf.split_at(params_end);
+        let params: Vec<i64> = params
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        if let Some(data) = rest.strip_prefix('q') {
+            if let Some(image) = sixel::decode(&params, data) {
+                let scroll = !self.mode.contains(TermMode::SIXEL_DISPLAY);
+                self.add_image(image, scroll);
+            }
+        } else if let Some(setting) = rest.strip_prefix("$q") {
+            self.report_setting(setting);
+        }
+    }
+
+    /// Replies to DECRQSS with the current value of a setting, named by the intermediate and
+    /// final bytes of the control sequence that sets it.
+    fn report_setting(&mut self, setting: &str) {
+        let reply = match setting {
+            " q" => format!("\x1bP1$r{} q\x1b\\", self.cursor_style.decscusr()),
+            "\"q" => {
+                let protected = self.cursor.attr.mode.contains(GlyphAttribute::PROTECTED);
+                format!("\x1bP1$r{}\"q\x1b\\", protected as u8)
+            }
+            _ => "\x1bP0$r\x1b\\".to_string(),
+        };
+        self.tty_write(reply.as_bytes(), false);
+    }
+
+    /// Shows an image over the cells from the cursor, or from the top left corner of the screen
+    /// without `scroll`. With `scroll` the screen scrolls to fit the image and the cursor moves
+    /// to the line below it.
+    pub fn add_image(&mut self, image: Image, scroll: bool) -> u32 {
+        let id = self.insert_image(image);
+        let (x, y) = if scroll {
+            (self.cursor.x, self.cursor.y)
+        } else {
+            (0, 0)
+        };
+        let y = self.place_image(id, x, y, scroll, 0, true);
+        if scroll {
+            self.move_to(x, y);
+            self.newline(false);
+        }
+        self.collect_images();
+        id
+    }
+
+    /// Adds an image for lines to show, cut into tiles the size of a cell.
+    pub fn insert_image(&mut self, mut image: Image) -> u32 {
+        image.cell_size = self.cell_size;
+        let id = self.next_image_id;
+        self.next_image_id = self.next_image_id.wrapping_add(1).max(1);
+        self.images.insert(id, image);
+        id
+    }
+
+    /// Lays the tiles of an image over the cells from `x`, `y`, with the screen scrolling to fit
+    /// it when `scroll` is set or the image cut off at the bottom otherwise. With `erase` the
+    /// cells under it are cleared. Returns the row of the image's last line.
+    pub fn place_image(
+        &mut self,
+        id: u32,
+        x: usize,
+        mut y: usize,
+        scroll: bool,
+        z: i32,
+        erase: bool,
+    ) -> usize {
+        let Some(image) = self.images.get(&id) else {
+            return y;
+        };
+        let (cols, rows) = image.size_in_cells();
+        let blank = self.cursor.attr_blank();
+        for row in 0..rows {
+            if row > 0 {
+                if !scroll && y + 1 >= self.rows {
+                    break;
+                }
+                if scroll && y == self.bottom {
+                    self.scroll_up(self.top, 1);
+                } else {
+                    y = (y + 1).min(self.rows - 1);
+                }
+            }
+            let last = (x + cols).min(self.cols);
+            let line = &mut self.lines[y];
+            if erase {
+                line.glyphs[x..last].fill(blank);
+            }
+            line.remove_images(x, last - 1);
+            line.images.extend((x..last).map(|col| ImageCell {
+                col,
+                image: id,
+                x: col - x,
+                y: row,
+                z,
+            }));
+            self.set_dirty(y, y);
+        }
+        y
+    }
+
+    /// Drops images no line shows any more, then the oldest images while they take more memory
+    /// than allowed.
+    pub fn collect_images(&mut self) {
+        let mut shown = HashSet::new();
+        for line in self.lines.iter().chain(&self.alt_lines) {
+            shown.extend(line.images.iter().map(|cell| cell.image));
+        }
+        for index in 0..self.history.len() {
+            if let Some(line) = self.history.get(index) {
+                shown.extend(line.images.iter().map(|cell| cell.image));
+            }
+        }
+        // Kitty placements go with their tiles, except virtual ones which are only shown through
+        // placeholders.
+        let placements = &mut self.kitty_graphics.placements;
+        placements.retain(|placement| placement.is_virtual || shown.contains(&placement.image));
+        shown.extend(placements.iter().map(|placement| placement.image));
+        self.images.retain(|id, _| shown.contains(id));
+
+        let mut ids: Vec<u32> = self.images.keys().copied().collect();
+        ids.sort_unstable();
+        let mut size: usize = self.images.values().map(Image::memory_size).sum();
+        for id in ids {
+            if size <= config::IMAGE_MEMORY_LIMIT {
+                break;
+            }
+            if let Some(image) = self.images.remove(&id) {
+                size -= image.memory_size();
+            }
+        }
+    }
+
+    fn osc_handle(&mut self, buf: &str) {
+        let (command, args) = buf.split_once(';').unwrap_or((buf, ""));
+        match command {
+            "0" | "2" => {
+                self.title = (!args.is_empty()).then(|| args.to_string());
+                self.events.push(TermEvent::TitleChanged);
+            }
+            "7" => self.set_reported_directory(args),
+            "8" => self.set_hyperlink(args),
+            "133" => self.set_semantic_zone(args),
+            "52" => self.osc_selection(args),
+            "1337" => self.iterm_command(args),
+            _ => {}
+        }
+    }
+
+    /// Sets or queries a selection from OSC 52, whose arguments are `targets;data` where data is
+    /// base64 or `?` to query.
+    fn osc_selection(&mut self, args: &str) {
+        let Some((targets, data)) = args.split_once(';') else {
+            return;
+        };
+        // Cut buffers aren't supported, and `s` is treated like the clipboard as xterm does by
+        // default.
+        let selection = match targets.chars().find(|c| "cps".contains(*c)) {
+            Some('p') => Selection::Primary,
+            Some(_) => Selection::Clipboard,
+            None if targets.is_empty() => Selection::Clipboard,
+            None => return,
+        };
+
+        match (self.clipboard_policy, data) {
+            (ClipboardPolicy::ReadWrite, "?") => {
+                self.events.push(TermEvent::RequestSelection(selection));
+            }
+            (ClipboardPolicy::WriteOnly | ClipboardPolicy::ReadWrite, data) if data != "?" => {
+                // Base64 grows data by a third, which is enough to reject anything too large
+                // before decoding it.
+                if data.len() / 4 * 3 > config::OSC52_SIZE_LIMIT {
+                    return;
+                }
+                let Some(decoded) = base64::decode(data) else {
+                    return;
+                };
+                let text = String::from_utf8_lossy(&decoded).into_owned();
+                self.events.push(TermEvent::SetSelection(selection, text));
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends pasted text to the application, bracketed with `CSI 200 ~` and `CSI 201 ~` when it
+    /// turned on bracketed paste (mode 2004). Control characters are stripped so that the text
+    /// can't end the bracketing early or act as keys, and newlines become carriage returns as
+    /// the Enter key sends.
+    pub fn paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\r").replace('\n', "\r");
+        let text: String = text
+            .chars()
+            .filter(|&c| c == '\t' || c == '\r' || !c.is_control())
+            .collect();
+        let bracketed = self.window_mode.contains(WindowMode::BRCKTPASTE);
+        if bracketed {
+            self.tty_write(b"\x1b[200~", false);
+        }
+        self.tty_write(text.as_bytes(), true);
+        if bracketed {
+            self.tty_write(b"\x1b[201~", false);
+        }
+    }
+
+    /// Answers an OSC 52 query with the text of a selection.
+    pub fn report_selection(&mut self, selection: Selection, text: &str) {
+        if self.clipboard_policy != ClipboardPolicy::ReadWrite {
+            return;
+        }
+        let text = if text.len() > config::OSC52_SIZE_LIMIT {
+            ""
+        } else {
+            text
+        };
+        let target = match selection {
+            Selection::Primary => 'p',
+            Selection::Clipboard => 'c',
+        };
+        let reply = format!(
+            "\x1b]52;{};{}\x1b\\",
+            target,
+            base64::encode(text.as_bytes())
+        );
+        self.tty_write(reply.as_bytes(), false);
+    }
+
+    /// Records the working directory from OSC 7, given as a `file://host/path` URL. Directories
+    /// on other hosts, eg: from a shell over SSH, are ignored.
+    fn set_reported_directory(&mut self, url: &str) {
+        let Some((host, path)) = url
+            .strip_prefix("file://")
+            .and_then(|rest| rest.find('/').map(|slash| rest.split_at(slash)))
+        else {
+            return;
+        };
+        let local = host.is_empty()
+            || host == "localhost"
+            || fs::read_to_string("/proc/sys/kernel/hostname")
+                .is_ok_and(|hostname| hostname.trim() == host);
+        if !local {
+            return;
+        }
+        self.reported_directory = Some(PathBuf::from(percent_decode(path)));
+        self.events.push(TermEvent::TitleChanged);
+    }
+
+    /// Gets the working directory of the shell, as reported with OSC 7 or otherwise of the
+    /// process in the foreground of the terminal.
+    pub fn working_directory(&self) -> Option<PathBuf> {
+        if let Some(directory) = &self.reported_directory {
+            return Some(directory.clone());
+        }
+
+        // The eighth field of stat is the foreground process group of the process's terminal,
+        // whose leader's pid is the same as the group id. The second field is the command name
+        // in brackets, which may itself contain spaces, so fields are counted after it.
+        let pid = self.child_pid?;
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let foreground = stat
+            .rsplit_once(')')?
+            .1
+            .split_whitespace()
+            .nth(5)
+            .and_then(|pgid| pgid.parse::<i32>().ok())
+            .filter(|&pgid| pgid > 0)
+            .map_or(pid, |pgid| pgid as u32);
+
+        fs::read_link(format!("/proc/{}/cwd", foreground))
+            .or_else(|_| fs::read_link(format!("/proc/{}/cwd", pid)))
+            .ok()
+    }
+
+    /// Marks the start of a prompt (`A`), command input (`B`), command output (`C`) or the end
+    /// of a command with its exit status (`D;status`) from OSC 133.
+    fn set_semantic_zone(&mut self, args: &str) {
+        let mut params = args.split(';');
+        let cursor_line = self.screen_line_number() + self.cursor.y as u64;
+        match params.next() {
+            Some("A") => {
+                self.semantic_zone = SemanticZone::Prompt;
+                let line = &mut self.lines[self.cursor.y];
+                line.zone = SemanticZone::Prompt;
+                line.prompt_start = true;
+                self.last_prompt = Some(cursor_line);
+            }
+            Some("B") => self.semantic_zone = SemanticZone::Input,
+            Some("C") => {
+                self.semantic_zone = SemanticZone::Output;
+                // Shells mark output after the newline ending the command line, so the cursor
+                // is usually already at the start of the first line of output.
+                if self.cursor.x == 0 {
+                    self.lines[self.cursor.y].zone = SemanticZone::Output;
+                }
+            }
+            Some("D") => {
+                self.semantic_zone = SemanticZone::Unknown;
+                let status = params.next().and_then(|status| status.parse().ok());
+                if let Some(prompt) = self.last_prompt.take() {
+                    if let Some(line) = self.line_by_number_mut(prompt) {
+                        line.exit_status = status;
+                    }
+                    self.set_dirty_all();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Scrolls the view so the previous prompt above the top of the view, or the next one below
+    /// it when `forward`, is at the top.
+    pub fn scroll_view_to_prompt(&mut self, forward: bool) {
+        let top = self.view_line_number(0);
+        let last = self.screen_line_number() + self.rows as u64;
+        let is_prompt = |number: &u64| {
+            self.line_by_number(*number)
+                .is_some_and(|line| line.prompt_start)
+        };
+        let prompt = if forward {
+            (top + 1..last).find(is_prompt)
+        } else {
+            (self.first_line_number()..top).rev().find(is_prompt)
+        };
+        let Some(prompt) = prompt else {
+            return;
+        };
+
+        let offset = self.screen_line_number().saturating_sub(prompt) as usize;
+        let offset = offset.min(self.history.len());
+        if !self.is_altscreen() && offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.set_dirty_all();
+        }
+    }
+
+    /// Gets the text of the most recent block of command output.
+    pub fn last_command_output(&self) -> Option<String> {
+        let first = self.first_line_number();
+        let last = self.screen_line_number() + self.cursor.y as u64;
+        let is_output = |number: &u64| {
+            self.line_by_number(*number)
+                .is_some_and(|line| line.zone == SemanticZone::Output)
+        };
+
+        let end = (first..=last).rev().find(is_output)?;
+        let start = (first..=end)
+            .rev()
+            .take_while(is_output)
+            .last()
+            .unwrap_or(end);
+
+        let mut text = String::new();
+        for number in start..=end {
+            let Some(line) = self.line_by_number(number) else {
+                continue;
+            };
+            let glyphs = line.glyphs[..line.cols()]
+                .iter()
+                .filter(|g| !g.mode.contains(GlyphAttribute::WDUMMY))
+                .map(|g| g.character);
+            if line.is_wrapped() {
+                text.extend(glyphs);
+            } else {
+                text.push_str(glyphs.collect::<String>().trim_end());
+                text.push('\n');
+            }
+        }
+        Some(text)
+    }
+
+    /// Starts or ends a hyperlink from OSC 8, whose arguments are `params;uri`. An empty URI
+    /// ends the link.
+    fn set_hyperlink(&mut self, args: &str) {
+        let Some((params, uri)) = args.split_once(';') else {
+            return;
+        };
+        if uri.is_empty() {
+            self.cursor.attr.hyperlink = 0;
+            return;
+        }
+        let id = params
+            .split(':')
+            .find_map(|param| param.strip_prefix("id="))
+            .map(String::from);
+        self.cursor.attr.hyperlink = self.hyperlinks.intern(Hyperlink {
+            id,
+            uri: uri.to_string(),
+        });
+    }
+
+    /// Gets the hyperlink of the glyph shown at a column and row of the view.
+    pub fn hyperlink_at(&self, col: usize, row: usize) -> Option<&Hyperlink> {
+        let glyph = self.view_line(row).glyphs.get(col)?;
+        self.hyperlinks.get(glyph.hyperlink)
+    }
+
+    fn csi_handle(&mut self) {
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let n = self.csi.arg(0, 1).max(1) as usize;
+
+        match (
+            self.csi.private,
+            self.csi.intermediates.as_str(),
+            self.csi.final_char,
+        ) {
+            // ICH
+            (None, "", '@') => self.insert_blank(n),
+            // CUU
+            (None, "", 'A') => self.move_to(x, y.saturating_sub(n)),
+            // CUD and VPR
+            (None, "", 'B' | 'e') => self.move_to(x, y + n),
+            // CUF and HPR
+            (None, "", 'C' | 'a') => self.move_to(x + n, y),
+            // CUB
+            (None, "", 'D') => self.move_to(x.saturating_sub(n), y),
+            // CNL
+            (None, "", 'E') => self.move_to(0, y + n),
+            // CPL
+            (None, "", 'F') => self.move_to(0, y.saturating_sub(n)),
+            // CHA and HPA
+            (None, "", 'G' | '`') => self.move_to(n - 1, y),
+            // CUP and HVP
+            (None, "", 'H' | 'f') => {
+                let col = self.csi.arg(1, 1).max(1) as usize;
+                self.move_to_origin(col - 1, n - 1);
+            }
+            // CHT
+            (None, "", 'I') => self.put_tab(n as i64),
+            // ED and DECSED
+            (None | Some('?'), "", 'J') => {
+                let selective = self.csi.private.is_some();
+                self.erase_display(self.csi.arg(0, 0), selective);
+            }
+            // EL and DECSEL
+            (None | Some('?'), "", 'K') => {
+                let selective = self.csi.private.is_some();
+                self.erase_line(self.csi.arg(0, 0), selective);
+            }
+            // IL
+            (None, "", 'L') => self.insert_blank_line(n),
+            // DL
+            (None, "", 'M') => self.delete_line(n),
+            // DCH
+            (None, "", 'P') => self.delete_char(n),
+            // SU
+            (None, "", 'S') => self.scroll_up(self.top, n),
+            // SD
+            (None, "", 'T') => self.scroll_down(self.top, n),
+            // ECH
+            (None, "", 'X') => self.erase_region(x, y, x + n - 1, y, false),
+            // CBT
+            (None, "", 'Z') => self.put_tab(-(n as i64)),
+            // VPA
+            (None, "", 'd') => self.move_to_origin(x, n - 1),
+            // TBC
+            (None, "", 'g') => match self.csi.arg(0, 0) {
+                0 => self.tabs[x] = false,
+                3 => self.tabs.fill(false),
+                _ => {}
+            },
+            // SM, RM, DECSET and DECRST
+            (None | Some('?'), "", 'h' | 'l') => {
+                let private = self.csi.private.is_some();
+                let set = self.csi.final_char == 'h';
+                for mode in self.csi.args.clone() {
+                    self.set_mode(private, set, mode);
+                }
+            }
+            // DECRQM
+            (None | Some('?'), "$", 'p') => self.report_mode(),
+            // DECSTR
+            (None, "!", 'p') => self.soft_reset(),
+            // MC
+            (None | Some('?'), "", 'i') => self.media_copy(),
+            // DECCRA
+            (None, "$", 'v') => self.copy_rectangle(),
+            // DECFRA
+            (None, "$", 'x') => self.fill_rectangle(),
+            // DECERA
+            (None, "$", 'z') => {
+                if let Some((top, left, bottom, right)) = self.csi_rectangle(0, false) {
+                    self.erase_region(left, top, right, bottom, false);
+                }
+            }
+            // DECSERA
+            (None, "$", '{') => {
+                if let Some((top, left, bottom, right)) = self.csi_rectangle(0, false) {
+                    self.erase_region(left, top, right, bottom, true);
+                }
+            }
+            // DECSCA
+            (None, "\"", 'q') => match self.csi.arg(0, 0) {
+                1 => {
+                    self.cursor.attr.mode.insert(GlyphAttribute::PROTECTED);
+                    self.protection = Protection::Dec;
+                }
+                0 | 2 => self.cursor.attr.mode.remove(GlyphAttribute::PROTECTED),
+                _ => {}
+            },
+            // DECCARA and DECRARA
+            (None, "$", 'r') => self.change_rectangle_attributes(false),
+            (None, "$", 't') => self.change_rectangle_attributes(true),
+            // DECSACE
+            (None, "*", 'x') => {
+                let rectangle = self.csi.arg(0, 0) == 2;
+                self.mode.set(TermMode::RECTANGLE_EXTENT, rectangle);
+            }
+            // SGR
+            (None, "", 'm') => self.set_attributes(),
+            // DSR
+            (None, "", 'n') => match self.csi.arg(0, 0) {
+                5 => self.tty_write(b"\x1b[0n", false),
+                6 => {
+                    let row = if self.cursor.origin { y - self.top } else { y };
+                    let report = format!("\x1b[{};{}R", row + 1, x + 1);
+                    self.tty_write(report.as_bytes(), false);
+                }
+                _ => {}
+            },
+            // DA
+            (None, "", 'c') if self.csi.arg(0, 0) == 0 => {
+                self.tty_write(config::VT_IDENTIFICATION, false)
+            }
+            // DECSCUSR
+            (None, " ", 'q') => {
+                if let Some(style) = CursorStyle::from_decscusr(self.csi.arg(0, 0)) {
+                    self.cursor_style = style;
+                }
+            }
+            // DECSTBM
+            (None, "", 'r') => {
+                let top = self.csi.arg(0, 1).max(1) as usize - 1;
+                let bottom =
+                    (self.csi.arg(1, self.rows as i64).max(1) as usize - 1).min(self.rows - 1);
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.move_to_origin(0, 0);
+                }
+            }
+            // SCOSC and SCORC
+            (None, "", 's') => self.save_cursor(),
+            (None, "", 'u') => self.load_cursor(),
+            // XTSMGRAPHICS, where the colour registers and sixel size can only be read
+            (Some('?'), "", 'S') => {
+                let reply = match self.csi.arg(0, 0) {
+                    1 => format!("\x1b[?1;0;{}S", sixel::COLOR_REGISTERS),
+                    2 => format!(
+                        "\x1b[?2;0;{};{}S",
+                        config::SIXEL_MAX_SIZE,
+                        config::SIXEL_MAX_SIZE
+                    ),
+                    item => format!("\x1b[?{};1;0S", item),
+                };
+                self.tty_write(reply.as_bytes(), false);
+            }
+            // XTMODKEYS, of which only modifyOtherKeys is supported
+            (Some('>'), "", 'm') if self.csi.arg(0, 0) == 4 => {
+                self.modify_other_keys = self.csi.arg(1, 0).clamp(0, 2) as u8;
+            }
+            (Some('>'), "", 'n') if self.csi.arg(0, 0) == 4 => self.modify_other_keys = 0,
+            // XTQMODKEYS
+            (Some('?'), "", 'm') if self.csi.arg(0, 0) == 4 => {
+                let report = format!("\x1b[>4;{}m", self.modify_other_keys);
+                self.tty_write(report.as_bytes(), false);
+            }
+            // Kitty keyboard protocol: push, pop, query and set the flags
+            (Some('>'), "", 'u') => {
+                let flags = KeyboardFlags::from_bits_truncate(self.csi.arg(0, 0) as u32);
+                let stack = self.keyboard_stack();
+                if stack.len() >= KEYBOARD_STACK_LIMIT {
+                    stack.remove(0);
+                }
+                stack.push(flags);
+            }
+            (Some('<'), "", 'u') => {
+                let stack = self.keyboard_stack();
+                stack.truncate(stack.len().saturating_sub(n));
+            }
+            (Some('?'), "", 'u') => {
+                let report = format!("\x1b[?{}u", self.keyboard_flags().bits());
+                self.tty_write(report.as_bytes(), false);
+            }
+            (Some('='), "", 'u') => {
+                let flags = KeyboardFlags::from_bits_truncate(self.csi.arg(0, 0) as u32);
+                let current = self.keyboard_flags();
+                let flags = match self.csi.arg(1, 1) {
+                    1 => flags,
+                    2 => current | flags,
+                    3 => current - flags,
+                    _ => return,
+                };
+                let stack = self.keyboard_stack();
+                match stack.last_mut() {
+                    Some(last) => *last = flags,
+                    None => stack.push(flags),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets or resets a mode of SM and RM, or DECSET and DECRST when `private`.
+    fn set_mode(&mut self, private: bool, set: bool, mode: i64) {
+        if !private {
+            match mode {
+                // IRM
+                4 => self.mode.set(TermMode::INSERT, set),
+                // SRM, which turns local echo off when set
+                12 => self.mode.set(TermMode::ECHO, !set),
+                // LNM
+                20 => self.mode.set(TermMode::CRLF, set),
+                _ => {}
+            }
+            return;
+        }
+
+        match mode {
+            // DECCKM
+            1 => self.window_mode.set(WindowMode::APPCURSOR, set),
+            // DECANM, where reset leaves ANSI mode for VT52 mode
+            2 => self.window_mode.set(WindowMode::VT52, !set),
+            // DECSCNM
+            5 => {
+                self.window_mode.set(WindowMode::REVERSE, set);
+                self.set_dirty_all();
+            }
+            // DECOM
+            6 => {
+                self.cursor.origin = set;
+                self.move_to_origin(0, 0);
+            }
+            // DECAWM
+            7 => self.mode.set(TermMode::WRAP, set),
+            // DECTCEM
+            25 => self.window_mode.set(WindowMode::HIDE, !set),
+            // DECPFF
+            18 => self.mode.set(TermMode::PRINT_FORM_FEED, set),
+            // DECPEX
+            19 => self.mode.set(TermMode::PRINT_EXTENT, set),
+            // DECSDM
+            80 => self.mode.set(TermMode::SIXEL_DISPLAY, set),
+            9 | 1000 | 1002 | 1003 => {
+                let mouse_mode = match mode {
+                    9 => WindowMode::MOUSEX10,
+                    1000 => WindowMode::MOUSEBTN,
+                    1002 => WindowMode::MOUSEMOTION,
+                    _ => WindowMode::MOUSEMANY,
+                };
+                self.window_mode.remove(WindowMode::MOUSE);
+                self.window_mode.set(mouse_mode, set);
+            }
+            1004 => self.window_mode.set(WindowMode::FOCUS, set),
+            1005 | 1006 | 1015 | 1016 => {
+                let encoding = match mode {
+                    1005 => WindowMode::MOUSEUTF8,
+                    1006 => WindowMode::MOUSESGR,
+                    1015 => WindowMode::MOUSEURXVT,
+                    _ => WindowMode::MOUSESGRPIXELS,
+                };
+                // Only one encoding is in use at a time, the one set last.
+                if set {
+                    self.window_mode.remove(WindowMode::MOUSE_ENCODING);
+                }
+                self.window_mode.set(encoding, set);
+            }
+            1007 => self.window_mode.set(WindowMode::ALTSCROLL, set),
+            1034 => self.window_mode.set(WindowMode::EIGHTBIT, set),
+            1049 | 47 | 1047 => {
+                if mode == 1049 {
+                    if set {
+                        self.save_cursor();
+                    } else {
+                        self.load_cursor();
+                    }
+                }
+                let alt = self.is_altscreen();
+                if alt {
+                    self.clear_region(0, 0, self.cols - 1, self.rows - 1);
+                }
+                if set != alt {
+                    self.swap_screen();
+                }
+                if mode == 1049 {
+                    if set {
+                        self.save_cursor();
+                    } else {
+                        self.load_cursor();
+                    }
+                }
+            }
+            1048 => {
+                if set {
+                    self.save_cursor();
+                } else {
+                    self.load_cursor();
+                }
+            }
+            2004 => self.window_mode.set(WindowMode::BRCKTPASTE, set),
+            2026 => self.set_synchronized_update(set),
+            _ => {}
+        }
+    }
+
+    /// Gets whether a mode is set, or `None` when it isn't one the terminal knows.
+    fn mode_state(&self, private: bool, mode: i64) -> Option<bool> {
+        if !private {
+            return match mode {
+                4 => Some(self.mode.contains(TermMode::INSERT)),
+                12 => Some(!self.mode.contains(TermMode::ECHO)),
+                20 => Some(self.mode.contains(TermMode::CRLF)),
+                _ => None,
+            };
+        }
+
+        let window_mode = |flag| Some(self.window_mode.contains(flag));
+        match mode {
+            1 => window_mode(WindowMode::APPCURSOR),
+            2 => Some(!self.window_mode.contains(WindowMode::VT52)),
+            5 => window_mode(WindowMode::REVERSE),
+            6 => Some(self.cursor.origin),
+            7 => Some(self.mode.contains(TermMode::WRAP)),
+            9 => window_mode(WindowMode::MOUSEX10),
+            18 => Some(self.mode.contains(TermMode::PRINT_FORM_FEED)),
+            19 => Some(self.mode.contains(TermMode::PRINT_EXTENT)),
+            25 => Some(!self.window_mode.contains(WindowMode::HIDE)),
+            80 => Some(self.mode.contains(TermMode::SIXEL_DISPLAY)),
+            1000 => window_mode(WindowMode::MOUSEBTN),
+            1002 => window_mode(WindowMode::MOUSEMOTION),
+            1003 => window_mode(WindowMode::MOUSEMANY),
+            1004 => window_mode(WindowMode::FOCUS),
+            1005 => window_mode(WindowMode::MOUSEUTF8),
+            1006 => window_mode(WindowMode::MOUSESGR),
+            1015 => window_mode(WindowMode::MOUSEURXVT),
+            1007 => window_mode(WindowMode::ALTSCROLL),
+            1016 => window_mode(WindowMode::MOUSESGRPIXELS),
+            1034 => window_mode(WindowMode::EIGHTBIT),
+            47 | 1047 | 1049 => Some(self.is_altscreen()),
+            2004 => window_mode(WindowMode::BRCKTPASTE),
+            2026 => Some(self.mode.contains(TermMode::SYNC)),
+            _ => None,
+        }
+    }
+
+    /// Handles MC, printing part of the screen or turning printer controller mode and auto
+    /// print mode on and off.
+    fn media_copy(&mut self) {
+        let private = self.csi.private.is_some();
+        match (private, self.csi.arg(0, 0)) {
+            (false, 0) => self.print_screen(self.mode.contains(TermMode::PRINT_EXTENT)),
+            (_, 1) => {
+                let number = self.screen_line_number() + self.cursor.y as u64;
+                self.print_lines(number..=number, false);
+            }
+            (false, 4) => self.set_printer_mode(TermMode::PRINT, false),
+            (false, 5) => self.set_printer_mode(TermMode::PRINT, true),
+            (true, 4) => self.set_printer_mode(TermMode::AUTO_PRINT, false),
+            (true, 5) => self.set_printer_mode(TermMode::AUTO_PRINT, true),
+            (true, 10) => self.print_screen(true),
+            // All pages, which are the history and the screen here.
+            (true, 11) => {
+                let last = self.screen_line_number() + self.rows as u64 - 1;
+                self.print_lines(self.first_line_number()..=last, true);
+            }
+            _ => {}
+        }
+    }
+
+    /// Turns printer controller mode or auto print mode on or off, ending the print job once
+    /// neither is on.
+    fn set_printer_mode(&mut self, mode: TermMode, set: bool) {
+        self.mode.set(mode, set);
+        if !self.mode.intersects(TermMode::PRINT | TermMode::AUTO_PRINT) {
+            self.printer.finish();
+        }
+    }
+
+    /// Turns auto print mode on or off.
+    pub fn toggle_auto_print(&mut self) {
+        let set = !self.mode.contains(TermMode::AUTO_PRINT);
+        self.set_printer_mode(TermMode::AUTO_PRINT, set);
+    }
+
+    /// Prints the screen, or only the scroll region unless `full`.
+    pub fn print_screen(&mut self, full: bool) {
+        let (top, bottom) = if full {
+            (0, self.rows - 1)
+        } else {
+            (self.top, self.bottom)
+        };
+        let screen = self.screen_line_number();
+        self.print_lines(screen + top as u64..=screen + bottom as u64, true);
+    }
+
+    /// Prints lines of the history or screen by number, followed by a form feed when they make
+    /// up a page and DECPFF is set.
+    fn print_lines(&mut self, numbers: RangeInclusive<u64>, page: bool) {
+        let mut text = String::new();
+        for number in numbers {
+            if let Some(line) = self.line_by_number(number) {
+                text.push_str(&line.text());
+                text.push('\n');
+            }
+        }
+        if page && self.mode.contains(TermMode::PRINT_FORM_FEED) {
+            text.push('\x0c');
+        }
+        self.print(&text);
+    }
+
+    /// Sends text to the printer, as a job of its own unless a printer mode keeps one open.
+    pub fn print(&mut self, text: &str) {
+        self.printer.write(text.as_bytes());
+        if !self.mode.intersects(TermMode::PRINT | TermMode::AUTO_PRINT) {
+            self.printer.finish();
+        }
+    }
+
+    /// Replies to DECRQM with whether a mode is set (1), reset (2) or not recognised (0).
+    fn report_mode(&mut self) {
+        let private = self.csi.private.is_some();
+        let mode = self.csi.arg(0, 0);
+        let state = match self.mode_state(private, mode) {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+        let reply = format!(
+            "\x1b[{}{};{}$y",
+            if private { "?" } else { "" },
+            mode,
+            state
+        );
+        self.tty_write(reply.as_bytes(), false);
+    }
+
+    /// Applies SGR parameters to the attributes of the cursor.
+    fn set_attributes(&mut self) {
+        let (args, subargs) = if self.csi.args.is_empty() {
+            (vec![0], vec![Vec::new()])
+        } else {
+            (self.csi.args.clone(), self.csi.subargs.clone())
+        };
+        let attr = &mut self.cursor.attr;
+        // Colours are given either in the following arguments, as `38;2;r;g;b`, or in
+        // sub-parameters, as `38:2::r:g:b`.
+        let color = |i: &mut usize| match subargs.get(*i) {
+            Some(subargs) if !subargs.is_empty() => subargs_color(subargs),
+            _ => extended_color(&args, i),
+        };
+
+        let mut i = 0;
+        while i < args.len() {
+            match args[i] {
+                0 => {
+                    attr.mode.remove(
+                        GlyphAttribute::BOLD
+                            | GlyphAttribute::FAINT
+                            | GlyphAttribute::ITALIC
+                            | GlyphAttribute::BLINK
+                            | GlyphAttribute::REVERSE
+                            | GlyphAttribute::INVISIBLE
+                            | GlyphAttribute::STRUCK,
+                    );
+                    attr.foreground = config::DEFAULT_FOREGROUND;
+                    attr.background = config::DEFAULT_BACKGROUND;
+                    attr.underline = UnderlineStyle::None;
+                    attr.underline_color = None;
+                }
+                1 => attr.mode.insert(GlyphAttribute::BOLD),
+                2 => attr.mode.insert(GlyphAttribute::FAINT),
+                3 => attr.mode.insert(GlyphAttribute::ITALIC),
+                4 => {
+                    attr.underline = match subargs.get(i).and_then(|sub| sub.first()) {
+                        None | Some(1) => UnderlineStyle::Single,
+                        Some(2) => UnderlineStyle::Double,
+                        Some(3) => UnderlineStyle::Curly,
+                        Some(4) => UnderlineStyle::Dotted,
+                        Some(5) => UnderlineStyle::Dashed,
+                        Some(_) => UnderlineStyle::None,
+                    }
+                }
+                5 | 6 => attr.mode.insert(GlyphAttribute::BLINK),
+                7 => attr.mode.insert(GlyphAttribute::REVERSE),
+                8 => attr.mode.insert(GlyphAttribute::INVISIBLE),
+                9 => attr.mode.insert(GlyphAttribute::STRUCK),
+                21 => attr.underline = UnderlineStyle::Double,
+                22 => attr
+                    .mode
+                    .remove(GlyphAttribute::BOLD | GlyphAttribute::FAINT),
+                23 => attr.mode.remove(GlyphAttribute::ITALIC),
+                24 => attr.underline = UnderlineStyle::None,
+                25 => attr.mode.remove(GlyphAttribute::BLINK),
+                27 => attr.mode.remove(GlyphAttribute::REVERSE),
+                28 => attr.mode.remove(GlyphAttribute::INVISIBLE),
+                29 => attr.mode.remove(GlyphAttribute::STRUCK),
+                38 => {
+                    if let Some(color) = color(&mut i) {
+                        attr.foreground = color;
+                    }
+                }
+                39 => attr.foreground = config::DEFAULT_FOREGROUND,
+                48 => {
+                    if let Some(color) = color(&mut i) {
+                        attr.background = color;
+                    }
+                }
+                49 => attr.background = config::DEFAULT_BACKGROUND,
+                58 => {
+                    if let Some(color) = color(&mut i) {
+                        attr.underline_color = Some(color);
+                    }
+                }
+                59 => attr.underline_color = None,
+                arg @ 30..=37 => attr.foreground = (arg - 30) as u32,
+                arg @ 40..=47 => attr.background = (arg - 40) as u32,
+                arg @ 90..=97 => attr.foreground = (arg - 90 + 8) as u32,
+                arg @ 100..=107 => attr.background = (arg - 100 + 8) as u32,
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    /// Moves the cursor `n` tab stops forward, or backward when negative.
+    fn put_tab(&mut self, n: i64) {
+        let mut x = self.cursor.x;
+        if n > 0 {
+            for _ in 0..n {
+                x += 1;
+                while x < self.line_cols(self.cursor.y) && !self.tabs[x] {
+                    x += 1;
+                }
+            }
+        } else {
+            for _ in 0..-n {
+                x = x.saturating_sub(1);
+                while x > 0 && !self.tabs[x] {
+                    x -= 1;
+                }
+            }
+        }
+        self.move_to(x, self.cursor.y);
+    }
+
+    /// Erases part of the display, as ED, or as DECSED when `selective`.
+    fn erase_display(&mut self, mode: i64, selective: bool) {
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let (last_col, last_row) = (self.cols - 1, self.rows - 1);
+        match mode {
+            0 => {
+                self.erase_region(x, y, last_col, y, selective);
+                if y < last_row {
+                    self.erase_region(0, y + 1, last_col, last_row, selective);
+                }
+            }
+            1 => {
+                if y > 0 {
+                    self.erase_region(0, 0, last_col, y - 1, selective);
+                }
+                self.erase_region(0, y, x, y, selective);
+            }
+            2 => self.erase_region(0, 0, last_col, last_row, selective),
+            3 if !selective => {
+                self.history.clear();
+                self.scroll_offset = 0;
+                self.set_dirty_all();
+            }
+            _ => {}
+        }
+
+        // Lines erased as a whole are single width again.
+        let whole_lines = match mode {
+            0 if x == 0 => y..self.rows,
+            0 => y + 1..self.rows,
+            1 if x + 1 >= self.line_cols(y) => 0..y + 1,
+            1 => 0..y,
+            2 => 0..self.rows,
+            _ => 0..0,
+        };
+        if !selective {
+            for line in &mut self.lines[whole_lines] {
+                line.size = LineSize::Single;
+            }
+        }
+    }
+
+    /// Erases part of the cursor's line, as EL, or as DECSEL when `selective`.
+    fn erase_line(&mut self, mode: i64, selective: bool) {
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        match mode {
+            0 => self.erase_region(x, y, self.cols - 1, y, selective),
+            1 => self.erase_region(0, y, x, y, selective),
+            2 => self.erase_region(0, y, self.cols - 1, y, selective),
+            _ => {}
+        }
+    }
+
+    /// Reads a rectangle given as top, left, bottom and right from argument `first` on, as
+    /// inclusive rows and columns clamped to the screen, where rows count from the top of the
+    /// scroll region in origin mode. Returns `None` when it is empty, where a `stream` of
+    /// characters in reading order may run to a column left of where it started.
+    fn csi_rectangle(&self, first: usize, stream: bool) -> Option<(usize, usize, usize, usize)> {
+        let (min_y, max_y) = self.addressable_rows();
+        let arg =
+            |n: usize, default: usize| self.csi.arg(first + n, default as i64).max(1) as usize;
+        let top = (min_y + arg(0, 1) - 1).min(max_y);
+        let left = (arg(1, 1) - 1).min(self.cols - 1);
+        let bottom = (min_y + arg(2, max_y - min_y + 1) - 1).min(max_y);
+        let right = (arg(3, self.cols) - 1).min(self.cols - 1);
+        let valid = top <= bottom && (left <= right || (stream && top < bottom));
+        valid.then_some((top, left, bottom, right))
+    }
+
+    /// First and last rows cursor addressing reaches, which is the scroll region in origin mode.
+    fn addressable_rows(&self) -> (usize, usize) {
+        if self.cursor.origin {
+            (self.top, self.bottom)
+        } else {
+            (0, self.rows - 1)
+        }
+    }
+
+    /// Copies a rectangle of the screen to another position (DECCRA). Pages are ignored as
+    /// there is only the one.
+    fn copy_rectangle(&mut self) {
+        let Some((top, left, bottom, right)) = self.csi_rectangle(0, false) else {
+            return;
+        };
+        let (min_y, max_y) = self.addressable_rows();
+        let to_top = (min_y + self.csi.arg(5, 1).max(1) as usize - 1).min(max_y);
+        let to_left = (self.csi.arg(6, 1).max(1) as usize - 1).min(self.cols - 1);
+        let rows = (bottom - top + 1).min(max_y - to_top + 1);
+        let cols = (right - left + 1).min(self.cols - to_left);
+
+        let source: Vec<Vec<Glyph>> = self.lines[top..top + rows]
+            .iter()
+            .map(|line| line.glyphs[left..left + cols].to_vec())
+            .collect();
+        for (row, glyphs) in source.into_iter().enumerate() {
+            let line = &mut self.lines[to_top + row];
+            line.glyphs[to_left..to_left + cols].copy_from_slice(&glyphs);
+            line.remove_images(to_left, to_left + cols - 1);
+        }
+        self.set_dirty(to_top, to_top + rows - 1);
+    }
+
+    /// Fills a rectangle with a character in the current attributes (DECFRA).
+    fn fill_rectangle(&mut self) {
+        let c = match char::from_u32(self.csi.arg(0, 0) as u32) {
+            Some(c @ (' '..='~' | '\u{a0}'..='\u{ff}')) => c,
+            _ => return,
+        };
+        let Some((top, left, bottom, right)) = self.csi_rectangle(1, false) else {
+            return;
+        };
+        let glyph = Glyph {
+            character: c,
+            ..self.cursor.attr
+        };
+        for line in &mut self.lines[top..=bottom] {
+            line.glyphs[left..=right].fill(glyph);
+            line.remove_images(left, right);
+        }
+        self.set_dirty(top, bottom);
+    }
+
+    /// Changes (DECCARA) or reverses (DECRARA) attributes in a rectangle, or in the characters
+    /// from its top left to its bottom right corner in reading order unless DECSACE chose the
+    /// rectangle.
+    fn change_rectangle_attributes(&mut self, reverse: bool) {
+        let stream = !self.mode.contains(TermMode::RECTANGLE_EXTENT);
+        let Some((top, left, bottom, right)) = self.csi_rectangle(0, stream) else {
+            return;
+        };
+        let params = match self.csi.args.get(4..) {
+            Some(params) if !params.is_empty() => params.to_vec(),
+            _ => vec![0],
+        };
+        for y in top..=bottom {
+            let (mut first, mut last) = (left, right);
+            if stream {
+                first = if y == top { left } else { 0 };
+                last = if y == bottom { right } else { self.cols - 1 };
+            }
+            for glyph in &mut self.lines[y].glyphs[first..=last] {
+                for &param in &params {
+                    change_attribute(glyph, param, reverse);
+                }
+            }
+        }
+        self.set_dirty(top, bottom);
+    }
+
+    fn insert_blank(&mut self, n: usize) {
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let n = n.min(self.cols - x);
+        let blank = self.cursor.attr_blank();
+        self.lines[y].remove_images(x, self.cols - 1);
+        let glyphs = &mut self.lines[y].glyphs[x..];
+        glyphs.rotate_right(n);
+        glyphs[..n].fill(blank);
+        self.set_dirty(y, y);
+    }
+
+    fn delete_char(&mut self, n: usize) {
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        let n = n.min(self.cols - x);
+        let blank = self.cursor.attr_blank();
+        self.lines[y].remove_images(x, self.cols - 1);
+        let glyphs = &mut self.lines[y].glyphs[x..];
+        glyphs.rotate_left(n);
+        let len = glyphs.len();
+        glyphs[len - n..].fill(blank);
+        self.set_dirty(y, y);
+    }
+
+    fn insert_blank_line(&mut self, n: usize) {
+        if (self.top..=self.bottom).contains(&self.cursor.y) {
+            self.scroll_down(self.cursor.y, n);
+        }
+    }
+
+    fn delete_line(&mut self, n: usize) {
+        if (self.top..=self.bottom).contains(&self.cursor.y) {
+            self.scroll_up(self.cursor.y, n);
+        }
+    }
+}
+
+fn default_tabs(cols: usize) -> Vec<bool> {
+    (0..cols)
+        .map(|col| col > 0 && col % config::TAB_SPACES == 0)
+        .collect()
+}
+
+/// Whether a character is a C0 or C1 control.
+fn is_control(c: char) -> bool {
+    c < ' ' || c == '\x7f' || ('\u{80}'..='\u{9f}').contains(&c)
+}
+
+/// Reads an SGR 38, 48 or 58 colour, either `5;index` or `2;red;green;blue`, advancing `i` past its
+/// arguments.
+fn extended_color(args: &[i64], i: &mut usize) -> Option<u32> {
+    match args.get(*i + 1) {
+        Some(2) => {
+            let rgb = args.get(*i + 2..*i + 5)?;
+            *i += 4;
+            let channel = |value: i64| value.clamp(0, 255) as u8;
+            Some(true_color(
+                channel(rgb[0]),
+                channel(rgb[1]),
+                channel(rgb[2]),
+            ))
+        }
+        Some(5) => {
+            let index = *args.get(*i + 2)?;
+            *i += 2;
+            (0..=255).contains(&index).then_some(index as u32)
+        }
+        _ => None,
+    }
+}
+
+/// Applies an SGR parameter of DECCARA to a glyph, or toggles it for DECRARA. Only bold,
+/// underline, blink and reverse can be changed, and DECRARA can't reset them.
+fn change_attribute(glyph: &mut Glyph, param: i64, reverse: bool) {
+    let (flags, underline) = match param {
+        0 => (
+            GlyphAttribute::BOLD | GlyphAttribute::BLINK | GlyphAttribute::REVERSE,
+            true,
+        ),
+        1 | 22 => (GlyphAttribute::BOLD, false),
+        4 | 24 => (GlyphAttribute::empty(), true),
+        5 | 25 => (GlyphAttribute::BLINK, false),
+        7 | 27 => (GlyphAttribute::REVERSE, false),
+        _ => return,
+    };
+    let set = if reverse {
+        if param >= 22 {
+            return;
+        }
+        glyph.mode.toggle(flags);
+        glyph.underline == UnderlineStyle::None
+    } else {
+        let set = param != 0 && param < 22;
+        glyph.mode.set(flags, set);
+        set
+    };
+    if underline {
+        glyph.underline = if set {
+            UnderlineStyle::Single
+        } else {
+            UnderlineStyle::None
+        };
+    }
+}
+
+/// Reads a colour given in sub-parameters, as `2:colorspace:r:g:b`, `2:r:g:b` or `5:index`.
+fn subargs_color(subargs: &[i64]) -> Option<u32> {
+    let channel = |value: i64| value.clamp(0, 255) as u8;
+    match *subargs {
+        [2, _, r, g, b, ..] | [2, r, g, b] => Some(true_color(channel(r), channel(g), channel(b))),
+        [5, index, ..] => (0..=255).contains(&index).then_some(index as u32),
+        _ => None,
+    }
+}
+
+/// Decodes `%XX` escapes in a URL path. A `%` not followed by two hex digits is kept as it is.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(c: char) -> Line {
+        let glyph = Glyph {
+            character: c,
+            ..Glyph::default()
+        };
+        Line::new(4, glyph)
+    }
+
+    fn history_text(history: &History) -> String {
+        (0..history.len())
+            .map(|index| history.get(index).unwrap().glyphs[0].character)
+            .collect()
+    }
+
+    #[test]
+    fn history_drops_oldest_lines_past_the_limit() {
+        let mut history = History::new(3, usize::MAX);
+        for c in "abcde".chars() {
+            history.push(line(c));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history_text(&history), "cde");
+        assert_eq!(history.first_line_number(), 2);
+        assert!(history.get(3).is_none());
+    }
+
+    #[test]
+    fn history_drops_oldest_lines_past_the_memory_limit() {
+        let size = line('a').memory_size();
+        let mut history = History::new(100, 2 * size);
+        for c in "abcd".chars() {
+            history.push(line(c));
+        }
+        assert_eq!(history_text(&history), "cd");
+        assert_eq!(history.memory_size(), 2 * size);
+        assert_eq!(history.first_line_number(), 2);
+    }
+
+    #[test]
+    fn history_without_lines_keeps_counting() {
+        let mut history = History::new(0, usize::MAX);
+        history.push(line('a'));
+        history.push(line('b'));
+        assert!(history.is_empty());
+        assert_eq!(history.first_line_number(), 2);
+    }
+
+    #[test]
+    fn history_indexes_from_the_oldest_line() {
+        let mut history = History::new(2, usize::MAX);
+        for c in "abc".chars() {
+            history.push(line(c));
+        }
+        history.get_mut(1).unwrap().glyphs[0].character = 'x';
+        assert_eq!(history_text(&history), "bx");
+
+        history.clear();
+        assert!(history.is_empty());
+        assert_eq!(history.memory_size(), 0);
+        assert_eq!(history.first_line_number(), 3);
+    }
+
+    #[test]
+    fn scrolling_fills_the_history() {
+        let mut term = Term::new(4, 2);
+        term.write(b"a\r\nb\r\nc\r\nd");
+        assert_eq!(term.history.len(), 2);
+        assert_eq!(term.history.get(0).unwrap().text(), "a");
+        assert_eq!(term.history.get(1).unwrap().text(), "b");
+        assert_eq!(term.line_by_number(3).unwrap().text(), "d");
+    }
+
+    #[test]
+    fn view_survives_an_offset_past_the_history() {
+        let mut term = Term::new(4, 2);
+        term.write(b"a\r\nb\r\nc");
+        term.scroll_view_up(1);
+        assert_eq!(term.view_line(0).text(), "a");
+
+        // As if the history had dropped lines under the view.
+        term.scroll_offset = 5;
+        assert_eq!(term.view_line(0).text(), "a");
+        assert_eq!(term.view_line(1).text(), "b");
+        assert_eq!(term.view_line_number(0), 0);
+
+        term.write(b"\r\nd");
+        assert!(term.scroll_offset <= term.history.len());
+        assert_eq!(term.view_line(0).text(), "a");
+    }
+
+    #[test]
+    fn shrinking_on_the_alternate_screen_keeps_primary_lines() {
+        let mut term = Term::new(4, 4);
+        term.write(b"a\r\nb\r\nc\r\nd\x1b[?1049h\x1b[4;1Hz");
+        term.resize(4, 2);
+        assert_eq!(term.history.len(), 2);
+        assert_eq!(term.history.get(0).unwrap().text(), "a");
+        assert_eq!(term.history.get(1).unwrap().text(), "b");
+        assert_eq!(term.lines[1].text(), "z");
+
+        term.write(b"\x1b[?1049l");
+        assert_eq!(term.lines[0].text(), "c");
+        assert_eq!(term.lines[1].text(), "d");
+    }
+
+    /// Takes what the terminal has written back to the child.
+    fn reply(term: &mut Term) -> String {
+        String::from_utf8(std::mem::take(&mut term.tty_buffer)).unwrap()
+    }
+
+    #[test]
+    fn sets_and_resets_private_modes() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[?1;2004h\x1b[?25l");
+        assert!(term.window_mode.contains(WindowMode::APPCURSOR));
+        assert!(term.window_mode.contains(WindowMode::HIDE));
+        assert!(term.window_mode.contains(WindowMode::BRCKTPASTE));
+
+        term.write(b"\x1b[?1l\x1b[?25h\x1b[?7l");
+        assert!(!term.window_mode.contains(WindowMode::APPCURSOR));
+        assert!(!term.window_mode.contains(WindowMode::HIDE));
+        assert!(!term.mode.contains(TermMode::WRAP));
+    }
+
+    #[test]
+    fn sets_and_resets_ansi_modes() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[4;20h");
+        assert!(term.mode.contains(TermMode::INSERT));
+        assert!(term.mode.contains(TermMode::CRLF));
+        // SRM turns local echo off when set.
+        term.write(b"\x1b[12l");
+        assert!(term.mode.contains(TermMode::ECHO));
+        term.write(b"\x1b[4l\x1b[12h");
+        assert!(!term.mode.contains(TermMode::INSERT));
+        assert!(!term.mode.contains(TermMode::ECHO));
+    }
+
+    #[test]
+    fn mouse_modes_replace_each_other() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[?1000h\x1b[?1002h");
+        assert_eq!(
+            term.window_mode & WindowMode::MOUSE,
+            WindowMode::MOUSEMOTION
+        );
+        term.write(b"\x1b[?1006h\x1b[?1015h");
+        assert_eq!(
+            term.window_mode & WindowMode::MOUSE_ENCODING,
+            WindowMode::MOUSEURXVT
+        );
+        term.write(b"\x1b[?1015l");
+        assert!((term.window_mode & WindowMode::MOUSE_ENCODING).is_empty());
+    }
+
+    #[test]
+    fn parses_sequences_split_across_writes() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b");
+        term.write(b"[?2");
+        term.write(b"5l");
+        assert!(term.window_mode.contains(WindowMode::HIDE));
+        term.write("é\u{1b}[2C".as_bytes()[..1].as_ref());
+        term.write("é\u{1b}[2C".as_bytes()[1..].as_ref());
+        assert_eq!(term.lines[0].glyphs[0].character, 'é');
+        assert_eq!(term.cursor.x, 3);
+    }
+
+    #[test]
+    fn reports_modes() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[?2026$p\x1b[?7$p\x1b[4$p\x1b[?9999$p\x1b[9999$p");
+        assert_eq!(
+            reply(&mut term),
+            "\x1b[?2026;2$y\x1b[?7;1$y\x1b[4;2$y\x1b[?9999;0$y\x1b[9999;0$y"
+        );
+
+        term.write(b"\x1b[?2026h\x1b[?2026$p");
+        assert_eq!(reply(&mut term), "\x1b[?2026;1$y");
+        assert_eq!(term.mode_state(true, 2026), Some(true));
+        assert_eq!(term.mode_state(true, 1049), Some(false));
+        assert_eq!(term.mode_state(false, 20), Some(false));
+        assert_eq!(term.mode_state(true, 12345), None);
+    }
+
+    #[test]
+    fn synchronized_updates_hold_back_drawing() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[?2026h");
+        assert!(term.in_synchronized_update(Duration::from_secs(60)));
+        term.write(b"\x1b[?2026l");
+        assert!(!term.in_synchronized_update(Duration::from_secs(60)));
+
+        // An update left open for too long is ended.
+        term.write(b"\x1b[?2026h");
+        assert!(!term.in_synchronized_update(Duration::ZERO));
+        assert!(!term.mode.contains(TermMode::SYNC));
+    }
+
+    fn text(term: &Term, y: usize) -> String {
+        term.lines[y]
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.character)
+            .collect()
+    }
+
+    #[test]
+    fn full_reset_starts_over() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[?1h\x1b[4h\x1b[?25l\x1b[2;3r\x1b[3g\x1b[5 q\x1b(0\x1b[1mhello");
+        term.write(b"\x1b]0;title\x07");
+        term.events.clear();
+        term.write(b"\x1bc");
+
+        assert!(!term.window_mode.contains(WindowMode::APPCURSOR));
+        assert!(!term.window_mode.contains(WindowMode::HIDE));
+        assert!(!term.mode.contains(TermMode::INSERT));
+        assert!(term.mode.contains(TermMode::WRAP));
+        assert_eq!((term.top, term.bottom), (0, 3));
+        assert_eq!(term.tabs, default_tabs(10));
+        assert_eq!(term.cursor_style, config::CURSOR_STYLE);
+        assert_eq!(term.cursor.charsets, [Charset::Ascii; 2]);
+        assert_eq!(term.cursor.attr, Glyph::default());
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+        assert_eq!(term.title, None);
+        assert_eq!(term.events, [TermEvent::TitleChanged]);
+        assert!((0..4).all(|y| text(&term, y).trim().is_empty()));
+    }
+
+    #[test]
+    fn full_reset_keeps_the_history() {
+        let mut term = Term::new(10, 2);
+        term.write(b"one\r\ntwo\r\nthree");
+        term.write(b"\x1bc");
+        assert_eq!(history_text(&term.history), "o");
+    }
+
+    #[test]
+    fn soft_reset_keeps_the_screen() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b[3g\x1b[?1h\x1b[4h\x1b[?25l\x1b[?7l\x1b[2;3r\x1b[?6h\x1b(0\x1b[1m");
+        term.write(b"\x1b[2;5Hqq\x1b7");
+        term.write(b"\x1b[!p");
+
+        assert!(!term.window_mode.contains(WindowMode::APPCURSOR));
+        assert!(!term.window_mode.contains(WindowMode::HIDE));
+        assert!(!term.mode.contains(TermMode::INSERT));
+        assert!(term.mode.contains(TermMode::WRAP));
+        assert!(!term.cursor.origin);
+        assert_eq!((term.top, term.bottom), (0, 3));
+        assert_eq!(term.cursor.attr, Glyph::default());
+        assert_eq!(term.cursor.charsets, [Charset::Ascii; 2]);
+        // The screen, the cursor position and the tab stops stay.
+        assert_eq!(text(&term, 2), "    ──    ");
+        assert_eq!((term.cursor.x, term.cursor.y), (6, 2));
+        assert_eq!(term.tabs, vec![false; 10]);
+        // The saved cursor goes back to the top left.
+        term.write(b"\x1b8");
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+    }
+
+    #[test]
+    fn alignment_test_fills_the_screen() {
+        let mut term = Term::new(4, 3);
+        term.write(b"\x1b[2;3r\x1b[?6h\x1b#6\x1b[2;2H\x1b[31m\x1b#8");
+        for y in 0..3 {
+            assert_eq!(text(&term, y), "EEEE");
+            assert_eq!(term.lines[y].size, LineSize::Single);
+        }
+        assert!(term.lines[0]
+            .glyphs
+            .iter()
+            .all(|glyph| glyph.foreground == Glyph::default().foreground));
+        assert_eq!((term.top, term.bottom), (0, 2));
+        assert!(!term.cursor.origin);
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+    }
+
+    #[test]
+    fn designates_character_sets() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b(0lqk\x1b(Bq");
+        assert_eq!(text(&term, 0), "┌─┐q      ");
+
+        // SO and SI switch between G0 and G1.
+        term.write(b"\r\n\x1b)0a\x0eqx\x0fq");
+        assert_eq!(text(&term, 1), "a─│q      ");
+
+        term.write(b"\r\n\x1b(A#\x1b(B#\x1b(Z#");
+        assert_eq!(text(&term, 2), "£##       ");
+    }
+
+    #[test]
+    fn saves_character_sets_with_the_cursor() {
+        let mut term = Term::new(10, 4);
+        term.write(b"\x1b(0\x1b7\x1b(Bq\x1b8q");
+        assert_eq!(text(&term, 0), "─         ");
+    }
+
+    /// A screen 6 columns wide with a different letter in each cell.
+    fn letters() -> Term {
+        let mut term = Term::new(6, 4);
+        term.write(b"abcdef\r\nghijkl\r\nmnopqr\r\nstuvwx\x1b[H");
+        term
+    }
+
+    fn screen(term: &Term) -> Vec<String> {
+        (0..term.rows).map(|y| text(term, y)).collect()
+    }
+
+    /// Cells of a row with `flag` set, as `#`, or `.` for the rest.
+    fn marked(term: &Term, y: usize, flag: GlyphAttribute) -> String {
+        let glyphs = &term.lines[y].glyphs;
+        glyphs
+            .iter()
+            .map(|glyph| if glyph.mode.contains(flag) { '#' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn copies_rectangles() {
+        let mut term = letters();
+        term.write(b"\x1b[1;1;2;2;1;3;4$v");
+        assert_eq!(screen(&term), ["abcdef", "ghijkl", "mnoabr", "stughx"]);
+
+        // Overlapping areas are copied as they were before.
+        let mut term = letters();
+        term.write(b"\x1b[1;1;2;6;1;2;2$v");
+        assert_eq!(screen(&term), ["abcdef", "gabcde", "mghijk", "stuvwx"]);
+    }
+
+    #[test]
+    fn copies_rectangles_cut_off_at_the_edge() {
+        let mut term = letters();
+        term.write(b"\x1b[1;1;2;3;1;4;5$v");
+        assert_eq!(screen(&term), ["abcdef", "ghijkl", "mnopqr", "stuvab"]);
+
+        let mut term = letters();
+        term.write(b"\x1b[1;1;999;999;1;999;999$v");
+        assert_eq!(screen(&term), ["abcdef", "ghijkl", "mnopqr", "stuvwa"]);
+        // A rectangle with its corners the wrong way round is empty.
+        term.write(b"\x1b[3;3;1;1;1;1;1$v");
+        assert_eq!(screen(&term), ["abcdef", "ghijkl", "mnopqr", "stuvwa"]);
+    }
+
+    #[test]
+    fn fills_rectangles() {
+        let mut term = letters();
+        term.write(b"\x1b[1m\x1b[35;1;2;3;3$x");
+        assert_eq!(screen(&term), ["a##def", "g##jkl", "m##pqr", "stuvwx"]);
+        assert_eq!(marked(&term, 1, GlyphAttribute::BOLD), ".##...");
+
+        // Control characters can't fill, and the rectangle stops at the edge of the screen.
+        term.write(b"\x1b[10$x\x1b[42;3;5;99;99$x");
+        assert_eq!(screen(&term), ["a##def", "g##jkl", "m##p**", "stuv**"]);
+    }
+
+    #[test]
+    fn erases_rectangles() {
+        let mut term = letters();
+        term.write(b"\x1b[2;2;3;4$z");
+        assert_eq!(screen(&term), ["abcdef", "g   kl", "m   qr", "stuvwx"]);
+        // Without parameters the whole screen is erased.
+        term.write(b"\x1b[$z");
+        assert!(screen(&term).iter().all(|row| row.trim().is_empty()));
+    }
+
+    #[test]
+    fn selective_erase_spares_protected_cells() {
+        let mut term = letters();
+        term.write(b"\x1b[1\"qAB\x1b[0\"qC");
+        term.write(b"\x1b[1;1;2;6${");
+        assert_eq!(screen(&term), ["AB    ", "      ", "mnopqr", "stuvwx"]);
+        // A rectangular erase that isn't selective clears them too.
+        term.write(b"\x1b[1;1;1;6$z");
+        assert_eq!(text(&term, 0), "      ");
+    }
+
+    #[test]
+    fn changes_attributes_in_reading_order() {
+        let mut term = letters();
+        term.write(b"\x1b[2;5;3;2;1;4$r");
+        assert_eq!(marked(&term, 0, GlyphAttribute::BOLD), "......");
+        assert_eq!(marked(&term, 1, GlyphAttribute::BOLD), "....##");
+        assert_eq!(marked(&term, 2, GlyphAttribute::BOLD), "##....");
+        assert_eq!(term.lines[1].glyphs[4].underline, UnderlineStyle::Single);
+        assert_eq!(term.lines[1].glyphs[3].underline, UnderlineStyle::None);
+
+        // SGR 0 and the resetting parameters take them off again.
+        term.write(b"\x1b[2;1;3;6;22$r");
+        assert_eq!(marked(&term, 1, GlyphAttribute::BOLD), "......");
+        assert_eq!(term.lines[1].glyphs[4].underline, UnderlineStyle::Single);
+        term.write(b"\x1b[2;1;3;6$r");
+        assert_eq!(term.lines[1].glyphs[4].underline, UnderlineStyle::None);
+    }
+
+    #[test]
+    fn changes_attributes_in_a_rectangle_after_decsace() {
+        let mut term = letters();
+        term.write(b"\x1b[2*x\x1b[2;2;3;3;5$r");
+        assert_eq!(marked(&term, 1, GlyphAttribute::BLINK), ".##...");
+        assert_eq!(marked(&term, 2, GlyphAttribute::BLINK), ".##...");
+        // The corners the wrong way round make an empty rectangle here.
+        term.write(b"\x1b[2;5;3;2;5$r");
+        assert_eq!(marked(&term, 1, GlyphAttribute::BLINK), ".##...");
+
+        // Back to reading order.
+        term.write(b"\x1b[1*x\x1b[1;5;2;2;5$r");
+        assert_eq!(marked(&term, 0, GlyphAttribute::BLINK), "....##");
+        assert_eq!(marked(&term, 1, GlyphAttribute::BLINK), "###...");
+    }
+
+    #[test]
+    fn reverses_attributes() {
+        let mut term = letters();
+        term.write(b"\x1b[2*x\x1b[1;1;1;2;7$t\x1b[1;2;1;3;7$t");
+        assert_eq!(marked(&term, 0, GlyphAttribute::REVERSE), "#.#...");
+        // Resetting parameters do nothing here.
+        term.write(b"\x1b[1;1;1;6;27$t");
+        assert_eq!(marked(&term, 0, GlyphAttribute::REVERSE), "#.#...");
+        term.write(b"\x1b[1;1;1;2;4$t");
+        assert_eq!(term.lines[0].glyphs[0].underline, UnderlineStyle::Single);
+        term.write(b"\x1b[1;1;1;1;4$t");
+        assert_eq!(term.lines[0].glyphs[0].underline, UnderlineStyle::None);
+    }
+
+    #[test]
+    fn rectangles_are_relative_to_the_margins_in_origin_mode() {
+        let mut term = letters();
+        term.write(b"\x1b[2;3r\x1b[?6h\x1b[1;1;1;6$z");
+        assert_eq!(screen(&term), ["abcdef", "      ", "mnopqr", "stuvwx"]);
+
+        // Rows past the scroll region are cut off.
+        let mut term = letters();
+        term.write(b"\x1b[2;3r\x1b[?6h\x1b[46;1;1;9;2$x");
+        assert_eq!(screen(&term), ["abcdef", "..ijkl", "..opqr", "stuvwx"]);
+
+        let mut term = letters();
+        term.write(b"\x1b[2;3r\x1b[?6h\x1b[1;1;2;2;1;2;5$v");
+        assert_eq!(screen(&term), ["abcdef", "ghijkl", "mnopgh", "stuvwx"]);
+    }
+
+    /// A terminal printing into files in a directory of its own, one for each job.
+    fn printing(name: &str) -> (Term, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("tt-print-{}-{}", std::process::id(), name));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // Jobs are renamed once done, so none is read while still being written.
+        let script = format!(
+            "cat > '{0}/.job.'$$ && mv '{0}/.job.'$$ '{0}/job.'$$",
+            dir.display()
+        );
+        let mut term = Term::new(10, 3);
+        term.printer = Printer::new(&["sh", "-c", &script]);
+        (term, dir)
+    }
+
+    /// Waits for `jobs` print jobs to finish, returning what they printed in sorted order.
+    fn printed(dir: &std::path::Path, jobs: usize) -> Vec<String> {
+        for _ in 0..500 {
+            let mut printed: Vec<String> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with("job.")
+                })
+                .map(|path| std::fs::read_to_string(path).unwrap())
+                .collect();
+            if printed.len() >= jobs {
+                std::fs::remove_dir_all(dir).unwrap();
+                printed.sort();
+                return printed;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("print jobs didn't finish");
+    }
+
+    #[test]
+    fn prints_the_screen() {
+        let (mut term, dir) = printing("screen");
+        term.write(b"one\r\ntwo\x1b[i");
+        assert_eq!(printed(&dir, 1), ["one\ntwo\n\n"]);
+    }
+
+    #[test]
+    fn prints_the_scroll_region_unless_asked_for_the_screen() {
+        let (mut term, dir) = printing("region");
+        term.write(b"one\r\ntwo\x1b[2;3r\x1b[?18h\x1b[i");
+        assert_eq!(printed(&dir, 1), ["two\n\n\x0c"]);
+
+        let (mut term, dir) = printing("extent");
+        term.write(b"one\r\ntwo\x1b[2;3r\x1b[?19h\x1b[i");
+        assert_eq!(printed(&dir, 1), ["one\ntwo\n\n"]);
+    }
+
+    #[test]
+    fn prints_the_cursor_line() {
+        let (mut term, dir) = printing("line");
+        term.write(b"one\r\ntwo\x1b[1i\x1b[A\x1b[?1i");
+        assert_eq!(printed(&dir, 2), ["one\n", "two\n"]);
+    }
+
+    #[test]
+    fn passes_everything_to_the_printer_in_controller_mode() {
+        let (mut term, dir) = printing("controller");
+        term.write(b"\x1b[5iabc\x1b[4\x1b[4ixyz");
+        assert!(!term.mode.contains(TermMode::PRINT));
+        assert_eq!(text(&term, 0), "xyz       ");
+        assert_eq!(printed(&dir, 1), ["abc\x1b[4"]);
+    }
+
+    #[test]
+    fn auto_prints_lines_as_the_cursor_leaves_them() {
+        let (mut term, dir) = printing("auto");
+        term.write(b"\x1b[?5ione\r\ntwo\r\n\x1b[?4i");
+        assert_eq!(text(&term, 1), "two       ");
+        assert_eq!(printed(&dir, 1), ["one\ntwo\n"]);
+    }
+
+    #[test]
+    fn marks_wraps_on_the_last_column_of_double_width_lines() {
+        let mut term = Term::new(10, 3);
+        term.write(b"\x1b#6hello world");
+        assert_eq!(term.lines[0].cols(), 5);
+        assert!(term.lines[0].is_wrapped());
+        assert!(!term.lines[1].is_wrapped());
+        assert_eq!(text(&term, 1), " world    ");
+
+        // A line made double width after wrapping no longer reaches the mark.
+        let mut term = Term::new(10, 3);
+        term.write(b"0123456789ab\x1b[H\x1b#6");
+        assert!(!term.lines[0].is_wrapped());
+        term.write(b"\x1b#5");
+        assert!(!term.lines[0].is_wrapped());
+    }
+
+    #[test]
+    fn moves_the_cursor_in_vt52_mode() {
+        let mut term = Term::new(10, 5);
+        term.write(b"\x1b[?2l");
+        assert!(term.window_mode.contains(WindowMode::VT52));
+
+        // ESC Y takes the row and then the column, each offset by 32.
+        term.write(b"\x1bY\"#");
+        assert_eq!((term.cursor.x, term.cursor.y), (3, 2));
+        term.write(b"\x1bA\x1bC\x1bC");
+        assert_eq!((term.cursor.x, term.cursor.y), (5, 1));
+        term.write(b"\x1bB\x1bD");
+        assert_eq!((term.cursor.x, term.cursor.y), (4, 2));
+        term.write(b"\x1bY~~");
+        assert_eq!((term.cursor.x, term.cursor.y), (9, 4));
+        term.write(b"\x1bH");
+        assert_eq!((term.cursor.x, term.cursor.y), (0, 0));
+    }
+
+    #[test]
+    fn edits_the_screen_in_vt52_mode() {
+        let mut term = Term::new(4, 3);
+        term.write(b"abcd\r\nefgh\r\nijkl\x1b[?2l");
+
+        term.write(b"\x1bY!!\x1bK");
+        assert_eq!(text(&term, 1), "e   ");
+        term.write(b"\x1bY !\x1bJ");
+        assert_eq!(text(&term, 0), "a   ");
+        assert_eq!(text(&term, 1), "    ");
+        assert_eq!(text(&term, 2), "    ");
+
+        // Reverse line feed scrolls down at the top of the screen.
+        term.write(b"\x1bH\x1bI");
+        assert_eq!(text(&term, 0), "    ");
+        assert_eq!(text(&term, 1), "a   ");
+    }
+
+    #[test]
+    fn draws_graphics_in_vt52_mode() {
+        let mut term = Term::new(10, 3);
+        term.write(b"\x1b[?2l\x1bFqx\x1bGq");
+        assert_eq!(text(&term, 0), "─│q       ");
+    }
+
+    #[test]
+    fn identifies_and_leaves_vt52_mode() {
+        let mut term = Term::new(10, 3);
+        term.write(b"\x1b[?2l\x1bZ");
+        assert_eq!(reply(&mut term), "\x1b/Z");
+
+        term.write(b"\x1b=");
+        assert!(term.window_mode.contains(WindowMode::APPKEYPAD));
+        term.write(b"\x1b>");
+        assert!(!term.window_mode.contains(WindowMode::APPKEYPAD));
+
+        // Control sequences aren't recognised, the characters after ESC [ are printed.
+        term.write(b"\x1b[2C");
+        assert_eq!(text(&term, 0), "2C        ");
+
+        term.write(b"\x1b<");
+        assert!(!term.window_mode.contains(WindowMode::VT52));
+        term.write(b"\x1b[2C!");
+        assert_eq!(text(&term, 0), "2C  !     ");
+        assert_eq!(reply(&mut term), "");
+    }
+
+    #[test]
+    fn interns_hyperlinks() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1b]8;;https://a\x07ab\x1b]8;;\x07c\x1b]8;;https://a\x07d");
+        let ids: Vec<u32> = term.lines[0].glyphs[..4]
+            .iter()
+            .map(|glyph| glyph.hyperlink)
+            .collect();
+        assert_eq!(ids, [1, 1, 0, 1]);
+        assert_eq!(term.hyperlinks.get(1).unwrap().uri, "https://a");
+        assert!(term.hyperlinks.get(2).is_none());
+        assert!(term.hyperlinks.get(0).is_none());
+    }
+
+    #[test]
+    fn hyperlink_ids_split_links_to_the_same_uri() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1b]8;id=1;https://a\x07a\x1b]8;id=2;https://a\x07b");
+        term.write(b"\x1b]8;foo=bar:id=1;https://a\x07c");
+        let ids: Vec<u32> = term.lines[0].glyphs[..3]
+            .iter()
+            .map(|glyph| glyph.hyperlink)
+            .collect();
+        assert_eq!(ids, [1, 2, 1]);
+        assert_eq!(term.hyperlinks.get(2).unwrap().id.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn finds_the_hyperlink_under_a_cell() {
+        let mut term = Term::new(4, 2);
+        term.write(b"a\x1b]8;;https://a\x07b\x1b]8;;\x07\r\n\r\n");
+        assert!(term.hyperlink_at(0, 1).is_none());
+
+        // The view follows the history when scrolled back.
+        term.scroll_view_up(1);
+        assert_eq!(term.hyperlink_at(1, 0).unwrap().uri, "https://a");
+        assert!(term.hyperlink_at(0, 0).is_none());
+        assert!(term.hyperlink_at(9, 0).is_none());
+    }
+
+    #[test]
+    fn opens_hyperlinks_with_the_handler() {
+        let link = Hyperlink {
+            id: None,
+            uri: "https://a".to_string(),
+        };
+        let command = Hyperlinks::new(&["open", "-n"])
+            .open_command(&link)
+            .unwrap();
+        assert_eq!(command.get_program(), "open");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-n", "https://a"]);
+        assert!(Hyperlinks::new(&[]).open_command(&link).is_none());
+    }
+
+    #[test]
+    fn sets_selections_with_osc52() {
+        let mut term = Term::new(10, 2);
+        assert_eq!(term.clipboard_policy, ClipboardPolicy::WriteOnly);
+        term.write(b"\x1b]52;c;aGk=\x07\x1b]52;p;aGk=\x07\x1b]52;s;aGk=\x07");
+        term.write(b"\x1b]52;;aGk=\x07\x1b]52;xp;aGk=\x07\x1b]52;x;aGk=\x07\x1b]52;c;!\x07");
+        let events: Vec<TermEvent> = [
+            Selection::Clipboard,
+            Selection::Primary,
+            Selection::Clipboard,
+            Selection::Clipboard,
+            Selection::Primary,
+        ]
+        .into_iter()
+        .map(|selection| TermEvent::SetSelection(selection, "hi".to_string()))
+        .collect();
+        assert_eq!(term.events, events);
+
+        // Reading is refused unless allowed.
+        term.events.clear();
+        term.write(b"\x1b]52;c;?\x07");
+        assert!(term.events.is_empty());
+    }
+
+    #[test]
+    fn reads_selections_with_osc52_when_allowed() {
+        let mut term = Term::new(10, 2);
+        term.clipboard_policy = ClipboardPolicy::ReadWrite;
+        term.write(b"\x1b]52;p;?\x07\x1b]52;c;aGk=\x07");
+        assert_eq!(
+            term.events,
+            [
+                TermEvent::RequestSelection(Selection::Primary),
+                TermEvent::SetSelection(Selection::Clipboard, "hi".to_string()),
+            ]
+        );
+
+        term.report_selection(Selection::Primary, "hi");
+        assert_eq!(reply(&mut term), "\x1b]52;p;aGk=\x1b\\");
+        term.report_selection(
+            Selection::Clipboard,
+            &"a".repeat(config::OSC52_SIZE_LIMIT + 1),
+        );
+        assert_eq!(reply(&mut term), "\x1b]52;c;\x1b\\");
+
+        // The policy survives a reset.
+        term.reset();
+        assert_eq!(term.clipboard_policy, ClipboardPolicy::ReadWrite);
+    }
+
+    #[test]
+    fn ignores_osc52_when_disabled() {
+        let mut term = Term::new(10, 2);
+        term.clipboard_policy = ClipboardPolicy::Disabled;
+        term.write(b"\x1b]52;c;aGk=\x07\x1b]52;c;?\x07");
+        assert!(term.events.is_empty());
+
+        term.report_selection(Selection::Clipboard, "hi");
+        term.clipboard_policy = ClipboardPolicy::WriteOnly;
+        term.report_selection(Selection::Clipboard, "hi");
+        assert_eq!(reply(&mut term), "");
+    }
+
+    #[test]
+    fn ignores_osc52_selections_past_the_size_limit() {
+        let mut term = Term::new(10, 2);
+        let fits = "QUFB".repeat(config::OSC52_SIZE_LIMIT / 3);
+        term.write(format!("\x1b]52;c;{}\x07", fits).as_bytes());
+        assert_eq!(term.events.len(), 1);
+
+        term.events.clear();
+        let too_large = "QUFB".repeat(config::OSC52_SIZE_LIMIT / 3 + 1);
+        term.write(format!("\x1b]52;c;{}\x07", too_large).as_bytes());
+        assert!(term.events.is_empty());
+    }
+
+    #[test]
+    fn records_the_directory_reported_on_this_host() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1b]7;file:///tmp/a%20b\x07");
+        assert_eq!(term.reported_directory, Some(PathBuf::from("/tmp/a b")));
+        assert_eq!(term.events, [TermEvent::TitleChanged]);
+
+        term.write(b"\x1b]7;file://localhost/home\x07");
+        assert_eq!(term.reported_directory, Some(PathBuf::from("/home")));
+
+        // Neither another host nor something other than a file URL changes it.
+        term.events.clear();
+        term.write(b"\x1b]7;file://elsewhere.invalid/srv\x07\x1b]7;/srv\x07");
+        assert_eq!(term.reported_directory, Some(PathBuf::from("/home")));
+        assert!(term.events.is_empty());
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("/a%20b/%C3%A9"), "/a b/é");
+        assert_eq!(percent_decode("/a%2fb%2F"), "/a/b/");
+        assert_eq!(percent_decode("/100%"), "/100%");
+        assert_eq!(percent_decode("/%4"), "/%4");
+        assert_eq!(percent_decode("/%+1%-1"), "/%+1%-1");
+        assert_eq!(percent_decode("/%zz"), "/%zz");
+    }
+
+    #[test]
+    fn prefers_the_reported_working_directory() {
+        let mut term = Term::new(10, 2);
+        term.child_pid = Some(std::process::id());
+        term.write(b"\x1b]7;file:///reported\x07");
+        assert_eq!(term.working_directory(), Some(PathBuf::from("/reported")));
+    }
+
+    /// Runs a command at a prompt marked with OSC 133, the way shell integration does.
+    fn run_command(term: &mut Term, command: &str, output: &str, status: i32) {
+        term.write(b"\x1b]133;A\x07$ \x1b]133;B\x07");
+        term.write(format!("{}\r\n\x1b]133;C\x07{}", command, output).as_bytes());
+        term.write(format!("\x1b]133;D;{}\x07", status).as_bytes());
+    }
+
+    fn zones(term: &Term) -> Vec<(SemanticZone, bool)> {
+        (term.first_line_number()..term.screen_line_number() + term.rows as u64)
+            .map(|number| {
+                let line = term.line_by_number(number).unwrap();
+                (line.zone, line.prompt_start)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn marks_semantic_zones() {
+        let mut term = Term::new(10, 4);
+        run_command(&mut term, "ls", "a\r\nb\r\n", 0);
+        run_command(&mut term, "cat", "c\r\n", 1);
+        term.write(b"\x1b]133;A\x07$ \x1b]133;B\x07");
+        assert_eq!(term.semantic_zone, SemanticZone::Input);
+
+        // The first command has scrolled into the history, keeping its marks.
+        assert_eq!(term.history.len(), 2);
+        use SemanticZone::*;
+        assert_eq!(
+            zones(&term),
+            [
+                (Prompt, true),
+                (Output, false),
+                (Output, false),
+                (Prompt, true),
+                (Output, false),
+                (Prompt, true),
+            ]
+        );
+        assert_eq!(term.history.get(0).unwrap().exit_status, Some(0));
+        assert_eq!(term.line_by_number(3).unwrap().exit_status, Some(1));
+        assert_eq!(term.line_by_number(5).unwrap().exit_status, None);
+        assert_eq!(term.line_by_number(1).unwrap().exit_status, None);
+    }
+
+    #[test]
+    fn jumps_between_prompts() {
+        let mut term = Term::new(10, 2);
+        for command in ["a", "b", "c"] {
+            run_command(&mut term, command, "x\r\ny\r\n", 0);
+        }
+        // Prompts start lines 0, 3 and 6, with the screen showing lines 8 and 9.
+        assert_eq!(term.screen_line_number(), 8);
+
+        term.scroll_view_to_prompt(false);
+        assert_eq!(term.view_line_number(0), 6);
+        term.scroll_view_to_prompt(false);
+        assert_eq!(term.view_line_number(0), 3);
+        term.scroll_view_to_prompt(false);
+        assert_eq!(term.view_line_number(0), 0);
+        term.scroll_view_to_prompt(false);
+        assert_eq!(term.view_line_number(0), 0);
+
+        term.scroll_view_to_prompt(true);
+        assert_eq!(term.view_line_number(0), 3);
+        term.scroll_view_to_prompt(true);
+        assert_eq!(term.view_line_number(0), 6);
+        term.scroll_view_to_prompt(true);
+        assert_eq!(term.view_line_number(0), 6);
+    }
+
+    #[test]
+    fn gets_the_last_command_output() {
+        let mut term = Term::new(10, 6);
+        assert_eq!(term.last_command_output(), None);
+
+        run_command(&mut term, "ls", "a\r\nb\r\n", 0);
+        run_command(&mut term, "cat", "long line!wrapped \r\nend\r\n", 0);
+        term.write(b"\x1b]133;A\x07$ ");
+        assert_eq!(
+            term.last_command_output().as_deref(),
+            Some("long line!wrapped\nend\n")
+        );
+    }
+
+    #[test]
+    fn sets_underline_styles() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1b[4ma\x1b[4:0mb\x1b[4:1mc\x1b[4:2md\x1b[4:3me\x1b[4:4mf\x1b[4:5mg");
+        term.write(b"\x1b[21mh\x1b[24mi\x1b[4:3m\x1b[0mj");
+        let styles: Vec<UnderlineStyle> = term.lines[0]
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.underline)
+            .collect();
+        use UnderlineStyle::*;
+        assert_eq!(
+            styles,
+            [Single, None, Single, Double, Curly, Dotted, Dashed, Double, None, None]
+        );
+    }
+
+    #[test]
+    fn sets_underline_colours() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1b[4:3;58:2::1:2:3ma\x1b[58;5;9mb\x1b[58:5:200mc\x1b[59md");
+        term.write(b"\x1b[58;2;4;5;6m\x1b[24me\x1b[0mf");
+        let glyphs = &term.lines[0].glyphs;
+        assert_eq!(glyphs[0].underline, UnderlineStyle::Curly);
+        assert_eq!(glyphs[0].underline_color, Some(true_color(1, 2, 3)));
+        assert_eq!(glyphs[1].underline_color, Some(9));
+        assert_eq!(glyphs[2].underline_color, Some(200));
+        assert_eq!(glyphs[3].underline, UnderlineStyle::Curly);
+        assert_eq!(glyphs[3].underline_color, None);
+        // Turning the underline off leaves its colour for the next one.
+        assert_eq!(glyphs[4].underline, UnderlineStyle::None);
+        assert_eq!(glyphs[4].underline_color, Some(true_color(4, 5, 6)));
+        assert_eq!(glyphs[5].underline_color, None);
+    }
+
+    #[test]
+    fn sets_the_cursor_style() {
+        let mut term = Term::new(10, 2);
+        let styles = [
+            CursorStyle::BlinkingBlock,
+            CursorStyle::SteadyBlock,
+            CursorStyle::BlinkingUnderline,
+            CursorStyle::SteadyUnderline,
+            CursorStyle::BlinkingBar,
+            CursorStyle::SteadyBar,
+        ];
+        for (param, style) in (1..).zip(styles) {
+            term.write(format!("\x1b[{} q", param).as_bytes());
+            assert_eq!(term.cursor_style, style);
+            term.write(b"\x1bP$q q\x1b\\");
+            assert_eq!(reply(&mut term), format!("\x1bP1$r{} q\x1b\\", param));
+        }
+
+        // Unknown styles are ignored, and zero is the configured style.
+        term.write(b"\x1b[7 q");
+        assert_eq!(term.cursor_style, CursorStyle::SteadyBar);
+        term.write(b"\x1b[0 q");
+        assert_eq!(term.cursor_style, config::CURSOR_STYLE);
+        term.write(b"\x1b[3 q\x1b[ q");
+        assert_eq!(term.cursor_style, config::CURSOR_STYLE);
+    }
+
+    #[test]
+    fn reports_invalid_settings() {
+        let mut term = Term::new(10, 2);
+        term.write(b"\x1bP$q!q\x1b\\\x1bP$q\x1b\\");
+        assert_eq!(reply(&mut term), "\x1bP0$r\x1b\\\x1bP0$r\x1b\\");
+    }
+
+    #[test]
+    fn pastes_without_control_characters() {
+        let mut term = Term::new(10, 2);
+        term.paste("a\tb\x07c\x00d\u{85}e\u{9b}f\x7fg\r");
+        assert_eq!(reply(&mut term), "a\tbcdefg\r");
+
+        term.paste("one\ntwo\r\nthree");
+        assert_eq!(reply(&mut term), "one\rtwo\rthree");
+    }
+
+    #[test]
+    fn brackets_pastes_when_asked() {
+        let mut term = Term::new(10, 2);
+        term.paste("a\x1b[201~b");
+        assert_eq!(reply(&mut term), "a[201~b");
+
+        term.write(b"\x1b[?2004h");
+        term.paste("a\x1b[201~b\n");
+        assert_eq!(reply(&mut term), "\x1b[200~a[201~b\r\x1b[201~");
+
+        term.write(b"\x1b[?2004l");
+        term.paste("c");
+        assert_eq!(reply(&mut term), "c");
+    }
+
+    #[test]
+    fn selective_erases_in_display_and_line_spare_protected_cells() {
+        let mut term = letters();
+        term.write(b"\x1b[1\"qAB\x1b[0\"q\x1b[?2J");
+        assert_eq!(screen(&term), ["AB    ", "      ", "      ", "      "]);
+
+        term.write(b"\x1b[2;1H\x1b[1\"qCD\x1b[0\"qEF\x1b[?1K");
+        assert_eq!(text(&term, 1), "CD    ");
+        term.write(b"\x1b[1;1H\x1b[?K");
+        assert_eq!(text(&term, 0), "AB    ");
+    }
+
+    #[test]
+    fn plain_erases_ignore_decsca_protection() {
+        let mut term = letters();
+        term.write(b"\x1b[1\"qABCDEF\x1b[0\"q");
+        term.write(b"\x1b[1;1H\x1b[2X");
+        assert_eq!(text(&term, 0), "  CDEF");
+        term.write(b"\x1b[K");
+        assert_eq!(text(&term, 0), "      ");
+
+        term.write(b"\x1b[1\"qAB\x1b[2J");
+        assert_eq!(screen(&term), ["      ", "      ", "      ", "      "]);
+    }
+
+    #[test]
+    fn every_erase_spares_iso_protection() {
+        let mut term = letters();
+        term.write(b"\x1bVAB\x1bWC");
+        assert_eq!(marked(&term, 0, GlyphAttribute::PROTECTED), "##....");
+
+        term.write(b"\x1b[1;1H\x1b[6X");
+        assert_eq!(text(&term, 0), "AB    ");
+        term.write(b"\x1b[2;1H\x1bVG\x1bW\x1b[2K");
+        assert_eq!(text(&term, 1), "G     ");
+        term.write(b"\x1b[2J");
+        assert_eq!(screen(&term), ["AB    ", "G     ", "      ", "      "]);
+        term.write(b"\x1b[?2J");
+        assert_eq!(screen(&term), ["AB    ", "G     ", "      ", "      "]);
+    }
+
+    #[test]
+    fn decsca_turns_protection_off() {
+        let mut term = letters();
+        term.write(b"\x1b[1\"qA\x1bP$q\"q\x1b\\");
+        assert_eq!(reply(&mut term), "\x1bP1$r1\"q\x1b\\");
+        term.write(b"\x1b[0\"qB\x1bP$q\"q\x1b\\");
+        assert_eq!(reply(&mut term), "\x1bP1$r0\"q\x1b\\");
+        term.write(b"\x1b[1\"qC\x1b[2\"qD\x1b[1\"qE\x1b[\"qF");
+        assert_eq!(marked(&term, 0, GlyphAttribute::PROTECTED), "#.#.#.");
+    }
+}
@@ -6,12 +6,35 @@ include!("bindings/bindings.rs");
 
 use thiserror::Error;
 
+use crate::{
+    config,
+    keyboard::{self, KeyEvent, KeyEventKind, KeyModifiers},
+    kitty_graphics,
+    mouse::{self, MouseEvent, MouseEventKind},
+    search::{Point, Search, SearchDirection},
+    st::{
+        self, is_true_color, CursorStyle, GlyphAttribute, LineSize, Term, TermEvent,
+        UnderlineStyle, WindowMode,
+    },
+};
+
 use std::{
     char,
-    ffi::c_void,
-    os::raw::{c_int, c_short},
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    os::raw::{c_char, c_int, c_short, c_uint},
+    process::{Command, Stdio},
 };
 
+// Predefined atoms from Xatom.h, which bindgen skips as they are casts.
+pub const XA_PRIMARY: Atom = 1;
+pub const XA_ATOM: Atom = 4;
+pub const XA_STRING: Atom = 31;
+
+// Messages of the XEmbed protocol telling an embedded window it gained or lost focus.
+const XEMBED_FOCUS_IN: i64 = 4;
+const XEMBED_FOCUS_OUT: i64 = 5;
+
 #[derive(Error, Debug)]
 pub enum XError {
     #[error("Buffer overflow occurred")]
@@ -29,8 +52,15 @@ pub enum Arg {
     Str(&'static str),
 }
 
+/// Matches any combination of modifiers in a shortcut.
+pub const XK_ANY_MOD: usize = usize::MAX;
+/// Matches no modifiers in a shortcut.
+pub const XK_NO_MOD: usize = 0;
+/// Modifier bit set while the keyboard group is switched, eg: by Mode_switch.
+pub const XK_SWITCH_MOD: c_uint = 1 << 13;
+
 /// Represents a keyboard shortcut.
-pub struct Shortcut<'a> {
+pub struct Shortcut {
     /// Modifiers held to execute the shortcut.
     pub modifiers: usize,
 
@@ -38,32 +68,25 @@ pub struct Shortcut<'a> {
     pub key_symbol: KeySym,
 
     /// Function to be executed.
-    pub func: Option<&'a dyn Fn(&Arg)>,
+    pub func: fn(&mut x, &Arg),
 
     /// Arguments passed through to the function.
     pub arg: Arg,
 }
 
-#[derive(PartialEq)]
-pub enum WindowMode {
-    VISIBLE,
-    FOCUSED,
-    APPKEYPAD,
-    MOUSEBTN,
-    MOUSEMOTION,
-    REVERSE,
-    KBDLOCK,
-    HIDE,
-    APPCURSOR,
-    MOUSESGR,
-    EIGHTBIT,
-    BLINK,
-    FBLINK,
-    FOCUS,
-    MOUSEX10,
-    MOUSEMANY,
-    BRCKTPASTE,
-    NUMLOCK,
+/// Represents a mouse button shortcut.
+pub struct MouseShortcut {
+    /// Modifiers held to execute the shortcut.
+    pub modifiers: usize,
+
+    /// Button pressed to invoke the shortcut, with 4 and 5 being the wheel.
+    pub button: c_uint,
+
+    /// Function to be executed.
+    pub func: fn(&mut x, &Arg),
+
+    /// Arguments passed through to the function.
+    pub arg: Arg,
 }
 
 pub struct TermWindow {
@@ -74,9 +97,23 @@ pub struct TermWindow {
     pub char_height: c_int,
     pub char_width: c_int,
 
-    // FIXME: Probably should be enums:
-    pub window_mode: WindowMode,
-    pub cursor_style: c_int,
+    /// Where the cursor was last drawn, as column and row of the view, so it can be erased.
+    pub old_cursor: (usize, usize),
+
+    /// Cell under the mouse pointer, as column and row of the view.
+    pub pointer_cell: (usize, usize),
+
+    /// Id of the hyperlink under the mouse pointer, or zero for none.
+    pub hovered_hyperlink: u32,
+
+    /// Key code of the key held down, to tell repeats from presses. Zero when none is.
+    pub pressed_key: c_uint,
+
+    /// Mouse buttons held down, with bit `n - 1` for button `n`.
+    pub mouse_buttons: u32,
+    /// Where the mouse was last reported, in cells or in pixels for SGR-pixel reports, so that
+    /// motion within it isn't reported again.
+    pub mouse_position: (c_int, c_int),
 }
 
 pub struct XWindow {
@@ -91,7 +128,7 @@ pub struct XWindow {
     pub net_wm_icon_name: Atom,
     pub net_wm_pid: Atom,
     pub input_method_editor: InputMethodEditor,
-    pub draw: XftDraw,
+    pub draw: *mut XftDraw,
     pub visual: *mut Visual,
     pub attributes: XSetWindowAttributes,
     pub screen: c_int,
@@ -112,8 +149,14 @@ pub struct InputMethodEditor {
 
 pub struct XSelection {
     pub x_target: Atom,
-    pub primary: char,
-    pub clipboard: char,
+    /// Text served for the PRIMARY selection while we own it.
+    pub primary: Option<String>,
+    /// Text served for the CLIPBOARD selection while we own it.
+    pub clipboard: Option<String>,
+    /// Selection being fetched from another client to answer an OSC 52 query.
+    pub osc52_request: Option<st::Selection>,
+    /// Selection being fetched from another client to paste.
+    pub paste_request: Option<st::Selection>,
     pub time_click_1: timespec,
     pub time_click_2: timespec,
 }
@@ -131,31 +174,186 @@ pub struct XFont {
     pub matched_font: *mut XftFont,
     pub font_set: *mut FcFontSet,     // set of fonts that match pattern
     pub font_pattern: *mut FcPattern, // pattern used to match fonts
+    /// The font stretched to twice the width, and to twice the width and height, for double
+    /// width and double height lines. Null until a line like that is first drawn.
+    pub double_width_font: *mut XftFont,
+    pub double_size_font: *mut XftFont,
+    /// Offset of the top of an underline below the baseline and its thickness, in pixels.
+    /// `None` until read by `load_underline_metrics`.
+    pub underline_metrics: Option<(c_int, c_int)>,
+}
+
+impl XFont {
+    /// Offset of the top of an underline from the top of the cell, where the font puts it or
+    /// else just below the baseline.
+    pub fn underline_position(&self) -> c_int {
+        match self.underline_metrics {
+            Some((position, _)) => self.ascent + position,
+            Option::None => self.ascent + (self.descent / 4).max(1),
+        }
+    }
+
+    /// Thickness of underlines and strikethroughs, as the font gives it or else growing with
+    /// the size of the font.
+    pub fn underline_thickness(&self) -> c_int {
+        match self.underline_metrics {
+            Some((_, thickness)) => thickness,
+            Option::None => ((self.ascent + self.descent) / 14).max(1),
+        }
+    }
+
+    /// Reads the underline position and thickness from the font, unless they were read
+    /// already. Fonts without them, such as bitmap fonts, keep to the guesses above.
+    pub fn load_underline_metrics(&mut self) {
+        if self.underline_metrics.is_some() || self.matched_font.is_null() {
+            return;
+        }
+        // SAFETY: The font is open, and its face is only read while locked.
+        let metrics = unsafe {
+            let face = XftLockFace(self.matched_font);
+            let metrics = face.as_ref().and_then(|face| {
+                let size = face.size.as_ref()?;
+                if face.units_per_EM == 0 || face.underline_thickness <= 0 {
+                    return Option::None;
+                }
+                // Font units to pixels, at the size the font was opened at.
+                let scale = size.metrics.y_ppem as f64 / face.units_per_EM as f64;
+                let thickness = (face.underline_thickness as f64 * scale).round().max(1.0);
+                // The font gives the centre of the line, with positive values above the
+                // baseline.
+                let top = -face.underline_position as f64 * scale - thickness / 2.0;
+                Some((top.round().max(0.0) as c_int, thickness as c_int))
+            });
+            if !face.is_null() {
+                XftUnlockFace(self.matched_font);
+            }
+            metrics
+        };
+        self.underline_metrics = Some(metrics.unwrap_or_else(|| {
+            (
+                self.underline_position() - self.ascent,
+                self.underline_thickness(),
+            )
+        }));
+    }
+
+    /// Opens the stretched fonts for double width and double height lines, unless they are
+    /// open already.
+    pub fn load_double_size(&mut self, display: *mut Display) {
+        if self.double_width_font.is_null() {
+            self.double_width_font = self.open_scaled(display, 2.0, 1.0);
+        }
+        if self.double_size_font.is_null() {
+            self.double_size_font = self.open_scaled(display, 2.0, 2.0);
+        }
+    }
+
+    /// Opens the matched font stretched by a transformation matrix, or returns null when it
+    /// can't be opened.
+    fn open_scaled(&self, display: *mut Display, scale_x: f64, scale_y: f64) -> *mut XftFont {
+        if self.matched_font.is_null() {
+            return std::ptr::null_mut();
+        }
+        let matrix = FcMatrix {
+            xx: scale_x,
+            xy: 0.0,
+            yx: 0.0,
+            yy: scale_y,
+        };
+        // SAFETY: The font is open, so its pattern is valid. The copy is owned by the new font
+        // once opened, and destroyed here otherwise.
+        unsafe {
+            let pattern = FcPatternDuplicate((*self.matched_font).pattern);
+            if pattern.is_null() {
+                return std::ptr::null_mut();
+            }
+            FcPatternDel(pattern, FC_MATRIX.as_ptr() as *const c_char);
+            FcPatternAddMatrix(pattern, FC_MATRIX.as_ptr() as *const c_char, &matrix);
+            let font = XftFontOpenPattern(display, pattern);
+            if font.is_null() {
+                FcPatternDestroy(pattern);
+            }
+            font
+        }
+    }
 }
 
 pub struct DrawingContext {
     pub Color: *mut XftColor,
     pub col_length: usize,
-    pub font: Font,
-    pub bold_font: Font,
-    pub italic_font: Font,
-    pub Italic_bold_font: Font,
+    pub font: XFont,
+    pub bold_font: XFont,
+    pub italic_font: XFont,
+    pub Italic_bold_font: XFont,
     pub graphics_context: GC,
+    /// Pictures of the images in the grid, uploaded to the X server when first drawn.
+    pub images: HashMap<u32, Picture>,
 }
 
-// TODO: Event handlers
+/// Checks the held modifiers against those of a shortcut, ignoring `config::IGNORE_MODIFIERS`.
+fn match_modifiers(mask: usize, state: c_uint) -> bool {
+    mask == XK_ANY_MOD || mask == (state & !config::IGNORE_MODIFIERS) as usize
+}
 
 pub struct x {
     pub drawing_context: DrawingContext,
     pub x_window: XWindow,
     pub x_selection: XSelection,
     pub term_window: TermWindow,
+    pub term: Term,
+    /// The open search, while the search bar is shown.
+    pub search: Option<Search>,
+    /// Pasted text held back until the user confirms it, see `config::PASTE_CONFIRM_MULTILINE`.
+    pub pending_paste: Option<String>,
+}
+
+/// Runs a program in the background, without waiting for it to finish.
+fn spawn_detached(command: &mut Command) {
+    match command.stdin(Stdio::null()).spawn() {
+        // Reap the child when it exits rather than leaving a zombie behind.
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(error) => eprintln!("tt: could not run {:?}: {}", command.get_program(), error),
+    }
+}
+
+/// Copies a colour, which bindgen doesn't derive `Clone` for.
+fn copy_color(color: &XftColor) -> XftColor {
+    XftColor {
+        pixel: color.pixel,
+        color: XRenderColor {
+            red: color.color.red,
+            green: color.color.green,
+            blue: color.color.blue,
+            alpha: color.color.alpha,
+        },
+    }
 }
 
 impl x {
+    /// Dispatches an X event to its handler.
+    pub fn handle_event(&mut self, e: &mut XEvent) {
+        // SAFETY: `type_` is the first member of every event in the union, so it is always set.
+        let event_type = unsafe { *e.type_.as_ref() } as u32;
+
+        match event_type {
+            KeyPress => self.key_press(e),
+            KeyRelease => self.key_release(e),
+            ButtonPress => self.button_press(e),
+            ButtonRelease => self.button_release(e),
+            MotionNotify => self.motion_notify(e),
+            SelectionRequest => self.selection_request(e),
+            SelectionNotify => self.selection_notify(e),
+            SelectionClear => self.selection_clear(e),
+            FocusIn | FocusOut => self.focus_change(e),
+            ClientMessage => self.client_message(e),
+            _ => {}
+        }
+    }
+
     // FIXME: Move TermWindow to a struct along with other static globals
-    #[allow(dead_code)]
-    fn key_press(self, e: *mut XEvent) {
+    fn key_press(&mut self, e: *mut XEvent) {
         debug_assert!(!e.is_null(), "Pointer `e` should not be null");
         // Event should not ever be a null pointer, fail if it is.
         let event: &mut XKeyEvent = unsafe { &mut (*e).xkey.as_mut() };
@@ -168,10 +366,9 @@ impl x {
         let mut length: *mut u64 = std::ptr::null_mut();
         let mut c: i8;
         let mut status: *mut i32 = std::ptr::null_mut();
-        let mut shortcut: *mut Shortcut = std::ptr::null_mut();
 
         // If the keyboard is locked, exit.
-        if self.term_window.window_mode == WindowMode::KBDLOCK {
+        if self.term.window_mode.contains(WindowMode::KBDLOCK) {
             return;
         }
 
@@ -209,9 +406,57 @@ impl x {
         // TODO: Figure out and handle error cases
         let mut len = get_len().unwrap();
 
-        // TODO: Shortcuts
+        if self.search.is_some() {
+            self.search_key_press(key_symbol, event.state, &buf[..len.max(0) as usize]);
+            return;
+        }
+        // Pressing a modifier, eg: Shift to type `Y`, doesn't answer the paste confirmation.
+        if self.pending_paste.is_some() && keyboard::is_modifier_key_symbol(key_symbol) {
+            return;
+        }
+        if let Some(text) = self.pending_paste.take() {
+            if matches!(key_symbol as u32, XK_y | XK_Y | XK_Return | XK_KP_Enter) {
+                self.term.scroll_view_reset();
+                self.term.paste(&text);
+            }
+            return;
+        }
+
+        for shortcut in config::SHORTCUTS {
+            if key_symbol == shortcut.key_symbol && match_modifiers(shortcut.modifiers, event.state)
+            {
+                (shortcut.func)(self, &shortcut.arg);
+                return;
+            }
+        }
+
         // TODO: Custom Keys from config file
 
+        let mode = self.term.window_mode;
+        if mode.contains(WindowMode::VT52) {
+            let app_keypad = mode.contains(WindowMode::APPKEYPAD);
+            if let Some(sequence) = keyboard::encode_vt52(key_symbol, app_keypad) {
+                self.term.scroll_view_reset();
+                self.term.tty_write(&sequence, true);
+                return;
+            }
+        }
+
+        // A press of the key already held down is the keyboard repeating it.
+        let kind = if self.term_window.pressed_key == event.keycode {
+            KeyEventKind::Repeat
+        } else {
+            KeyEventKind::Press
+        };
+        self.term_window.pressed_key = event.keycode;
+        let text: Vec<u8> = buf[..len.max(0) as usize]
+            .iter()
+            .map(|&b| b as u8)
+            .collect();
+        if self.encoded_key(event, key_symbol, kind, &String::from_utf8_lossy(&text)) {
+            return;
+        }
+
         // Invalid input
         if len == 0 {
             return;
@@ -223,7 +468,7 @@ impl x {
         // extended ascii as well as an ESC-prefixed sequecne.
         if len == 1 && (event.state & Mod1Mask) != 0 {
             // A key was pressed with the meta / alt key
-            if self.term_window.window_mode == WindowMode::EIGHTBIT {
+            if self.term.window_mode.contains(WindowMode::EIGHTBIT) {
                 if buf[0] < 0o177 {
                     // 127 in decimal, ie: 7 bit ascii that needs to be converted to 8 bit. This represents meta key usage.
                     let high_bit = 0x79; // FIXME: this ir wrong :)
@@ -240,8 +485,1358 @@ impl x {
                 len = 2;
             }
         }
-        // TODO:
-        //ttywrite(buf, len, 1);
+        // Typing snaps the view back from the scrollback to the live screen.
+        self.term.scroll_view_reset();
+
+        let bytes: Vec<u8> = buf[..len as usize].iter().map(|&b| b as u8).collect();
+        self.term.tty_write(&bytes, true);
+    }
+
+    fn key_release(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for KeyRelease, so `xkey` is the active member.
+        let event: &mut XKeyEvent = unsafe { (*e).xkey.as_mut() };
+        // The key is still held down, and the press that follows is reported as a repeat.
+        if self.is_autorepeat(event) {
+            return;
+        }
+        if self.term_window.pressed_key == event.keycode {
+            self.term_window.pressed_key = 0;
+        }
+        if self.search.is_some()
+            || self.pending_paste.is_some()
+            || self.term.window_mode.contains(WindowMode::KBDLOCK)
+        {
+            return;
+        }
+
+        let mut key_symbol: KeySym = 0;
+        // SAFETY: `event` is a valid key event and no text is asked for.
+        unsafe {
+            XLookupString(
+                event,
+                std::ptr::null_mut(),
+                0,
+                &mut key_symbol,
+                std::ptr::null_mut(),
+            )
+        };
+        self.encoded_key(event, key_symbol, KeyEventKind::Release, "");
+    }
+
+    /// Whether a key release is one the X server makes up while a key autorepeats, which comes
+    /// right before a press of the same key with the same timestamp.
+    fn is_autorepeat(&self, event: &XKeyEvent) -> bool {
+        let display = self.x_window.display;
+        // SAFETY: The display is open for as long as `x` exists.
+        if unsafe { XEventsQueued(display, QueuedAfterReading as c_int) } == 0 {
+            return false;
+        }
+        // SAFETY: An all zero event is a valid value for every member of the union.
+        let mut next: XEvent = unsafe { std::mem::zeroed() };
+        // SAFETY: There is an event queued, so this doesn't block, and `next` is written to
+        // without being removed from the queue.
+        unsafe { XPeekEvent(display, &mut next) };
+        // SAFETY: `type_` is the first member of every event in the union, so it is always set.
+        let next_type = unsafe { *next.type_.as_ref() } as u32;
+        if next_type != KeyPress {
+            return false;
+        }
+        // SAFETY: The event is a KeyPress, so `xkey` is the active member.
+        let next = unsafe { next.xkey.as_ref() };
+        next.keycode == event.keycode && next.time == event.time
+    }
+
+    /// Sends a key with the kitty keyboard protocol or modifyOtherKeys, if the application has
+    /// turned either on. Returns whether the key was dealt with, rather than needing to be sent
+    /// the legacy way.
+    fn encoded_key(
+        &mut self,
+        event: &mut XKeyEvent,
+        key_symbol: KeySym,
+        kind: KeyEventKind,
+        text: &str,
+    ) -> bool {
+        let flags = self.term.keyboard_flags();
+        let modify_other_keys = self.term.modify_other_keys;
+        if flags.is_empty() && modify_other_keys == 0 {
+            return false;
+        }
+        let key_event = KeyEvent {
+            key_symbol,
+            // SAFETY: `event` is a valid key event.
+            base_symbol: unsafe { XLookupKeysym(event, 0) },
+            modifiers: KeyModifiers::from_x_state(event.state),
+            kind,
+            text,
+        };
+        let sequence = if flags.is_empty() {
+            keyboard::encode_modify_other_keys(
+                modify_other_keys,
+                config::FORMAT_OTHER_KEYS,
+                &key_event,
+            )
+        } else {
+            keyboard::encode(flags, &key_event)
+        };
+        let Some(sequence) = sequence else {
+            return false;
+        };
+        if !sequence.is_empty() {
+            self.term.scroll_view_reset();
+            self.term.tty_write(&sequence, true);
+        }
+        true
+    }
+
+    fn button_press(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for ButtonPress, so `xbutton` is the active member.
+        let event: &XButtonEvent = unsafe { (*e).xbutton.as_ref() };
+        self.update_pointer(event.x, event.y);
+        if (1..=11).contains(&event.button) {
+            self.term_window.mouse_buttons |= 1 << (event.button - 1);
+        }
+
+        if self.mouse_reporting(event.state) {
+            self.mouse_report(ButtonPress, event.button, event.state, event.x, event.y);
+            return;
+        }
+        if self.alternate_scroll(event.button, event.state) {
+            return;
+        }
+
+        for shortcut in config::MOUSE_SHORTCUTS {
+            if event.button == shortcut.button
+                && (match_modifiers(shortcut.modifiers, event.state)
+                    || match_modifiers(
+                        shortcut.modifiers,
+                        event.state & !config::FORCE_MOUSE_MODIFIER,
+                    ))
+            {
+                (shortcut.func)(self, &shortcut.arg);
+                return;
+            }
+        }
+    }
+
+    fn motion_notify(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for MotionNotify, so `xmotion` is the active member.
+        let event: &XMotionEvent = unsafe { (*e).xmotion.as_ref() };
+        self.update_pointer(event.x, event.y);
+        if self.mouse_reporting(event.state) {
+            self.mouse_report(MotionNotify, 0, event.state, event.x, event.y);
+        }
+    }
+
+    fn button_release(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for ButtonRelease, so `xbutton` is the active
+        // member.
+        let event: &XButtonEvent = unsafe { (*e).xbutton.as_ref() };
+        self.update_pointer(event.x, event.y);
+        if (1..=11).contains(&event.button) {
+            self.term_window.mouse_buttons &= !(1 << (event.button - 1));
+        }
+        if self.mouse_reporting(event.state) {
+            self.mouse_report(ButtonRelease, event.button, event.state, event.x, event.y);
+        }
+    }
+
+    /// Sends the wheel as Up and Down keys on the alternate screen (mode 1007), where programs
+    /// like `less` have no history to scroll. Returns whether the button was handled.
+    fn alternate_scroll(&mut self, button: c_uint, state: c_uint) -> bool {
+        if state & config::FORCE_MOUSE_MODIFIER != 0 {
+            return false;
+        }
+        let Some(keys) = mouse::alternate_scroll_keys(
+            self.term.window_mode,
+            self.term.is_altscreen(),
+            button,
+            config::ALTERNATE_SCROLL_LINES,
+        ) else {
+            return false;
+        };
+        self.term.tty_write(&keys, false);
+        true
+    }
+
+    /// Whether mouse events go to the application. Holding the force modifier lets the user
+    /// select and scroll even when the application has asked for them.
+    fn mouse_reporting(&self, state: c_uint) -> bool {
+        self.term.window_mode.intersects(WindowMode::MOUSE)
+            && state & config::FORCE_MOUSE_MODIFIER == 0
+    }
+
+    /// Reports a mouse event of `event_type` to the application, in the encoding it asked for.
+    /// Motion reports the lowest numbered button held down rather than `button`.
+    fn mouse_report(&mut self, event_type: u32, button: c_uint, state: c_uint, x: c_int, y: c_int) {
+        let mode = self.term.window_mode;
+        let position = if mode.contains(WindowMode::MOUSESGRPIXELS) {
+            (
+                (x - self.x_window.left_offset).max(0),
+                (y - self.x_window.top_offset).max(0),
+            )
+        } else {
+            let (col, row) = self.term_window.pointer_cell;
+            (col as c_int, row as c_int)
+        };
+
+        let (kind, button) = match event_type {
+            MotionNotify => {
+                if position == self.term_window.mouse_position {
+                    return;
+                }
+                let buttons = self.term_window.mouse_buttons;
+                let held = (1..=11).find(|button| buttons & 1 << (button - 1) != 0);
+                (MouseEventKind::Motion, held.unwrap_or(0))
+            }
+            ButtonRelease => (MouseEventKind::Release, button),
+            _ => (MouseEventKind::Press, button),
+        };
+        let event = MouseEvent {
+            kind,
+            button,
+            modifiers: KeyModifiers::from_x_state(state),
+            position: (position.0 as u32, position.1 as u32),
+        };
+        if let Some(report) = mouse::encode(mode, &event) {
+            self.term_window.mouse_position = position;
+            self.term.tty_write(&report, false);
+        }
+    }
+
+    fn focus_change(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for FocusIn and FocusOut, so `xfocus` is the
+        // active member.
+        let event: &XFocusChangeEvent = unsafe { (*e).xfocus.as_ref() };
+        // Keyboard grabs, eg: by the window manager while switching windows, briefly move focus
+        // away without the user leaving the window.
+        if event.mode == NotifyGrab as c_int {
+            return;
+        }
+        self.set_focus(event.type_ == FocusIn as c_int);
+    }
+
+    /// Handles the XEmbed focus messages sent while embedded in another window.
+    fn client_message(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for ClientMessage, so `xclient` is the active
+        // member, and `l` is the member of the data read with a format of 32.
+        let event: &XClientMessageEvent = unsafe { (*e).xclient.as_ref() };
+        if event.message_type != self.x_window.x_embed || event.format != 32 {
+            return;
+        }
+        // SAFETY: As above, a format of 32 means the data is five longs.
+        let data = unsafe { event.data.l.as_ref() };
+        match data[1] {
+            XEMBED_FOCUS_IN => self.set_focus(true),
+            XEMBED_FOCUS_OUT => self.set_focus(false),
+            _ => {}
+        }
+    }
+
+    /// Tracks whether the window has the keyboard focus, and reports changes to the
+    /// application when it turned on focus reporting (mode 1004).
+    fn set_focus(&mut self, focused: bool) {
+        let mode = &mut self.term.window_mode;
+        if mode.contains(WindowMode::FOCUSED) == focused {
+            return;
+        }
+        mode.set(WindowMode::FOCUSED, focused);
+
+        let x_input_context = self.x_window.input_method_editor.x_input_context;
+        if !x_input_context.is_null() {
+            // SAFETY: The input context is created at startup and lives as long as `x`.
+            unsafe {
+                if focused {
+                    XSetICFocus(x_input_context);
+                } else {
+                    XUnsetICFocus(x_input_context);
+                }
+            }
+        }
+        if self.term.window_mode.contains(WindowMode::FOCUS) {
+            let report: &[u8] = if focused { b"\x1b[I" } else { b"\x1b[O" };
+            self.term.tty_write(report, false);
+        }
+        // Redraw the cursor, which is hollow while unfocused.
+        let (_, row) = self.term_window.old_cursor;
+        self.term.set_dirty(row, row);
+    }
+
+    /// Records the cell under the pointer, redrawing when it moves on or off a hyperlink.
+    fn update_pointer(&mut self, x: c_int, y: c_int) {
+        let col = (x - self.x_window.left_offset).max(0) / self.term_window.char_width.max(1);
+        let row = (y - self.x_window.top_offset).max(0) / self.term_window.char_height.max(1);
+        let row = (row as usize).min(self.term.rows - 1);
+        // Cells on double width and double height lines are twice as wide.
+        let col = match self.term.view_line(row).size {
+            LineSize::Single => col as usize,
+            _ => col as usize / 2,
+        };
+        let col = col.min(self.term.cols - 1);
+        self.term_window.pointer_cell = (col, row);
+
+        let hovered = self
+            .term
+            .view_line(row)
+            .glyphs
+            .get(col)
+            .map_or(0, |g| g.hyperlink);
+        if hovered != self.term_window.hovered_hyperlink {
+            self.term_window.hovered_hyperlink = hovered;
+            self.term.set_dirty_all();
+        }
+    }
+
+    /// Opens the hyperlink under the pointer with the handler the terminal was made with,
+    /// `config::HYPERLINK_HANDLER`.
+    pub fn open_hyperlink(&mut self, _: &Arg) {
+        let (col, row) = self.term_window.pointer_cell;
+        let Some(link) = self.term.hyperlink_at(col, row) else {
+            return;
+        };
+        if let Some(mut command) = self.term.hyperlinks.open_command(link) {
+            spawn_detached(&mut command);
+        }
+    }
+
+    /// Prints the whole screen with `config::PRINTER`.
+    pub fn print_screen(&mut self, _: &Arg) {
+        self.term.print_screen(true);
+    }
+
+    /// Prints the PRIMARY selection, when it's text selected in this terminal.
+    pub fn print_selection(&mut self, _: &Arg) {
+        if let Some(text) = self.x_selection.primary.clone() {
+            self.term.print(&text);
+        }
+    }
+
+    /// Turns auto print mode on or off, printing lines as the cursor leaves them.
+    pub fn toggle_auto_print(&mut self, _: &Arg) {
+        self.term.toggle_auto_print();
+    }
+
+    /// Resets the terminal, eg: after a binary file garbled it.
+    pub fn reset(&mut self, _: &Arg) {
+        self.search = Option::None;
+        self.pending_paste = Option::None;
+        self.term.reset();
+    }
+
+    /// Scrolls the view to the previous prompt, or the next one for a positive `Arg::Int`.
+    pub fn scroll_to_prompt(&mut self, arg: &Arg) {
+        let forward = matches!(*arg, Arg::Int(n) if n > 0);
+        self.term.scroll_view_to_prompt(forward);
+    }
+
+    /// Selects the output of the last command, copying it to the clipboard as well for a
+    /// positive `Arg::Int`.
+    pub fn copy_command_output(&mut self, arg: &Arg) {
+        let Some(output) = self.term.last_command_output() else {
+            return;
+        };
+        if matches!(*arg, Arg::Int(n) if n > 0) {
+            let clipboard = self.intern_atom(c"CLIPBOARD");
+            self.set_selection(clipboard, output.clone());
+        }
+        self.set_selection(XA_PRIMARY, output);
+    }
+
+    /// Opens another terminal in the working directory of this one.
+    pub fn new_terminal(&mut self, _: &Arg) {
+        let program = std::env::current_exe().unwrap_or_else(|_| "tt".into());
+        let mut command = Command::new(program);
+        if let Some(directory) = self.term.working_directory() {
+            command.current_dir(directory);
+        }
+        spawn_detached(&mut command);
+    }
+
+    /// Copies the URI of the hyperlink under the pointer to the clipboard.
+    pub fn copy_hyperlink(&mut self, _: &Arg) {
+        let (col, row) = self.term_window.pointer_cell;
+        let Some(link) = self.term.hyperlink_at(col, row) else {
+            return;
+        };
+        let uri = link.uri.clone();
+        let clipboard = self.intern_atom(c"CLIPBOARD");
+        self.set_selection(clipboard, uri);
+    }
+
+    fn intern_atom(&self, name: &CStr) -> Atom {
+        // SAFETY: The display is open for as long as `x` exists and `name` is nul terminated.
+        unsafe { XInternAtom(self.x_window.display, name.as_ptr(), 0) }
+    }
+
+    /// Takes ownership of a selection, either `XA_PRIMARY` or CLIPBOARD, serving `text` to other
+    /// clients that ask for it.
+    pub fn set_selection(&mut self, selection: Atom, text: String) {
+        if selection == XA_PRIMARY {
+            self.x_selection.primary = Some(text);
+        } else {
+            self.x_selection.clipboard = Some(text);
+        }
+        // SAFETY: The display and window are valid for as long as `x` exists.
+        unsafe {
+            XSetSelectionOwner(
+                self.x_window.display,
+                selection,
+                self.x_window.window,
+                CurrentTime as Time,
+            )
+        };
+    }
+
+    fn selection_atom(&self, selection: st::Selection) -> Atom {
+        match selection {
+            st::Selection::Primary => XA_PRIMARY,
+            st::Selection::Clipboard => self.intern_atom(c"CLIPBOARD"),
+        }
+    }
+
+    /// Acts on the requests the terminal queued while handling output.
+    pub fn handle_term_events(&mut self) {
+        for event in std::mem::take(&mut self.term.events) {
+            match event {
+                TermEvent::SetSelection(selection, text) => {
+                    let atom = self.selection_atom(selection);
+                    self.set_selection(atom, text);
+                }
+                TermEvent::RequestSelection(selection) => self.request_selection(selection),
+                TermEvent::TitleChanged => self.update_title(),
+            }
+        }
+    }
+
+    /// Sets the window title from `config::TITLE_TEMPLATE`.
+    fn update_title(&mut self) {
+        let title = self.term.title.as_deref().unwrap_or(config::DEFAULT_TITLE);
+        let mut directory = self
+            .term
+            .working_directory()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+        if let Some(home) = std::env::var_os("HOME") {
+            let home = home.to_string_lossy();
+            if let Some(rest) = directory.strip_prefix(home.as_ref()) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    directory = format!("~{}", rest);
+                }
+            }
+        }
+        let title = config::TITLE_TEMPLATE
+            .replace("{title}", title)
+            .replace("{cwd}", &directory);
+
+        // Xlib wants a nul terminated string, so anything after an embedded nul is dropped.
+        let title = title.split('\0').next().unwrap_or_default();
+        let Ok(name) = std::ffi::CString::new(title) else {
+            return;
+        };
+        // SAFETY: The display and window are valid for as long as `x` exists, and `name` outlives
+        // the calls.
+        unsafe {
+            XStoreName(self.x_window.display, self.x_window.window, name.as_ptr());
+            XChangeProperty(
+                self.x_window.display,
+                self.x_window.window,
+                self.x_window.net_wm_name,
+                self.x_selection.x_target,
+                8,
+                PropModeReplace as c_int,
+                name.as_ptr() as *const u8,
+                title.len() as c_int,
+            );
+        }
+    }
+
+    /// Fetches the text of a selection to answer an OSC 52 query. When another client owns it,
+    /// the answer is sent once the text arrives in `selection_notify`.
+    fn request_selection(&mut self, selection: st::Selection) {
+        let owned = match selection {
+            st::Selection::Primary => self.x_selection.primary.clone(),
+            st::Selection::Clipboard => self.x_selection.clipboard.clone(),
+        };
+        if let Some(text) = owned {
+            self.term.report_selection(selection, &text);
+            return;
+        }
+
+        self.x_selection.osc52_request = Some(selection);
+        self.convert_selection(selection);
+    }
+
+    /// Pastes the PRIMARY selection, or the clipboard for a positive `Arg::Int`.
+    pub fn paste_selection(&mut self, arg: &Arg) {
+        let selection = match *arg {
+            Arg::Int(n) if n > 0 => st::Selection::Clipboard,
+            _ => st::Selection::Primary,
+        };
+        let owned = match selection {
+            st::Selection::Primary => self.x_selection.primary.clone(),
+            st::Selection::Clipboard => self.x_selection.clipboard.clone(),
+        };
+        match owned {
+            Some(text) => self.paste(text),
+            Option::None => {
+                self.x_selection.paste_request = Some(selection);
+                self.convert_selection(selection);
+            }
+        }
+    }
+
+    /// Sends pasted text to the terminal, first asking the user about text of several lines
+    /// when `config::PASTE_CONFIRM_MULTILINE` is set and the application can't tell it was
+    /// pasted.
+    fn paste(&mut self, text: String) {
+        if config::PASTE_CONFIRM_MULTILINE
+            && !self.term.window_mode.contains(WindowMode::BRCKTPASTE)
+            && text.trim_end_matches(['\r', '\n']).contains(['\r', '\n'])
+        {
+            self.pending_paste = Some(text);
+            self.term.set_dirty(self.term.rows - 1, self.term.rows - 1);
+            return;
+        }
+        self.term.scroll_view_reset();
+        self.term.paste(&text);
+    }
+
+    /// Asks the owner of a selection for its text, which arrives in `selection_notify`.
+    fn convert_selection(&mut self, selection: st::Selection) {
+        let atom = self.selection_atom(selection);
+        // SAFETY: The display and window are valid for as long as `x` exists.
+        unsafe {
+            XConvertSelection(
+                self.x_window.display,
+                atom,
+                self.x_selection.x_target,
+                atom,
+                self.x_window.window,
+                CurrentTime as Time,
+            )
+        };
+    }
+
+    /// Receives the text of a selection we asked another client for.
+    fn selection_notify(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for SelectionNotify, so `xselection` is the
+        // active member.
+        let event: &XSelectionEvent = unsafe { (*e).xselection.as_ref() };
+
+        // TODO: INCR transfers for selections too large to send in one go.
+        let mut text = String::new();
+        if event.property != 0 {
+            let mut kind: Atom = 0;
+            let mut format: c_int = 0;
+            let mut items: std::os::raw::c_ulong = 0;
+            let mut remaining: std::os::raw::c_ulong = 0;
+            let mut data: *mut u8 = std::ptr::null_mut();
+            // SAFETY: The display and window are valid, and every out pointer is to a local.
+            let status = unsafe {
+                XGetWindowProperty(
+                    self.x_window.display,
+                    self.x_window.window,
+                    event.property,
+                    0,
+                    std::os::raw::c_long::MAX / 4,
+                    True as c_int,
+                    AnyPropertyType as Atom,
+                    &mut kind,
+                    &mut format,
+                    &mut items,
+                    &mut remaining,
+                    &mut data,
+                )
+            };
+            if status == Success as c_int && !data.is_null() {
+                if format == 8 {
+                    // SAFETY: Xlib returned `items` bytes of 8 bit data at `data`.
+                    let bytes = unsafe { std::slice::from_raw_parts(data, items as usize) };
+                    text = String::from_utf8_lossy(bytes).into_owned();
+                }
+                // SAFETY: `data` was allocated by Xlib and isn't used past here.
+                unsafe { XFree(data as *mut c_void) };
+            }
+        }
+
+        let atoms = [st::Selection::Primary, st::Selection::Clipboard]
+            .map(|selection| (selection, self.selection_atom(selection)));
+        let requested =
+            |selection: &mut st::Selection| atoms.contains(&(*selection, event.selection));
+        if let Some(selection) = self.x_selection.osc52_request.take_if(requested) {
+            self.term.report_selection(selection, &text);
+        }
+        if self.x_selection.paste_request.take_if(requested).is_some() {
+            self.paste(text);
+        }
+    }
+
+    /// Forgets the text of a selection another client has taken over, so it is fetched from
+    /// the new owner from then on.
+    fn selection_clear(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for SelectionClear, so `xselectionclear` is the
+        // active member.
+        let event: &XSelectionClearEvent = unsafe { (*e).xselectionclear.as_ref() };
+
+        if event.selection == XA_PRIMARY {
+            self.x_selection.primary = Option::None;
+        } else if event.selection == self.intern_atom(c"CLIPBOARD") {
+            self.x_selection.clipboard = Option::None;
+        }
+    }
+
+    /// Answers another client asking for the contents of a selection we own.
+    fn selection_request(&mut self, e: *mut XEvent) {
+        debug_assert!(!e.is_null(), "Pointer `e` should not be null");
+        // SAFETY: Only called by `handle_event` for SelectionRequest, so `xselectionrequest` is
+        // the active member.
+        let request: &XSelectionRequestEvent = unsafe { (*e).xselectionrequest.as_ref() };
+        let display = self.x_window.display;
+
+        // A property of None in the reply tells the requestor the conversion failed.
+        let mut property: Atom = 0;
+        if request.target == self.intern_atom(c"TARGETS") {
+            let targets = [self.x_selection.x_target];
+            // SAFETY: `targets` outlives the call and holds the one atom we say it does.
+            unsafe {
+                XChangeProperty(
+                    display,
+                    request.requestor,
+                    request.property,
+                    XA_ATOM,
+                    32,
+                    PropModeReplace as c_int,
+                    targets.as_ptr() as *const u8,
+                    targets.len() as c_int,
+                )
+            };
+            property = request.property;
+        } else if request.target == self.x_selection.x_target || request.target == XA_STRING {
+            let text = if request.selection == XA_PRIMARY {
+                self.x_selection.primary.as_ref()
+            } else if request.selection == self.intern_atom(c"CLIPBOARD") {
+                self.x_selection.clipboard.as_ref()
+            } else {
+                Option::None
+            };
+            if let Some(text) = text {
+                // SAFETY: `text` outlives the call and the length given is its length in bytes.
+                unsafe {
+                    XChangeProperty(
+                        display,
+                        request.requestor,
+                        request.property,
+                        request.target,
+                        8,
+                        PropModeReplace as c_int,
+                        text.as_ptr(),
+                        text.len() as c_int,
+                    )
+                };
+                property = request.property;
+            }
+        }
+
+        // SAFETY: An all zero event is a valid value for every member of the union.
+        let mut reply: XEvent = unsafe { std::mem::zeroed() };
+        // SAFETY: `xselection` is the member SelectionNotify replies are sent with.
+        unsafe {
+            *reply.xselection.as_mut() = XSelectionEvent {
+                type_: SelectionNotify as c_int,
+                serial: 0,
+                send_event: 1,
+                display: request.display,
+                requestor: request.requestor,
+                selection: request.selection,
+                target: request.target,
+                property,
+                time: request.time,
+            };
+            XSendEvent(display, request.requestor, 1, 0, &mut reply);
+        }
+    }
+
+    /// Rows a scroll shortcut moves by: a line count for `Arg::Int` and `Arg::UInt`, or a fraction
+    /// of the screen for `Arg::Float`.
+    fn scroll_amount(&self, arg: &Arg) -> usize {
+        match *arg {
+            Arg::Int(n) => n.max(0) as usize,
+            Arg::UInt(n) => n as usize,
+            Arg::Float(f) => ((self.term.rows as f32 * f).round() as usize).max(1),
+            _ => 0,
+        }
+    }
+
+    /// Scrolls the view back into the scrollback.
+    pub fn scroll_up(&mut self, arg: &Arg) {
+        let n = self.scroll_amount(arg);
+        self.term.scroll_view_up(n);
+    }
+
+    /// Scrolls the view forward towards the live screen.
+    pub fn scroll_down(&mut self, arg: &Arg) {
+        let n = self.scroll_amount(arg);
+        self.term.scroll_view_down(n);
+    }
+
+    /// Scrolls the view to the oldest line in the scrollback.
+    pub fn scroll_to_top(&mut self, _: &Arg) {
+        self.term.scroll_view_up(self.term.history.len());
+    }
+
+    /// Scrolls the view back to the live screen.
+    pub fn scroll_to_bottom(&mut self, _: &Arg) {
+        self.term.scroll_view_reset();
+    }
+
+    /// Opens the search bar. A positive `Arg::Int` searches forward from the top of the view,
+    /// anything else searches backward from the bottom.
+    pub fn search_start(&mut self, arg: &Arg) {
+        let direction = match *arg {
+            Arg::Int(n) if n > 0 => SearchDirection::Forward,
+            _ => SearchDirection::Backward,
+        };
+        self.search = Some(Search::new(&self.term, direction));
+        self.term.set_dirty(self.term.rows - 1, self.term.rows - 1);
+    }
+
+    /// Edits the query of the open search, refreshing matches as it changes.
+    ///
+    /// Return moves to the next match and Shift+Return to the previous one, Ctrl+R cycles
+    /// between plain, case insensitive and regex matching, and Escape closes the search.
+    fn search_key_press(&mut self, key_symbol: KeySym, state: c_uint, text: &[i8]) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+
+        match key_symbol as u32 {
+            XK_Escape => {
+                self.search.take();
+                self.term.scroll_view_reset();
+                self.term.set_dirty_all();
+                return;
+            }
+            XK_Return | XK_KP_Enter => search.step(state & ShiftMask != 0),
+            XK_BackSpace => {
+                search.query.pop();
+                search.update(&self.term);
+            }
+            XK_r if state & ControlMask != 0 => {
+                search.mode = search.mode.next();
+                search.update(&self.term);
+            }
+            _ => {
+                let bytes: Vec<u8> = text.iter().map(|&b| b as u8).collect();
+                let text = String::from_utf8_lossy(&bytes);
+                let text: String = text.chars().filter(|c| !c.is_control()).collect();
+                if text.is_empty() {
+                    return;
+                }
+                search.query.push_str(&text);
+                search.update(&self.term);
+            }
+        }
+
+        if let Some(current) = search.current_match() {
+            self.term.scroll_view_to_line(current.start.line);
+        }
+        self.term.set_dirty_all();
+    }
+
+    /// Redraws the rows of the view that are marked dirty.
+    pub fn draw(&mut self) {
+        if !self.term.window_mode.contains(WindowMode::VISIBLE) {
+            return;
+        }
+
+        // Hold back frames while the application is part way through a synchronized update.
+        if self.term.in_synchronized_update(config::SYNC_TIMEOUT) {
+            return;
+        }
+
+        let (_, old_row) = self.term_window.old_cursor;
+        self.term.set_dirty(old_row, old_row);
+
+        // Images are cut into tiles the size of a cell as they are placed.
+        self.term.cell_size = (
+            self.term_window.char_width.max(1) as usize,
+            self.term_window.char_height.max(1) as usize,
+        );
+        self.free_images();
+
+        let context = &mut self.drawing_context;
+        for font in [
+            &mut context.font,
+            &mut context.bold_font,
+            &mut context.italic_font,
+            &mut context.Italic_bold_font,
+        ] {
+            font.load_underline_metrics();
+        }
+
+        // The stretched fonts are only opened once a double width or double height line shows.
+        if (0..self.term.rows).any(|row| self.term.view_line(row).size != LineSize::Single) {
+            let display = self.x_window.display;
+            let context = &mut self.drawing_context;
+            for font in [
+                &mut context.font,
+                &mut context.bold_font,
+                &mut context.italic_font,
+                &mut context.Italic_bold_font,
+            ] {
+                font.load_double_size(display);
+            }
+        }
+
+        for row in 0..self.term.rows {
+            if !std::mem::replace(&mut self.term.dirty[row], false) {
+                continue;
+            }
+            let line_number = self.term.view_line_number(row);
+            self.draw_exit_status(row);
+            let size = self.term.view_line(row).size;
+            let last_col = self.term.cols - 1;
+            if size != LineSize::Single && self.term.cols % 2 == 1 {
+                // Clear the half cell left over at the right of an odd width screen.
+                let blank = st::Glyph::default();
+                self.draw_glyph(blank, last_col, row, LineSize::Single, true);
+            }
+            let mut glyphs = Vec::with_capacity(self.term.cols);
+            for col in 0..size.cols(self.term.cols) {
+                let mut glyph = self
+                    .term
+                    .view_line(row)
+                    .glyphs
+                    .get(col)
+                    .copied()
+                    .unwrap_or_default();
+
+                let point = Point {
+                    line: line_number,
+                    col,
+                };
+                match self.search.as_ref().and_then(|s| s.match_at(point)) {
+                    Some(true) => {
+                        glyph.foreground = config::SEARCH_CURRENT_FOREGROUND;
+                        glyph.background = config::SEARCH_CURRENT_BACKGROUND;
+                    }
+                    Some(false) => {
+                        glyph.foreground = config::SEARCH_MATCH_FOREGROUND;
+                        glyph.background = config::SEARCH_MATCH_BACKGROUND;
+                    }
+                    _ => {}
+                }
+                if glyph.hyperlink != 0
+                    && glyph.hyperlink == self.term_window.hovered_hyperlink
+                    && glyph.underline == UnderlineStyle::None
+                {
+                    glyph.underline = UnderlineStyle::Single;
+                }
+                self.draw_glyph(glyph, col, row, size, true);
+                glyphs.push(glyph);
+            }
+
+            // Text goes over images with a negative z-index and under the rest.
+            self.draw_images(row, true);
+            for tile in &self.term.view_line(row).images {
+                if tile.z < 0 {
+                    if let Some(&glyph) = glyphs.get(tile.col) {
+                        self.draw_glyph(glyph, tile.col, row, size, false);
+                    }
+                }
+            }
+            self.draw_images(row, false);
+        }
+
+        self.draw_cursor();
+        self.draw_search_bar();
+
+        // SAFETY: The display is open for as long as `x` exists.
+        unsafe { XFlush(self.x_window.display) };
+    }
+
+    /// Marks the prompt of a finished command in the left border, coloured by its exit status.
+    fn draw_exit_status(&self, row: usize) {
+        let width = self.x_window.left_offset;
+        if !config::EXIT_STATUS_GUTTER || width <= 0 {
+            return;
+        }
+        let color = match self.term.view_line(row).exit_status {
+            Some(0) => config::EXIT_SUCCESS_COLOR,
+            Some(_) => config::EXIT_FAILURE_COLOR,
+            _ => config::DEFAULT_BACKGROUND,
+        };
+        let color = self.color(color);
+        let height = self.term_window.char_height;
+        // SAFETY: `draw` is created at startup and lives as long as `x`.
+        unsafe {
+            XftDrawRect(
+                self.x_window.draw,
+                &color,
+                0,
+                self.x_window.top_offset + row as c_int * height,
+                width as c_uint,
+                height as c_uint,
+            )
+        };
+    }
+
+    /// Draws the image tiles on a row of the view with a negative z-index, or the rest, in
+    /// z-index order.
+    fn draw_images(&mut self, row: usize, below_text: bool) {
+        let mut tiles: Vec<_> = self
+            .term
+            .view_line(row)
+            .images
+            .iter()
+            .filter(|tile| (tile.z < 0) == below_text)
+            .copied()
+            .collect();
+        tiles.sort_by_key(|tile| tile.z);
+        let (width, height) = (self.term_window.char_width, self.term_window.char_height);
+        for tile in tiles {
+            if tile.col >= self.term.cols {
+                continue;
+            }
+            let Some(image) = self.term.images.get(&tile.image) else {
+                continue;
+            };
+            let (tile_width, tile_height) =
+                (image.cell_size.0 as c_int, image.cell_size.1 as c_int);
+            let Some(picture) = self.image_picture(tile.image) else {
+                continue;
+            };
+            // SAFETY: `draw` is created at startup and lives as long as `x`, and `picture` is
+            // freed only once the image is gone.
+            unsafe {
+                XRenderComposite(
+                    self.x_window.display,
+                    PictOpOver as c_int,
+                    picture,
+                    0,
+                    XftDrawPicture(self.x_window.draw),
+                    tile.x as c_int * tile_width,
+                    tile.y as c_int * tile_height,
+                    0,
+                    0,
+                    self.x_window.left_offset + tile.col as c_int * width,
+                    self.x_window.top_offset + row as c_int * height,
+                    tile_width.min(width) as c_uint,
+                    tile_height.min(height) as c_uint,
+                )
+            };
+        }
+    }
+
+    /// Gets the picture of an image, uploading it to the X server the first time it is drawn.
+    fn image_picture(&mut self, id: u32) -> Option<Picture> {
+        if let Some(&picture) = self.drawing_context.images.get(&id) {
+            return Some(picture);
+        }
+        let image = self.term.images.get(&id)?;
+        let (width, height) = (image.width as c_uint, image.height as c_uint);
+        let mut pixels = image.pixels.clone();
+        let display = self.x_window.display;
+
+        // SAFETY: The display and window are open for as long as `x` exists. `pixels` outlives
+        // the XImage pointing into it, which is let go of before it is freed so Xlib doesn't try
+        // to free the pixels too.
+        let picture = unsafe {
+            let pixmap = XCreatePixmap(display, self.x_window.window, width, height, 32);
+            let ximage = XCreateImage(
+                display,
+                std::ptr::null_mut(),
+                32,
+                ZPixmap as c_int,
+                0,
+                pixels.as_mut_ptr() as *mut c_char,
+                width,
+                height,
+                32,
+                0,
+            );
+            if ximage.is_null() {
+                XFreePixmap(display, pixmap);
+                return Option::None;
+            }
+            let gc = XCreateGC(display, pixmap, 0, std::ptr::null_mut());
+            XPutImage(display, pixmap, gc, ximage, 0, 0, 0, 0, width, height);
+            XFreeGC(display, gc);
+            (*ximage).data = std::ptr::null_mut();
+            XFree(ximage as *mut c_void);
+
+            let format = XRenderFindStandardFormat(display, PictStandardARGB32 as c_int);
+            let picture = XRenderCreatePicture(display, pixmap, format, 0, std::ptr::null());
+            // The picture keeps the pixmap alive.
+            XFreePixmap(display, pixmap);
+            picture
+        };
+        self.drawing_context.images.insert(id, picture);
+        Some(picture)
+    }
+
+    /// Frees the pictures of images the terminal no longer has.
+    fn free_images(&mut self) {
+        let display = self.x_window.display;
+        let images = &self.term.images;
+        self.drawing_context.images.retain(|id, &mut picture| {
+            let keep = images.contains_key(id);
+            if !keep {
+                // SAFETY: The picture was created on this display and is not used again.
+                unsafe { XRenderFreePicture(display, picture) };
+            }
+            keep
+        });
+    }
+
+    fn draw_cursor(&mut self) {
+        let cursor = self.term.cursor;
+        // The cursor stays with the live screen, so it moves down and out of the view when
+        // scrolled back.
+        let row = cursor.y + self.term.scroll_offset;
+        if row >= self.term.rows {
+            return;
+        }
+        self.term_window.old_cursor = (cursor.x, row);
+
+        // The blink itself is driven by the event loop, which flips `WindowMode::BLINK`.
+        let mode = self.term.window_mode;
+        let style = self.term.cursor_style;
+        if mode.contains(WindowMode::HIDE) || (style.blinks() && mode.contains(WindowMode::BLINK)) {
+            return;
+        }
+
+        let mut glyph = self.term.lines[cursor.y].glyphs[cursor.x];
+        let size = self.term.lines[cursor.y].size;
+        let color = if glyph.mode.contains(GlyphAttribute::REVERSE) {
+            glyph.background
+        } else {
+            glyph.foreground
+        };
+        let color = self.color(color);
+        let cols = if glyph.mode.contains(GlyphAttribute::WIDE) {
+            2
+        } else {
+            1
+        };
+        let char_width = match size {
+            LineSize::Single => self.term_window.char_width,
+            _ => self.term_window.char_width * 2,
+        };
+        let width = char_width * cols;
+        let height = self.term_window.char_height;
+        let win_x = self.x_window.left_offset + cursor.x as c_int * char_width;
+        let win_y = self.x_window.top_offset + row as c_int * height;
+        let thickness = config::CURSOR_THICKNESS.min(width).min(height);
+        let rect = |x: c_int, y: c_int, width: c_int, height: c_int| {
+            // SAFETY: `draw` is created at startup and lives as long as `x`, and the colour is
+            // valid for the duration of the call.
+            unsafe {
+                XftDrawRect(
+                    self.x_window.draw,
+                    &color,
+                    x,
+                    y,
+                    width as c_uint,
+                    height as c_uint,
+                )
+            }
+        };
+
+        // Without focus the cursor is drawn as a hollow block, whatever its style.
+        if !mode.contains(WindowMode::FOCUSED) {
+            rect(win_x, win_y, width, 1);
+            rect(win_x, win_y + height - 1, width, 1);
+            rect(win_x, win_y, 1, height);
+            rect(win_x + width - 1, win_y, 1, height);
+            return;
+        }
+        match style {
+            CursorStyle::BlinkingBlock | CursorStyle::SteadyBlock => {
+                glyph.mode ^= GlyphAttribute::REVERSE;
+                self.draw_glyph(glyph, cursor.x, row, size, true);
+            }
+            CursorStyle::BlinkingUnderline | CursorStyle::SteadyUnderline => {
+                rect(win_x, win_y + height - thickness, width, thickness);
+            }
+            CursorStyle::BlinkingBar | CursorStyle::SteadyBar => {
+                rect(win_x, win_y, thickness, height);
+            }
+        }
+    }
+
+    /// Draws the query of the open search, or the question of a held back paste, over the
+    /// bottom row of the view.
+    fn draw_search_bar(&mut self) {
+        let bar = if let Some(search) = self.search.as_ref() {
+            let mut bar = format!("{}: {}", search.mode.name(), search.query);
+            if let Some(error) = &search.error {
+                bar.push_str(&format!(" [{}]", error.lines().last().unwrap_or_default()));
+            } else if search.current.is_none() && !search.query.is_empty() {
+                bar.push_str(" [no matches]");
+            }
+            bar
+        } else if let Some(text) = self.pending_paste.as_ref() {
+            let lines = text.trim_end_matches(['\r', '\n']).lines().count();
+            format!("Paste {} lines? [y/N]", lines)
+        } else {
+            return;
+        };
+
+        let row = self.term.rows - 1;
+        let glyph = st::Glyph {
+            mode: GlyphAttribute::REVERSE,
+            ..st::Glyph::default()
+        };
+        let mut chars = bar.chars();
+        for col in 0..self.term.cols {
+            let character = chars.next().unwrap_or(' ');
+            let glyph = st::Glyph { character, ..glyph };
+            self.draw_glyph(glyph, col, row, LineSize::Single, true);
+        }
+        // Redraw the row underneath once the search bar goes away.
+        self.term.set_dirty(row, row);
+    }
+
+    /// Draws a single glyph at a column and row of the view, on a line of the given size,
+    /// leaving out the background to draw over an image.
+    fn draw_glyph(
+        &self,
+        glyph: st::Glyph,
+        col: usize,
+        row: usize,
+        size: LineSize,
+        fill_background: bool,
+    ) {
+        let (mut foreground, mut background) = (glyph.foreground, glyph.background);
+        if glyph.mode.contains(GlyphAttribute::REVERSE) {
+            std::mem::swap(&mut foreground, &mut background);
+        }
+        if glyph.mode.contains(GlyphAttribute::INVISIBLE) {
+            foreground = background;
+        }
+        let underline_color = match glyph.underline_color {
+            Some(_) if glyph.mode.contains(GlyphAttribute::INVISIBLE) => background,
+            Some(color) => color,
+            Option::None => foreground,
+        };
+        let foreground = self.color(foreground);
+        let background = self.color(background);
+
+        let font = match (
+            glyph.mode.contains(GlyphAttribute::BOLD),
+            glyph.mode.contains(GlyphAttribute::ITALIC),
+        ) {
+            (false, false) => &self.drawing_context.font,
+            (true, false) => &self.drawing_context.bold_font,
+            (false, true) => &self.drawing_context.italic_font,
+            (true, true) => &self.drawing_context.Italic_bold_font,
+        };
+
+        let width = match size {
+            LineSize::Single => self.term_window.char_width,
+            _ => self.term_window.char_width * 2,
+        };
+        let height = self.term_window.char_height;
+        let win_x = self.x_window.left_offset + col as c_int * width;
+        let win_y = self.x_window.top_offset + row as c_int * height;
+        let character = glyph.character as FcChar32;
+
+        // Double height characters are drawn whole and cut down to the half on this row, so
+        // offsets below are from the top of the character rather than the row.
+        let stretched_font = match size {
+            LineSize::Single => std::ptr::null_mut(),
+            LineSize::DoubleWidth => font.double_width_font,
+            _ => font.double_size_font,
+        };
+        let (matched_font, ascent, top) = match size {
+            _ if stretched_font.is_null() => (font.matched_font, font.ascent, 0),
+            LineSize::DoubleHeightTop => (stretched_font, 2 * font.ascent, 0),
+            LineSize::DoubleHeightBottom => (stretched_font, 2 * font.ascent, height),
+            _ => (stretched_font, font.ascent, 0),
+        };
+        let double_height = !stretched_font.is_null()
+            && matches!(
+                size,
+                LineSize::DoubleHeightTop | LineSize::DoubleHeightBottom
+            );
+        let clip = XRectangle {
+            x: win_x as c_short,
+            y: win_y as c_short,
+            width: width as u16,
+            height: height as u16,
+        };
+
+        // SAFETY: `draw` and the fonts are created at startup and live as long as `x`, and the
+        // colours are valid for the duration of the calls.
+        unsafe {
+            if fill_background {
+                XftDrawRect(
+                    self.x_window.draw,
+                    &background,
+                    win_x,
+                    win_y,
+                    width as c_uint,
+                    height as c_uint,
+                );
+            }
+            if double_height {
+                XftDrawSetClipRectangles(self.x_window.draw, 0, 0, &clip, 1);
+            }
+            if glyph.character != ' '
+                && glyph.character != kitty_graphics::PLACEHOLDER
+                && !glyph.mode.contains(GlyphAttribute::WDUMMY)
+            {
+                XftDrawString32(
+                    self.x_window.draw,
+                    &foreground,
+                    matched_font,
+                    win_x,
+                    win_y - top + ascent,
+                    &character,
+                    1,
+                );
+            }
+            if glyph.mode.contains(GlyphAttribute::STRUCK) {
+                XftDrawRect(
+                    self.x_window.draw,
+                    &foreground,
+                    win_x,
+                    win_y - top + 2 * ascent / 3,
+                    width as c_uint,
+                    font.underline_thickness() as c_uint,
+                );
+            }
+            if double_height {
+                XftDrawSetClip(self.x_window.draw, std::ptr::null_mut());
+            }
+        }
+        // Underlines go along the bottom half of double height characters.
+        if glyph.underline != UnderlineStyle::None && size != LineSize::DoubleHeightTop {
+            self.draw_underline(glyph.underline, underline_color, font, win_x, win_y, width);
+        }
+    }
+
+    /// Draws an underline across the cell at `win_x`, `win_y` that is `width` wide, keeping it
+    /// inside the cell.
+    fn draw_underline(
+        &self,
+        style: UnderlineStyle,
+        color: u32,
+        font: &XFont,
+        win_x: c_int,
+        win_y: c_int,
+        width: c_int,
+    ) {
+        let color = self.color(color);
+        let height = self.term_window.char_height;
+        let thickness = font.underline_thickness();
+        // Room for the tallest style, two lines with a gap between them.
+        let y = win_y + font.underline_position().min(height - 3 * thickness).max(0);
+        // SAFETY: `draw` is created at startup and lives as long as `x`, and the colour is
+        // valid for the duration of the calls.
+        let rect = |x: c_int, y: c_int, width: c_int| unsafe {
+            XftDrawRect(
+                self.x_window.draw,
+                &color,
+                x,
+                y,
+                width as c_uint,
+                thickness as c_uint,
+            )
+        };
+
+        match style {
+            UnderlineStyle::None => {}
+            UnderlineStyle::Single => rect(win_x, y, width),
+            UnderlineStyle::Double => {
+                rect(win_x, y, width);
+                rect(win_x, y + 2 * thickness, width);
+            }
+            UnderlineStyle::Dotted | UnderlineStyle::Dashed | UnderlineStyle::Curly => {
+                let (on, period) = match style {
+                    UnderlineStyle::Dotted => (thickness, 2 * thickness),
+                    _ => (3 * thickness, 5 * thickness),
+                };
+                // Height of the line in each column of the cell, or `None` for a gap.
+                let column = |x: c_int| match style {
+                    // One wave to a cell, swinging a line's thickness above and below.
+                    UnderlineStyle::Curly => {
+                        let phase = x as f64 / width as f64 * std::f64::consts::TAU;
+                        let offset = (phase.sin() * thickness as f64).round() as c_int;
+                        Some(y + thickness - offset)
+                    }
+                    // Dots and dashes are laid out from the left of the window, so they carry
+                    // on evenly from one cell to the next.
+                    _ => ((win_x + x - self.x_window.left_offset) % period < on).then_some(y),
+                };
+                // Neighbouring columns at the same height are drawn as one rectangle.
+                let mut run: Option<(c_int, c_int, c_int)> = Option::None;
+                for x in 0..width {
+                    let column_y = column(x);
+                    match run {
+                        Some((start, run_y, length)) if column_y == Some(run_y) => {
+                            run = Some((start, run_y, length + 1));
+                        }
+                        _ => {
+                            if let Some((start, run_y, length)) = run {
+                                rect(start, run_y, length);
+                            }
+                            run = column_y.map(|column_y| (win_x + x, column_y, 1));
+                        }
+                    }
+                }
+                if let Some((start, run_y, length)) = run {
+                    rect(start, run_y, length);
+                }
+            }
+        }
+    }
+
+    /// Looks up a colour in the palette, or allocates it when it is a true colour.
+    fn color(&self, color: u32) -> XftColor {
+        if is_true_color(color) {
+            let channel = |shift: u32| ((color >> shift & 0xff) * 0x101) as u16;
+            let render_color = XRenderColor {
+                red: channel(16),
+                green: channel(8),
+                blue: channel(0),
+                alpha: 0xffff,
+            };
+            let mut result = XftColor {
+                pixel: 0,
+                color: XRenderColor {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                    alpha: 0,
+                },
+            };
+            // SAFETY: The display, visual and colormap are set up at startup and both colours
+            // are valid for the duration of the call.
+            unsafe {
+                XftColorAllocValue(
+                    self.x_window.display,
+                    self.x_window.visual,
+                    self.x_window.colormap,
+                    &render_color,
+                    &mut result,
+                )
+            };
+            result
+        } else {
+            let index = (color as usize).min(self.drawing_context.col_length - 1);
+            // SAFETY: `Color` holds the `col_length` colours loaded at startup, and the index is
+            // clamped to it.
+            copy_color(unsafe { &*self.drawing_context.Color.add(index) })
+        }
     }
 
     // ttywrite is a rabbit hole, I'll first need ttywriteraw, which needs translation of the C